@@ -1,6 +1,7 @@
 extern crate core;
 
 pub mod keyset_generator;
+pub mod test_files;
 pub mod tree_utils;
 
 use minstant::Instant;
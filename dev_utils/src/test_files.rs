@@ -0,0 +1,6 @@
+use std::path::PathBuf;
+
+/// A file path in the temporary directory that is unique to `name` and the running test binary.
+pub fn temp_path(name: &str, extension: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("umolc-{name}-{}.{extension}", std::process::id()))
+}
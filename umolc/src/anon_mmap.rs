@@ -0,0 +1,64 @@
+use std::io;
+use std::ptr::null_mut;
+
+/// An anonymous private mapping that only reserves address space.
+/// Memory is committed by the kernel when it is first touched and can be handed back with [AnonMmap::release].
+pub(crate) struct AnonMmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+unsafe impl Send for AnonMmap {}
+unsafe impl Sync for AnonMmap {}
+
+impl AnonMmap {
+    pub fn reserve(len: usize) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                null_mut(),
+                len.max(1),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(AnonMmap { ptr: ptr as *mut u8, len })
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// Drops the physical memory backing all OS pages fully contained in the range.
+    /// Subsequent reads of those return zeros.
+    pub fn release(&self, offset: usize, len: usize) {
+        let os_page = os_page_size();
+        let start = offset.next_multiple_of(os_page);
+        let end = (offset + len) / os_page * os_page;
+        if start < end {
+            self.advise(start, end - start, libc::MADV_DONTNEED);
+        }
+    }
+
+    pub fn advise(&self, offset: usize, len: usize, advice: libc::c_int) {
+        assert!(offset + len <= self.len);
+        let r = unsafe { libc::madvise(self.ptr.add(offset) as *mut libc::c_void, len, advice) };
+        debug_assert!(r == 0, "madvise failed: {}", io::Error::last_os_error());
+    }
+}
+
+pub(crate) fn os_page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+impl Drop for AnonMmap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len.max(1));
+        }
+    }
+}
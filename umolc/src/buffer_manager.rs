@@ -1,65 +1,316 @@
-use crate::seqlock::SeqLock;
+use crate::anon_mmap::AnonMmap;
+use crate::free_pages::FreePages;
+use crate::page_stats::{PageEvent, PageStats};
+use crate::seqlock::{forget_locks, label_lock, SeqLock};
+use crate::trace_bm::{LockMode, TraceOp};
 use crate::{
-    BufferManageGuardUpgrade, BufferManager, BufferManagerGuard, ExclusiveGuard, OPtr, OlcErrorHandler, OlcVersion,
-    OptimisticGuard, PageId, UnwindOlcEh,
+    BufferManageGuardDowngrade, BufferManageGuardUpgrade, BufferManager, BufferManagerGuard, ExclusiveGuard, FaultSite,
+    Locked, OPtr, OlcErrorHandler, OlcVersion, Optimistic, OptimisticError, OptimisticGuard, OutOfPages, PageId,
+    UnwindOlcEh, WouldBlock,
 };
 use bytemuck::Zeroable;
 use std::cell::UnsafeCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::marker::PhantomData;
 use std::mem::{forget, MaybeUninit};
-use std::ops::{Deref, DerefMut};
-use std::sync::Mutex;
-
-pub struct SimpleBm<P> {
-    pages: Box<[UnsafeCell<P>]>,
+use std::ops::{Deref, DerefMut, Range};
+use std::path::Path;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+const SNAPSHOT_MAGIC: u64 = u64::from_le_bytes(*b"umolcsnp");
+
+/// Pages of a [SimpleBm] are added in chunks of equal size.
+/// Page memory for all chunks is reserved up front, so a chunk only holds the locks of its pages.
+struct Chunk {
     locks: Box<[SeqLock]>,
-    free_list: Mutex<Vec<usize>>,
 }
 
-unsafe impl<P> Sync for SimpleBm<P> {}
+impl Chunk {
+    fn new(capacity: usize) -> Self {
+        unsafe { Chunk { locks: Box::<[MaybeUninit<_>]>::assume_init(Box::new_zeroed_slice(capacity)) } }
+    }
+}
+
+/// Guards of a `SimpleBm` report optimistic errors through `O`, see [crate::PanicOlcEh] for builds without unwinding.
+pub struct SimpleBm<P, O = UnwindOlcEh> {
+    chunk_capacity: usize,
+    /// address space for the pages of all chunks, contiguous so page ids can be computed from addresses
+    pages: AnonMmap,
+    /// initialized in order, the first chunk is always present
+    chunks: Box<[OnceLock<Chunk>]>,
+    free_pages: FreePages,
+    live: AtomicUsize,
+    high_water: AtomicUsize,
+    leak_check: Option<fn(&P) -> u8>,
+    page_stats: Option<PageStats>,
+    _o: PhantomData<fn() -> O>,
+}
+
+unsafe impl<P, O> Sync for SimpleBm<P, O> {}
 
 impl<P: Zeroable> SimpleBm<P> {
+    /// Creates a buffer manager with a fixed number of pages.
     pub fn new(capacity: usize) -> Self {
-        unsafe {
-            SimpleBm {
-                pages: Box::<[MaybeUninit<_>]>::assume_init(Box::new_zeroed_slice(capacity)),
-                locks: Box::<[MaybeUninit<_>]>::assume_init(Box::new_zeroed_slice(capacity)),
-                free_list: Mutex::new((0..capacity).collect()),
+        Self::new_growable(capacity, 1)
+    }
+
+    /// Creates a buffer manager with `chunk_capacity` pages that grows by another `chunk_capacity` pages whenever
+    /// it runs out of free pages, up to `max_chunks` times the chunk capacity.
+    pub fn new_growable(chunk_capacity: usize, max_chunks: usize) -> Self {
+        Self::with_error_handler(chunk_capacity, max_chunks)
+    }
+
+    /// Restores a buffer manager written by [SimpleBm::save_snapshot].
+    /// Returns it along with the page id passed when saving.
+    pub fn load_snapshot(path: impl AsRef<Path>) -> io::Result<(Self, PageId)> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut read_u64 = || -> io::Result<u64> {
+            let mut b = [0; 8];
+            file.read_exact(&mut b)?;
+            Ok(u64::from_le_bytes(b))
+        };
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+        if read_u64()? != SNAPSHOT_MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        if read_u64()? != size_of::<P>() as u64 {
+            return Err(invalid("page size mismatch"));
+        }
+        let capacity = read_u64()? as usize;
+        let meta = PageId { x: read_u64()? };
+        let free_count = read_u64()? as usize;
+        if free_count > capacity {
+            return Err(invalid("bad free list"));
+        }
+        let free_list = (0..free_count).map(|_| read_u64().map(|x| x as usize)).collect::<io::Result<Vec<_>>>()?;
+        // every free page must be handed out at most once and lie within the page array
+        let mut free = vec![false; capacity];
+        for &pid in &free_list {
+            match free.get_mut(pid) {
+                Some(seen @ false) => *seen = true,
+                _ => return Err(invalid("bad free list entry")),
             }
         }
+        let mut bm = SimpleBm::new(capacity);
+        bm.live.store(capacity - free_list.len(), Relaxed);
+        bm.high_water.store(capacity - free_list.len(), Relaxed);
+        bm.free_pages = FreePages::new(free_list);
+        let bytes = bm.bytes(0..capacity);
+        file.read_exact(unsafe { std::slice::from_raw_parts_mut(bytes.as_ptr() as *mut u8, bytes.len()) })?;
+        Ok((bm, meta))
+    }
+}
+
+impl<P: Zeroable, O: OlcErrorHandler> SimpleBm<P, O> {
+    /// Like [SimpleBm::new_growable], but with guards that report optimistic errors through `O`.
+    pub fn with_error_handler(chunk_capacity: usize, max_chunks: usize) -> Self {
+        assert!(max_chunks > 0);
+        let pages = AnonMmap::reserve(chunk_capacity * max_chunks * size_of::<P>())
+            .unwrap_or_else(|e| panic!("failed to reserve page memory: {e}"));
+        assert_eq!(pages.as_ptr().addr() % align_of::<P>(), 0);
+        let chunks: Box<[OnceLock<Chunk>]> = (0..max_chunks).map(|_| OnceLock::new()).collect();
+        let _ = chunks[0].set(Chunk::new(chunk_capacity));
+        SimpleBm {
+            chunk_capacity,
+            pages,
+            chunks,
+            free_pages: FreePages::new(0..chunk_capacity),
+            live: AtomicUsize::new(0),
+            high_water: AtomicUsize::new(0),
+            leak_check: None,
+            page_stats: None,
+            _o: PhantomData,
+        }
+    }
+
+    /// Adds the next chunk, caller must hold the lock on the shared pool of free pages.
+    fn grow(&self, free_list: &mut VecDeque<usize>) -> Result<(), OutOfPages> {
+        let index = self.chunks.iter().position(|c| c.get().is_none()).ok_or(OutOfPages)?;
+        let _ = self.chunks[index].set(Chunk::new(self.chunk_capacity));
+        free_list.extend(index * self.chunk_capacity..(index + 1) * self.chunk_capacity);
+        Ok(())
+    }
+}
+
+impl<P, O> SimpleBm<P, O> {
+    fn chunk(&self, index: usize) -> &Chunk {
+        self.chunks[index].get().expect("page id out of range")
+    }
+
+    fn initialized_chunks(&self) -> impl Iterator<Item = &Chunk> {
+        self.chunks.iter().map_while(|c| c.get())
+    }
+
+    /// Page `pid`, whose chunk must have been initialized.
+    fn page_cell(&self, pid: usize) -> &UnsafeCell<P> {
+        debug_assert!(self.chunks[pid / self.chunk_capacity].get().is_some());
+        unsafe { &*(self.pages.as_ptr() as *const UnsafeCell<P>).add(pid) }
+    }
+
+    fn bytes(&self, pids: Range<usize>) -> &[u8] {
+        let start = pids.start * size_of::<P>();
+        unsafe { std::slice::from_raw_parts(self.pages.as_ptr().add(start), pids.len() * size_of::<P>()) }
+    }
+
+    /// Number of pages currently backed by memory.
+    pub fn capacity(&self) -> usize {
+        self.initialized_chunks().count() * self.chunk_capacity
+    }
+
+    /// Number of pages this buffer manager can grow to.
+    pub fn max_capacity(&self) -> usize {
+        self.chunks.len() * self.chunk_capacity
+    }
+
+    /// If true, which is the default, the most recently freed pages are reused first for better cache locality.
+    /// Otherwise, the least recently freed pages are reused first.
+    pub fn set_prefer_recent_pages(&mut self, prefer_recent: bool) {
+        self.free_pages.set_prefer_recent(prefer_recent);
+    }
+
+    /// Number of pages currently allocated.
+    pub fn live_pages(&self) -> usize {
+        self.live.load(Relaxed)
+    }
+
+    /// Highest number of pages that were allocated at the same time.
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water.load(Relaxed)
+    }
+
+    /// Ids of all pages that are currently allocated, in ascending order.
+    /// The result is only exact if no pages are allocated or freed concurrently.
+    pub fn allocated_pages(&self) -> Vec<PageId> {
+        let free_list = self.free_pages.to_vec();
+        let mut is_free = vec![false; self.capacity()];
+        for &pid in &free_list {
+            is_free[pid] = true;
+        }
+        (0..is_free.len()).filter(|&pid| !is_free[pid]).map(|pid| PageId { x: pid as u64 }).collect()
+    }
+
+    /// Makes dropping the buffer manager panic if any page is still allocated.
+    ///
+    /// Trees borrow their buffer manager, so by the time it is dropped all pages should have been returned.
+    /// The panic message lists the leaked pages along with the tag `tag` extracts from each of them,
+    /// which helps to tell which kind of node was leaked.
+    /// With the `track-thread-locks` feature, `tag` also labels pages in lock-order reports.
+    pub fn enable_leak_check(&mut self, tag: fn(&P) -> u8) {
+        self.leak_check = Some(tag);
+    }
+
+    /// Starts counting lock acquisitions, optimistic failures and exclusive wait time for every page,
+    /// see [CommonSeqLockBM::page_stats].
+    /// Covers all pages the buffer manager can grow to.
+    pub fn enable_page_stats(&mut self) {
+        self.page_stats = Some(PageStats::new(self.max_capacity()));
+    }
+
+    /// Writes all pages and the free list to a file, along with `meta`, which typically identifies a tree.
+    ///
+    /// No page may be locked while the snapshot is taken.
+    /// Pages are checked before and after copying, an error of kind [io::ErrorKind::ResourceBusy] is returned if any
+    /// page was locked or modified in the meantime.
+    pub fn save_snapshot(&self, path: impl AsRef<Path>, meta: PageId) -> io::Result<()> {
+        let busy = || io::Error::new(io::ErrorKind::ResourceBusy, "buffer manager is not quiescent");
+        let free_list = self.free_pages.to_vec();
+        let chunks: Vec<&Chunk> = self.initialized_chunks().collect();
+        let locks = || chunks.iter().flat_map(|c| c.locks.iter());
+        let lock_words: Vec<u64> = locks().map(|l| l.snapshot()).collect();
+        if !lock_words.iter().all(|&w| SeqLock::is_unlocked(w)) {
+            return Err(busy());
+        }
+        let mut file = BufWriter::new(File::create(path)?);
+        let capacity = chunks.len() * self.chunk_capacity;
+        for x in [SNAPSHOT_MAGIC, size_of::<P>() as u64, capacity as u64, meta.x, free_list.len() as u64] {
+            file.write_all(&x.to_le_bytes())?;
+        }
+        for &pid in &free_list {
+            file.write_all(&(pid as u64).to_le_bytes())?;
+        }
+        file.write_all(self.bytes(0..capacity))?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        if locks().zip(&lock_words).any(|(l, &w)| l.snapshot() != w) {
+            return Err(busy());
+        }
+        Ok(())
+    }
+}
+
+impl<P, O> Drop for SimpleBm<P, O> {
+    fn drop(&mut self) {
+        for chunk in self.initialized_chunks() {
+            forget_locks(chunk.locks.as_ptr(), chunk.locks.len());
+        }
+        let Some(tag) = self.leak_check else {
+            return;
+        };
+        if std::thread::panicking() {
+            return;
+        }
+        let leaked: Vec<(u64, u8)> = self
+            .allocated_pages()
+            .into_iter()
+            .map(|pid| {
+                let pid = pid.x as usize;
+                let page = unsafe { &*self.page_cell(pid).get() };
+                (pid as u64, tag(page))
+            })
+            .collect();
+        if !leaked.is_empty() {
+            panic!("leaked {} pages (page id, tag): {leaked:?}", leaked.len());
+        }
     }
 }
 
-impl<'bm, P> CommonSeqLockBM<'bm> for &'bm SimpleBm<P> {
+impl<'bm, P: Zeroable, O: OlcErrorHandler + 'bm> CommonSeqLockBM<'bm> for &'bm SimpleBm<P, O> {
     type Page = P;
-    type OlcEH = UnwindOlcEh;
+    type OlcEH = O;
 
     fn pid_from_address(self, address: usize) -> PageId {
         let start = self.pages.as_ptr().addr();
         debug_assert!(address >= start);
-        debug_assert!(address < start + size_of::<P>() * self.pages.len());
+        debug_assert!(address < start + size_of::<P>() * self.max_capacity());
         let offset = address - start;
         assert_eq!(offset % size_of::<P>(), 0);
         PageId { x: (offset / size_of::<P>()) as u64 }
     }
 
-    fn alloc(self) -> PageId {
-        let pid = self.free_list.lock().unwrap().pop().expect("out of pages");
-        self.locks[pid].force_lock_exclusive();
-        PageId { x: pid as u64 }
+    fn try_alloc(self) -> Result<PageId, OutOfPages> {
+        let pid = self.free_pages.pop(|pool| self.grow(pool))?;
+        let live = self.live.fetch_add(1, Relaxed) + 1;
+        self.high_water.fetch_max(live, Relaxed);
+        self.lock(PageId { x: pid as u64 }).force_lock_exclusive();
+        Ok(PageId { x: pid as u64 })
     }
 
     fn dealloc(self, pid: PageId) {
-        let pid = pid.x as usize;
-        self.locks[pid].unlock_exclusive();
-        self.free_list.lock().unwrap().push(pid)
+        self.lock(pid).unlock_exclusive();
+        self.live.fetch_sub(1, Relaxed);
+        self.free_pages.push(pid.x as usize)
     }
 
     fn page(self, pid: PageId) -> &'bm UnsafeCell<Self::Page> {
-        &self.pages[pid.x as usize]
+        self.page_cell(pid.x as usize)
     }
 
     fn lock(self, pid: PageId) -> &'bm SeqLock {
-        &self.locks[pid.x as usize]
+        let pid = pid.x as usize;
+        &self.chunk(pid / self.chunk_capacity).locks[pid % self.chunk_capacity]
+    }
+
+    fn page_tag(self, pid: PageId) -> Option<u8> {
+        self.leak_check.map(|tag| tag(unsafe { &*self.page(pid).get() }))
+    }
+
+    fn page_stats(self) -> Option<&'bm PageStats> {
+        self.page_stats.as_ref()
     }
 }
 
@@ -68,11 +319,212 @@ pub trait CommonSeqLockBM<'bm>: Copy + Sync + Send + 'bm {
     type OlcEH: OlcErrorHandler;
     fn pid_from_address(self, address: usize) -> PageId;
     /// acquires exclusive lock
-    fn alloc(self) -> PageId;
+    fn try_alloc(self) -> Result<PageId, OutOfPages>;
+    /// acquires exclusive lock
+    fn alloc(self) -> PageId {
+        self.try_alloc().unwrap_or_else(|e| panic!("{e}"))
+    }
     /// releases exclusive lock
     fn dealloc(self, pid: PageId);
     fn page(self, pid: PageId) -> &'bm UnsafeCell<Self::Page>;
     fn lock(self, pid: PageId) -> &'bm SeqLock;
+
+    /// Called after the lock of `pid` has been acquired in any mode.
+    /// If this returns false, the page is not in memory.
+    /// The lock is then released and [Self::fault_in] is called before retrying.
+    /// For optimistic locks, the result only needs to be correct if the lock is successfully validated later.
+    fn is_resident(self, _pid: PageId) -> bool {
+        true
+    }
+
+    /// Loads a page that was reported as not resident.
+    /// Called without holding the lock of `pid`, but possibly while holding other locks, so it must not block on them.
+    /// Fails if there is no room for the page, e.g. because every resident page is locked.
+    fn fault_in(self, _pid: PageId) -> Result<(), WouldBlock> {
+        Ok(())
+    }
+
+    /// Identifies the kind of page in the reports of the `track-thread-locks` feature.
+    /// Only called while the lock of `pid` is held shared or exclusively.
+    fn page_tag(self, _pid: PageId) -> Option<u8> {
+        None
+    }
+
+    /// If this returns true, the optimistic operation at `site` fails as if the page had been modified concurrently.
+    /// Used by [crate::FaultBm] to exercise restarts.
+    fn inject_fault(self, _site: FaultSite) -> bool {
+        false
+    }
+
+    /// Per-page lock statistics, if enabled for this buffer manager.
+    fn page_stats(self) -> Option<&'bm PageStats> {
+        None
+    }
+
+    /// Called for every operation of the guards on `pid`, see [crate::TraceBm].
+    /// `version` is the version the operation observed or expected, `ok` is false if it failed.
+    fn trace(self, _pid: PageId, _op: TraceOp, _version: Option<OlcVersion>, _ok: bool) {}
+}
+
+/// A buffer manager that wraps another one to observe or disturb its guards, like [crate::FaultBm] and
+/// [crate::TraceBm].
+/// References to it implement [CommonSeqLockBM] by forwarding to the wrapped buffer manager, only fault injection
+/// and tracing go through the hooks below first.
+pub trait WrappingBm: Sync {
+    type Inner: Sync;
+
+    fn inner(&self) -> &Self::Inner;
+
+    /// The fault is injected if this or the wrapped buffer manager returns true, see [CommonSeqLockBM::inject_fault].
+    fn fault(&self, _site: FaultSite) -> bool {
+        false
+    }
+
+    /// Called before the operation is passed on to the wrapped buffer manager, see [CommonSeqLockBM::trace].
+    fn record_trace(&self, _pid: PageId, _op: TraceOp, _version: Option<OlcVersion>, _ok: bool) {}
+}
+
+impl<'bm, W: WrappingBm> CommonSeqLockBM<'bm> for &'bm W
+where
+    &'bm W::Inner: CommonSeqLockBM<'bm>,
+{
+    type Page = <&'bm W::Inner as CommonSeqLockBM<'bm>>::Page;
+    type OlcEH = <&'bm W::Inner as CommonSeqLockBM<'bm>>::OlcEH;
+
+    fn pid_from_address(self, address: usize) -> PageId {
+        self.inner().pid_from_address(address)
+    }
+
+    fn try_alloc(self) -> Result<PageId, OutOfPages> {
+        CommonSeqLockBM::try_alloc(self.inner())
+    }
+
+    fn dealloc(self, pid: PageId) {
+        self.inner().dealloc(pid)
+    }
+
+    fn page(self, pid: PageId) -> &'bm UnsafeCell<Self::Page> {
+        self.inner().page(pid)
+    }
+
+    fn lock(self, pid: PageId) -> &'bm SeqLock {
+        self.inner().lock(pid)
+    }
+
+    fn is_resident(self, pid: PageId) -> bool {
+        self.inner().is_resident(pid)
+    }
+
+    fn fault_in(self, pid: PageId) -> Result<(), WouldBlock> {
+        self.inner().fault_in(pid)
+    }
+
+    fn page_tag(self, pid: PageId) -> Option<u8> {
+        self.inner().page_tag(pid)
+    }
+
+    fn inject_fault(self, site: FaultSite) -> bool {
+        self.fault(site) || self.inner().inject_fault(site)
+    }
+
+    fn page_stats(self) -> Option<&'bm PageStats> {
+        self.inner().page_stats()
+    }
+
+    fn trace(self, pid: PageId, op: TraceOp, version: Option<OlcVersion>, ok: bool) {
+        self.record_trace(pid, op, version, ok);
+        self.inner().trace(pid, op, version, ok)
+    }
+}
+
+fn check_fault<'bm, BM: CommonSeqLockBM<'bm>>(bm: BM, site: FaultSite) -> Result<(), OptimisticError> {
+    if bm.inject_fault(site) {
+        Err(OptimisticError::new())
+    } else {
+        Ok(())
+    }
+}
+
+/// Counts `event` in the page statistics of `bm`, if enabled.
+fn record<'bm, BM: CommonSeqLockBM<'bm>>(bm: BM, pid: PageId, event: PageEvent) {
+    if let Some(stats) = bm.page_stats() {
+        stats.record(pid, event);
+    }
+}
+
+/// Passes `r` through, counting an error as optimistic failure on `pid`.
+fn record_failure<'bm, BM: CommonSeqLockBM<'bm>, R>(
+    bm: BM,
+    pid: PageId,
+    r: Result<R, OptimisticError>,
+) -> Result<R, OptimisticError> {
+    if r.is_err() {
+        record(bm, pid, PageEvent::OptimisticFailure);
+    }
+    r
+}
+
+/// Starts measuring the wait for an exclusive lock, only if page statistics are enabled.
+fn wait_timer<'bm, BM: CommonSeqLockBM<'bm>>(bm: BM) -> Option<Instant> {
+    bm.page_stats().map(|_| Instant::now())
+}
+
+fn record_exclusive<'bm, BM: CommonSeqLockBM<'bm>>(bm: BM, pid: PageId, timer: Option<Instant>) {
+    record(bm, pid, PageEvent::Exclusive(timer.map_or(Duration::ZERO, |t| t.elapsed())));
+}
+
+/// Names the lock of `pid` in the reports of the `track-thread-locks` feature.
+/// The page is only inspected if `locked`, i.e. the lock is held shared or exclusively.
+fn label_page<'bm, BM: CommonSeqLockBM<'bm>>(bm: BM, pid: PageId, locked: bool) {
+    if cfg!(feature = "track-thread-locks") {
+        label_lock(bm.lock(pid), pid, if locked { bm.page_tag(pid) } else { None });
+    }
+}
+
+/// Locks a page, faulting it in and retrying until it is resident.
+/// Waits for other threads to make room if the page cannot be faulted in.
+fn lock_resident<'bm, BM: CommonSeqLockBM<'bm>, R, E>(
+    bm: BM,
+    pid: PageId,
+    lock: impl Fn(&SeqLock) -> Result<R, E>,
+    unlock: impl Fn(&SeqLock),
+) -> Result<R, E> {
+    loop {
+        match try_lock_resident(bm, pid, |l| lock(l).map(Some), &unlock)? {
+            Some(r) => return Ok(r),
+            None => std::thread::yield_now(),
+        }
+    }
+}
+
+/// Like [lock_resident], for lock attempts that may give up, which is reported as `Ok(None)`.
+/// Also gives up if the page cannot be faulted in.
+fn try_lock_resident<'bm, BM: CommonSeqLockBM<'bm>, R, E>(
+    bm: BM,
+    pid: PageId,
+    lock: impl Fn(&SeqLock) -> Result<Option<R>, E>,
+    unlock: impl Fn(&SeqLock),
+) -> Result<Option<R>, E> {
+    loop {
+        let Some(r) = lock(bm.lock(pid))? else {
+            return Ok(None);
+        };
+        if bm.is_resident(pid) {
+            return Ok(Some(r));
+        }
+        unlock(bm.lock(pid));
+        if bm.fault_in(pid).is_err() {
+            return Ok(None);
+        }
+    }
+}
+
+fn unlock_shared(lock: &SeqLock) {
+    lock.unlock_shared();
+}
+
+fn unlock_exclusive(lock: &SeqLock) {
+    lock.unlock_exclusive();
 }
 
 pub struct SimpleGuardO<'bm, BM: CommonSeqLockBM<'bm>> {
@@ -93,17 +545,61 @@ pub struct SimpleGuardS<'bm, BM: CommonSeqLockBM<'bm>> {
 }
 
 impl<'bm, BM: CommonSeqLockBM<'bm>> BufferManagerGuard<'bm, BM> for SimpleGuardS<'bm, BM> {
+    type Mode = Locked;
+
     fn acquire_wait(bm: BM, page_id: PageId) -> Self {
-        let Ok(_) = bm.lock(page_id).lock_shared(());
+        let Ok(version) = lock_resident(bm, page_id, |l| l.lock_shared(()), unlock_shared);
+        bm.trace(page_id, TraceOp::Acquire(LockMode::Shared), Some(version), true);
+        label_page(bm, page_id, true);
+        record(bm, page_id, PageEvent::Shared);
         SimpleGuardS { bm, ptr: unsafe { &*bm.page(page_id).get() } }
     }
 
     fn acquire_wait_version(bm: BM, page_id: PageId, v: OlcVersion) -> Option<Self> {
-        bm.lock(page_id).lock_shared(v).ok()?;
+        let locked = check_fault(bm, FaultSite::AcquireVersion)
+            .and_then(|()| lock_resident(bm, page_id, |l| l.lock_shared(v), unlock_shared));
+        bm.trace(page_id, TraceOp::Acquire(LockMode::Shared), Some(v), locked.is_ok());
+        record_failure(bm, page_id, locked).ok()?;
+        label_page(bm, page_id, true);
+        record(bm, page_id, PageEvent::Shared);
         Some(SimpleGuardS { bm, ptr: unsafe { &*bm.page(page_id).get() } })
     }
 
+    fn try_acquire(bm: BM, page_id: PageId) -> Result<Self, WouldBlock> {
+        let Ok(locked) = try_lock_resident(bm, page_id, |l| l.try_lock_shared(()), unlock_shared);
+        bm.trace(page_id, TraceOp::Acquire(LockMode::Shared), locked, locked.is_some());
+        locked.ok_or(WouldBlock)?;
+        label_page(bm, page_id, true);
+        record(bm, page_id, PageEvent::Shared);
+        Ok(SimpleGuardS { bm, ptr: unsafe { &*bm.page(page_id).get() } })
+    }
+
+    fn try_acquire_version(bm: BM, page_id: PageId, v: OlcVersion) -> Result<Option<Self>, WouldBlock> {
+        let locked = check_fault(bm, FaultSite::AcquireVersion)
+            .and_then(|()| try_lock_resident(bm, page_id, |l| l.try_lock_shared(v), unlock_shared));
+        bm.trace(page_id, TraceOp::Acquire(LockMode::Shared), Some(v), matches!(locked, Ok(Some(()))));
+        match record_failure(bm, page_id, locked) {
+            Ok(Some(())) => {
+                label_page(bm, page_id, true);
+                record(bm, page_id, PageEvent::Shared);
+                Ok(Some(SimpleGuardS { bm, ptr: unsafe { &*bm.page(page_id).get() } }))
+            }
+            Ok(None) => Err(WouldBlock),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn acquire_with_deadline(bm: BM, page_id: PageId, deadline: Instant) -> Result<Self, WouldBlock> {
+        let Ok(locked) = try_lock_resident(bm, page_id, |l| l.lock_shared_until((), deadline), unlock_shared);
+        bm.trace(page_id, TraceOp::Acquire(LockMode::Shared), locked, locked.is_some());
+        locked.ok_or(WouldBlock)?;
+        label_page(bm, page_id, true);
+        record(bm, page_id, PageEvent::Shared);
+        Ok(SimpleGuardS { bm, ptr: unsafe { &*bm.page(page_id).get() } })
+    }
+
     fn release(self) -> OlcVersion {
+        self.bm.trace(self.page_id(), TraceOp::Release(LockMode::Shared), None, true);
         let version = self.bm.lock(self.page_id()).unlock_shared();
         forget(self);
         version
@@ -113,8 +609,8 @@ impl<'bm, BM: CommonSeqLockBM<'bm>> BufferManagerGuard<'bm, BM> for SimpleGuardS
         self.bm.pid_from_address((self.ptr as *const BM::Page).addr())
     }
 
-    fn o_ptr(&mut self) -> OPtr<'_, BM::Page, BM::OlcEH> {
-        unsafe { OPtr::from_ref(self.ptr) }
+    fn o_ptr(&mut self) -> OPtr<'_, BM::Page, BM::OlcEH, Locked> {
+        OPtr::locked(self.ptr)
     }
 }
 
@@ -133,17 +629,64 @@ pub struct SimpleGuardX<'bm, BM: CommonSeqLockBM<'bm>> {
 }
 
 impl<'bm, BM: CommonSeqLockBM<'bm>> BufferManagerGuard<'bm, BM> for SimpleGuardX<'bm, BM> {
+    type Mode = Locked;
+
     fn acquire_wait(bm: BM, page_id: PageId) -> Self {
-        let Ok(_version) = bm.lock(page_id).lock_exclusive(());
+        let timer = wait_timer(bm);
+        let Ok(version) = lock_resident(bm, page_id, |l| l.lock_exclusive(()), unlock_exclusive);
+        bm.trace(page_id, TraceOp::Acquire(LockMode::Exclusive), Some(version), true);
+        label_page(bm, page_id, true);
+        record_exclusive(bm, page_id, timer);
         SimpleGuardX { bm, ptr: unsafe { &mut *bm.page(page_id).get() }, written: false }
     }
 
     fn acquire_wait_version(bm: BM, page_id: PageId, version: OlcVersion) -> Option<Self> {
-        bm.lock(page_id).lock_exclusive(version).ok()?;
+        let timer = wait_timer(bm);
+        let locked = check_fault(bm, FaultSite::AcquireVersion)
+            .and_then(|()| lock_resident(bm, page_id, |l| l.lock_exclusive(version), unlock_exclusive));
+        bm.trace(page_id, TraceOp::Acquire(LockMode::Exclusive), Some(version), locked.is_ok());
+        record_failure(bm, page_id, locked).ok()?;
+        label_page(bm, page_id, true);
+        record_exclusive(bm, page_id, timer);
         Some(SimpleGuardX { bm, ptr: unsafe { &mut *bm.page(page_id).get() }, written: false })
     }
 
+    fn try_acquire(bm: BM, page_id: PageId) -> Result<Self, WouldBlock> {
+        let Ok(locked) = try_lock_resident(bm, page_id, |l| l.try_lock_exclusive(()), unlock_exclusive);
+        bm.trace(page_id, TraceOp::Acquire(LockMode::Exclusive), locked, locked.is_some());
+        locked.ok_or(WouldBlock)?;
+        label_page(bm, page_id, true);
+        record(bm, page_id, PageEvent::Exclusive(Duration::ZERO));
+        Ok(SimpleGuardX { bm, ptr: unsafe { &mut *bm.page(page_id).get() }, written: false })
+    }
+
+    fn try_acquire_version(bm: BM, page_id: PageId, version: OlcVersion) -> Result<Option<Self>, WouldBlock> {
+        let locked = check_fault(bm, FaultSite::AcquireVersion)
+            .and_then(|()| try_lock_resident(bm, page_id, |l| l.try_lock_exclusive(version), unlock_exclusive));
+        bm.trace(page_id, TraceOp::Acquire(LockMode::Exclusive), Some(version), matches!(locked, Ok(Some(()))));
+        match record_failure(bm, page_id, locked) {
+            Ok(Some(())) => {
+                label_page(bm, page_id, true);
+                record(bm, page_id, PageEvent::Exclusive(Duration::ZERO));
+                Ok(Some(SimpleGuardX { bm, ptr: unsafe { &mut *bm.page(page_id).get() }, written: false }))
+            }
+            Ok(None) => Err(WouldBlock),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn acquire_with_deadline(bm: BM, page_id: PageId, deadline: Instant) -> Result<Self, WouldBlock> {
+        let timer = wait_timer(bm);
+        let Ok(locked) = try_lock_resident(bm, page_id, |l| l.lock_exclusive_until((), deadline), unlock_exclusive);
+        bm.trace(page_id, TraceOp::Acquire(LockMode::Exclusive), locked, locked.is_some());
+        locked.ok_or(WouldBlock)?;
+        label_page(bm, page_id, true);
+        record_exclusive(bm, page_id, timer);
+        Ok(SimpleGuardX { bm, ptr: unsafe { &mut *bm.page(page_id).get() }, written: false })
+    }
+
     fn release(self) -> OlcVersion {
+        self.bm.trace(self.page_id(), TraceOp::Release(LockMode::Exclusive), None, true);
         let version = self.bm.lock(self.page_id()).unlock_exclusive();
         forget(self);
         version
@@ -153,8 +696,8 @@ impl<'bm, BM: CommonSeqLockBM<'bm>> BufferManagerGuard<'bm, BM> for SimpleGuardX
         self.bm.pid_from_address((self.ptr as *const BM::Page).addr())
     }
 
-    fn o_ptr(&mut self) -> OPtr<'_, BM::Page, BM::OlcEH> {
-        OPtr::from_mut(self.ptr)
+    fn o_ptr(&mut self) -> OPtr<'_, BM::Page, BM::OlcEH, Locked> {
+        OPtr::locked(self.ptr)
     }
 }
 
@@ -164,7 +707,11 @@ impl<'bm, BM: CommonSeqLockBM<'bm>> ExclusiveGuard<'bm, BM> for SimpleGuardX<'bm
     }
 
     fn dealloc(self) {
-        self.bm.dealloc(self.page_id());
+        let pid = self.page_id();
+        // the page may be reused for a different purpose, with different lock orders
+        forget_locks(self.bm.lock(pid), 1);
+        self.bm.trace(pid, TraceOp::Dealloc, None, true);
+        self.bm.dealloc(pid);
         forget(self);
     }
 }
@@ -191,29 +738,81 @@ impl<'bm, BM: CommonSeqLockBM<'bm>> BufferManager<'bm> for BM {
     type GuardS = SimpleGuardS<'bm, Self>;
     type GuardX = SimpleGuardX<'bm, Self>;
 
-    fn alloc(self) -> Self::GuardX {
-        let pid = self.alloc();
-        SimpleGuardX { bm: self, ptr: unsafe { &mut *self.page(pid).get() }, written: false }
+    fn try_alloc(self) -> Result<Self::GuardX, OutOfPages> {
+        let pid = CommonSeqLockBM::try_alloc(self)?;
+        self.trace(pid, TraceOp::Alloc, None, true);
+        label_page(self, pid, false);
+        Ok(SimpleGuardX { bm: self, ptr: unsafe { &mut *self.page(pid).get() }, written: false })
     }
 }
 
 impl<'bm, BM: CommonSeqLockBM<'bm>> BufferManageGuardUpgrade<'bm, BM, SimpleGuardS<'bm, BM>> for SimpleGuardO<'bm, BM> {
-    fn upgrade(self) -> SimpleGuardS<'bm, BM> {
+    fn try_upgrade(self) -> Result<SimpleGuardS<'bm, BM>, OptimisticError> {
         let pid = self.bm.pid_from_address(self.ptr.to_raw().addr());
-        BM::OlcEH::optmistic_fail_check(self.bm.lock(pid).lock_shared(self.version));
-        let ret = SimpleGuardS { bm: self.bm, ptr: unsafe { &*self.bm.page(pid).get() } };
+        let locked =
+            check_fault(self.bm, FaultSite::Upgrade).and_then(|()| self.bm.lock(pid).lock_shared(self.version));
+        let bm = self.bm;
+        bm.trace(pid, TraceOp::Upgrade(LockMode::Optimistic, LockMode::Shared), Some(self.version), locked.is_ok());
         self.release_unchecked();
-        ret
+        record_failure(bm, pid, locked)?;
+        record(bm, pid, PageEvent::Shared);
+        Ok(SimpleGuardS { bm, ptr: unsafe { &*bm.page(pid).get() } })
     }
 }
 
 impl<'bm, BM: CommonSeqLockBM<'bm>> BufferManageGuardUpgrade<'bm, BM, SimpleGuardX<'bm, BM>> for SimpleGuardO<'bm, BM> {
-    fn upgrade(self) -> SimpleGuardX<'bm, BM> {
+    fn try_upgrade(self) -> Result<SimpleGuardX<'bm, BM>, OptimisticError> {
         let pid = self.bm.pid_from_address(self.ptr.to_raw().addr());
-        BM::OlcEH::optmistic_fail_check(self.bm.lock(pid).lock_exclusive(self.version));
-        let ret = SimpleGuardX { bm: self.bm, ptr: unsafe { &mut *self.bm.page(pid).get() }, written: false };
+        let timer = wait_timer(self.bm);
+        let locked =
+            check_fault(self.bm, FaultSite::Upgrade).and_then(|()| self.bm.lock(pid).lock_exclusive(self.version));
+        let bm = self.bm;
+        bm.trace(pid, TraceOp::Upgrade(LockMode::Optimistic, LockMode::Exclusive), Some(self.version), locked.is_ok());
         self.release_unchecked();
-        ret
+        record_failure(bm, pid, locked)?;
+        record_exclusive(bm, pid, timer);
+        Ok(SimpleGuardX { bm, ptr: unsafe { &mut *bm.page(pid).get() }, written: false })
+    }
+}
+
+impl<'bm, BM: CommonSeqLockBM<'bm>> BufferManageGuardUpgrade<'bm, BM, SimpleGuardX<'bm, BM>> for SimpleGuardS<'bm, BM> {
+    /// Only succeeds if no other thread holds the lock, see [SeqLock::try_upgrade_shared].
+    /// The guard is consumed either way, so on failure the shared lock is released when it is dropped.
+    fn try_upgrade(self) -> Result<SimpleGuardX<'bm, BM>, OptimisticError> {
+        let pid = self.page_id();
+        let upgraded = self.bm.lock(pid).try_upgrade_shared();
+        self.bm.trace(pid, TraceOp::Upgrade(LockMode::Shared, LockMode::Exclusive), None, upgraded.is_ok());
+        upgraded?;
+        let bm = self.bm;
+        forget(self);
+        record(bm, pid, PageEvent::Exclusive(Duration::ZERO));
+        Ok(SimpleGuardX { bm, ptr: unsafe { &mut *bm.page(pid).get() }, written: false })
+    }
+}
+
+impl<'bm, BM: CommonSeqLockBM<'bm>> BufferManageGuardDowngrade<'bm, BM, SimpleGuardS<'bm, BM>>
+    for SimpleGuardX<'bm, BM>
+{
+    fn downgrade(self) -> SimpleGuardS<'bm, BM> {
+        let pid = self.page_id();
+        let bm = self.bm;
+        let version = bm.lock(pid).downgrade_exclusive();
+        bm.trace(pid, TraceOp::Downgrade(LockMode::Shared), Some(version), true);
+        forget(self);
+        SimpleGuardS { bm, ptr: unsafe { &*bm.page(pid).get() } }
+    }
+}
+
+impl<'bm, BM: CommonSeqLockBM<'bm>> BufferManageGuardDowngrade<'bm, BM, SimpleGuardO<'bm, BM>>
+    for SimpleGuardX<'bm, BM>
+{
+    fn downgrade(self) -> SimpleGuardO<'bm, BM> {
+        let pid = self.page_id();
+        let bm = self.bm;
+        let version = bm.lock(pid).unlock_exclusive();
+        bm.trace(pid, TraceOp::Downgrade(LockMode::Optimistic), Some(version), true);
+        forget(self);
+        SimpleGuardO { bm, ptr: unsafe { OPtr::from_raw(bm.page(pid).get()) }, version }
     }
 }
 
@@ -222,11 +821,13 @@ impl<'bm, BM: CommonSeqLockBM<'bm>> OptimisticGuard<'bm, BM> for SimpleGuardO<'b
         forget(self);
     }
 
-    fn check(&self) -> OlcVersion {
-        BM::OlcEH::optmistic_fail_check(
-            self.bm.lock(self.bm.pid_from_address(self.ptr.to_raw().addr())).try_unlock_optimistic(self.version),
-        );
-        self.version
+    fn try_check(&self) -> Result<OlcVersion, OptimisticError> {
+        let pid = self.bm.pid_from_address(self.ptr.to_raw().addr());
+        let checked =
+            check_fault(self.bm, FaultSite::Check).and_then(|()| self.bm.lock(pid).try_unlock_optimistic(self.version));
+        self.bm.trace(pid, TraceOp::Validate, Some(self.version), checked.is_ok());
+        record_failure(self.bm, pid, checked)?;
+        Ok(self.version)
     }
 
     fn o_ptr_bm(&self) -> OPtr<'bm, BM::Page, BM::OlcEH> {
@@ -236,7 +837,7 @@ impl<'bm, BM: CommonSeqLockBM<'bm>> OptimisticGuard<'bm, BM> for SimpleGuardO<'b
 
 impl<'bm, BM: CommonSeqLockBM<'bm>> Drop for SimpleGuardO<'bm, BM> {
     fn drop(&mut self) {
-        match self.bm.lock(self.bm.pid_from_address(self.ptr.to_raw().addr())).try_unlock_optimistic(self.version) {
+        match self.try_check() {
             Ok(_) => (),
             Err(e) => {
                 if !BM::OlcEH::is_unwinding() {
@@ -249,6 +850,7 @@ impl<'bm, BM: CommonSeqLockBM<'bm>> Drop for SimpleGuardO<'bm, BM> {
 
 impl<'bm, BM: CommonSeqLockBM<'bm>> Drop for SimpleGuardS<'bm, BM> {
     fn drop(&mut self) {
+        self.bm.trace(self.page_id(), TraceOp::Release(LockMode::Shared), None, true);
         self.bm.lock(self.page_id()).unlock_shared();
     }
 }
@@ -258,23 +860,70 @@ impl<'bm, BM: CommonSeqLockBM<'bm>> Drop for SimpleGuardX<'bm, BM> {
         if BM::OlcEH::is_unwinding() {
             assert!(!self.written);
         }
+        self.bm.trace(self.page_id(), TraceOp::Release(LockMode::Exclusive), None, true);
         self.bm.lock(self.page_id()).unlock_exclusive();
     }
 }
 
 impl<'bm, BM: CommonSeqLockBM<'bm>> BufferManagerGuard<'bm, BM> for SimpleGuardO<'bm, BM> {
+    type Mode = Optimistic;
+
     fn acquire_wait(bm: BM, page_id: PageId) -> Self {
-        let Ok(version) = bm.lock(page_id).lock_optimistic(());
+        let Ok(version) = lock_resident(bm, page_id, |l| l.lock_optimistic(()), |_| ());
+        bm.trace(page_id, TraceOp::Acquire(LockMode::Optimistic), Some(version), true);
+        label_page(bm, page_id, false);
+        record(bm, page_id, PageEvent::Optimistic);
         SimpleGuardO { bm, ptr: unsafe { OPtr::from_raw(bm.page(page_id).get()) }, version }
     }
 
     fn acquire_wait_version(bm: BM, page_id: PageId, version: OlcVersion) -> Option<Self> {
-        bm.lock(page_id).lock_optimistic(version).ok()?;
+        let locked = check_fault(bm, FaultSite::AcquireVersion)
+            .and_then(|()| lock_resident(bm, page_id, |l| l.lock_optimistic(version), |_| ()));
+        bm.trace(page_id, TraceOp::Acquire(LockMode::Optimistic), Some(version), locked.is_ok());
+        record_failure(bm, page_id, locked).ok()?;
+        label_page(bm, page_id, false);
+        record(bm, page_id, PageEvent::Optimistic);
         Some(SimpleGuardO { bm, ptr: unsafe { OPtr::from_raw(bm.page(page_id).get()) }, version })
     }
 
+    fn try_acquire(bm: BM, page_id: PageId) -> Result<Self, WouldBlock> {
+        let Ok(version) = try_lock_resident(bm, page_id, |l| l.try_lock_optimistic(()), |_| ());
+        bm.trace(page_id, TraceOp::Acquire(LockMode::Optimistic), version, version.is_some());
+        let version = version.ok_or(WouldBlock)?;
+        label_page(bm, page_id, false);
+        record(bm, page_id, PageEvent::Optimistic);
+        Ok(SimpleGuardO { bm, ptr: unsafe { OPtr::from_raw(bm.page(page_id).get()) }, version })
+    }
+
+    fn try_acquire_version(bm: BM, page_id: PageId, version: OlcVersion) -> Result<Option<Self>, WouldBlock> {
+        let locked = check_fault(bm, FaultSite::AcquireVersion)
+            .and_then(|()| try_lock_resident(bm, page_id, |l| l.try_lock_optimistic(version), |_| ()));
+        bm.trace(page_id, TraceOp::Acquire(LockMode::Optimistic), Some(version), matches!(locked, Ok(Some(()))));
+        match record_failure(bm, page_id, locked) {
+            Ok(Some(())) => {
+                label_page(bm, page_id, false);
+                record(bm, page_id, PageEvent::Optimistic);
+                Ok(Some(SimpleGuardO { bm, ptr: unsafe { OPtr::from_raw(bm.page(page_id).get()) }, version }))
+            }
+            Ok(None) => Err(WouldBlock),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn acquire_with_deadline(bm: BM, page_id: PageId, deadline: Instant) -> Result<Self, WouldBlock> {
+        let Ok(version) = try_lock_resident(bm, page_id, |l| l.lock_optimistic_until((), deadline), |_| ());
+        bm.trace(page_id, TraceOp::Acquire(LockMode::Optimistic), version, version.is_some());
+        let version = version.ok_or(WouldBlock)?;
+        label_page(bm, page_id, false);
+        record(bm, page_id, PageEvent::Optimistic);
+        Ok(SimpleGuardO { bm, ptr: unsafe { OPtr::from_raw(bm.page(page_id).get()) }, version })
+    }
+
     fn release(self) -> OlcVersion {
-        BM::OlcEH::optmistic_fail_check(self.bm.lock(self.page_id()).try_unlock_optimistic(self.version));
+        let checked = check_fault(self.bm, FaultSite::Release)
+            .and_then(|()| self.bm.lock(self.page_id()).try_unlock_optimistic(self.version));
+        self.bm.trace(self.page_id(), TraceOp::Release(LockMode::Optimistic), Some(self.version), checked.is_ok());
+        BM::OlcEH::optmistic_fail_check(record_failure(self.bm, self.page_id(), checked));
         let version = self.version;
         forget(self);
         version
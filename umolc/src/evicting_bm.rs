@@ -0,0 +1,291 @@
+use crate::anon_mmap::AnonMmap;
+use crate::seqlock::{forget_locks, SeqLock};
+use crate::{CommonSeqLockBM, OutOfPages, PageId, PageStats, UnwindOlcEh, WouldBlock};
+use bytemuck::Zeroable;
+use std::cell::UnsafeCell;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize};
+use std::sync::Mutex;
+
+/// page is not allocated
+const STATE_FREE: u8 = 0;
+/// page is allocated and its contents are only in the backing file
+const STATE_EVICTED: u8 = 1;
+/// page is resident and is next in line for eviction
+const STATE_COLD: u8 = 2;
+/// page is resident and was accessed since the clock hand last passed it
+const STATE_HOT: u8 = 3;
+
+const NEVER_WRITTEN: u64 = u64::MAX;
+
+/// [EvictingBm::reserve_frame] gives up after this many clock sweeps without finding an unlocked cold page.
+const MAX_FAILED_SWEEPS: u32 = 1024;
+
+/// A buffer manager that can hold more pages than fit into memory.
+///
+/// Address space for all `capacity` pages is reserved up front, but at most `frames` pages are resident at a time.
+/// When a new frame is needed, a clock sweep picks a cold page that is not locked, writes it to the backing file if it
+/// was modified and releases its memory.
+/// Evicting a page takes its exclusive lock, so optimistic readers of an evicted page fail validation.
+/// Locking an evicted page loads it from the backing file.
+///
+/// Frames are only reclaimed from unlocked pages, so `frames` must comfortably exceed the number of pages locked
+/// concurrently by all threads.
+/// If no frame can be reclaimed for a while, allocation fails with [OutOfPages] and loading an evicted page panics.
+pub struct EvictingBm<P> {
+    memory: AnonMmap,
+    capacity: usize,
+    frames: usize,
+    file: File,
+    locks: Box<[SeqLock]>,
+    states: Box<[AtomicU8]>,
+    /// version of each page when it was last loaded or written back
+    clean_versions: Box<[AtomicU64]>,
+    resident: AtomicUsize,
+    clock_hand: AtomicUsize,
+    evictions: AtomicU64,
+    faults: AtomicU64,
+    free_list: Mutex<Vec<usize>>,
+    page_stats: Option<PageStats>,
+    _p: PhantomData<P>,
+}
+
+unsafe impl<P> Sync for EvictingBm<P> {}
+unsafe impl<P> Send for EvictingBm<P> {}
+
+impl<P: Zeroable> EvictingBm<P> {
+    /// Creates a buffer manager for up to `capacity` pages, using the file at `path` to hold evicted pages.
+    /// Any existing file at `path` is truncated.
+    pub fn new(path: impl AsRef<Path>, capacity: usize, frames: usize) -> io::Result<Self> {
+        assert!(frames > 0);
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        let memory = AnonMmap::reserve(capacity * size_of::<P>())?;
+        assert_eq!(memory.as_ptr().addr() % align_of::<P>(), 0);
+        unsafe {
+            Ok(EvictingBm {
+                memory,
+                capacity,
+                frames,
+                file,
+                locks: Box::<[MaybeUninit<_>]>::assume_init(Box::new_zeroed_slice(capacity)),
+                states: Box::<[MaybeUninit<_>]>::assume_init(Box::new_zeroed_slice(capacity)),
+                clean_versions: (0..capacity).map(|_| AtomicU64::new(NEVER_WRITTEN)).collect(),
+                resident: AtomicUsize::new(0),
+                clock_hand: AtomicUsize::new(0),
+                evictions: AtomicU64::new(0),
+                faults: AtomicU64::new(0),
+                free_list: Mutex::new((0..capacity).collect()),
+                page_stats: None,
+                _p: PhantomData,
+            })
+        }
+    }
+}
+
+impl<P> EvictingBm<P> {
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Starts counting lock acquisitions, optimistic failures and exclusive wait time for every page,
+    /// see [CommonSeqLockBM::page_stats].
+    pub fn enable_page_stats(&mut self) {
+        self.page_stats = Some(PageStats::new(self.capacity));
+    }
+
+    /// number of pages currently held in memory
+    pub fn resident_count(&self) -> usize {
+        self.resident.load(Relaxed)
+    }
+
+    /// number of pages removed from memory to free up a frame
+    pub fn eviction_count(&self) -> u64 {
+        self.evictions.load(Relaxed)
+    }
+
+    /// number of pages loaded from the backing file
+    pub fn fault_count(&self) -> u64 {
+        self.faults.load(Relaxed)
+    }
+
+    fn page_ptr(&self, pid: usize) -> *mut P {
+        debug_assert!(pid < self.capacity);
+        unsafe { (self.memory.as_ptr() as *mut P).add(pid) }
+    }
+
+    fn page_bytes(&self, pid: usize) -> *mut [u8] {
+        std::ptr::slice_from_raw_parts_mut(self.page_ptr(pid) as *mut u8, size_of::<P>())
+    }
+
+    fn file_offset(pid: usize) -> u64 {
+        (pid * size_of::<P>()) as u64
+    }
+
+    /// Claims a frame for a page that is about to become resident, evicting other pages if necessary.
+    /// Fails if all resident pages stay locked for [MAX_FAILED_SWEEPS] sweeps.
+    fn reserve_frame(&self) -> Result<(), OutOfPages> {
+        let mut fails = 0;
+        loop {
+            let resident = self.resident.load(Relaxed);
+            if resident < self.frames {
+                if self.resident.compare_exchange_weak(resident, resident + 1, Relaxed, Relaxed).is_ok() {
+                    return Ok(());
+                }
+                continue;
+            }
+            if self.evict_one() {
+                continue;
+            }
+            fails += 1;
+            if fails >= MAX_FAILED_SWEEPS {
+                return Err(OutOfPages);
+            }
+            if fails > 16 {
+                std::thread::yield_now();
+            }
+        }
+    }
+
+    /// Advances the clock hand by up to two rounds and evicts the first cold page that can be locked.
+    fn evict_one(&self) -> bool {
+        for _ in 0..2 * self.capacity {
+            let pid = self.clock_hand.fetch_add(1, Relaxed) % self.capacity;
+            match self.states[pid].load(Relaxed) {
+                STATE_HOT => {
+                    let _ = self.states[pid].compare_exchange(STATE_HOT, STATE_COLD, Relaxed, Relaxed);
+                }
+                STATE_COLD => {
+                    if let Ok(Some(version)) = self.locks[pid].try_lock_exclusive(()) {
+                        // page may have been freed before we locked it
+                        let resident = self.states[pid].load(Relaxed) >= STATE_COLD;
+                        if resident {
+                            self.evict_locked(pid, version.x);
+                        }
+                        self.locks[pid].unlock_exclusive();
+                        if resident {
+                            return true;
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        false
+    }
+
+    /// Caller must hold the exclusive lock and `version` must be the version it was acquired at.
+    fn evict_locked(&self, pid: usize, version: u64) {
+        if self.clean_versions[pid].load(Relaxed) != version {
+            let bytes = unsafe { &*self.page_bytes(pid) };
+            self.file.write_all_at(bytes, Self::file_offset(pid)).expect("failed to write back page");
+        }
+        self.memory.release(pid * size_of::<P>(), size_of::<P>());
+        self.states[pid].store(STATE_EVICTED, Release);
+        self.resident.fetch_sub(1, Relaxed);
+        self.evictions.fetch_add(1, Relaxed);
+    }
+}
+
+impl<P> Drop for EvictingBm<P> {
+    fn drop(&mut self) {
+        forget_locks(self.locks.as_ptr(), self.locks.len());
+    }
+}
+
+impl<'bm, P> CommonSeqLockBM<'bm> for &'bm EvictingBm<P> {
+    type Page = P;
+    type OlcEH = UnwindOlcEh;
+
+    fn pid_from_address(self, address: usize) -> PageId {
+        let start = self.memory.as_ptr().addr();
+        debug_assert!(address >= start);
+        debug_assert!(address < start + size_of::<P>() * self.capacity);
+        let offset = address - start;
+        assert_eq!(offset % size_of::<P>(), 0);
+        PageId { x: (offset / size_of::<P>()) as u64 }
+    }
+
+    fn try_alloc(self) -> Result<PageId, OutOfPages> {
+        let pid = self.free_list.lock().unwrap().pop().ok_or(OutOfPages)?;
+        if let Err(e) = self.reserve_frame() {
+            self.free_list.lock().unwrap().push(pid);
+            return Err(e);
+        }
+        self.locks[pid].force_lock_exclusive();
+        debug_assert_eq!(self.states[pid].load(Relaxed), STATE_FREE);
+        self.clean_versions[pid].store(NEVER_WRITTEN, Relaxed);
+        self.states[pid].store(STATE_HOT, Release);
+        Ok(PageId { x: pid as u64 })
+    }
+
+    fn dealloc(self, pid: PageId) {
+        let pid = pid.x as usize;
+        self.memory.release(pid * size_of::<P>(), size_of::<P>());
+        self.states[pid].store(STATE_FREE, Release);
+        self.resident.fetch_sub(1, Relaxed);
+        self.locks[pid].unlock_exclusive();
+        self.free_list.lock().unwrap().push(pid)
+    }
+
+    fn page(self, pid: PageId) -> &'bm UnsafeCell<Self::Page> {
+        unsafe { &*(self.page_ptr(pid.x as usize) as *const UnsafeCell<P>) }
+    }
+
+    fn lock(self, pid: PageId) -> &'bm SeqLock {
+        &self.locks[pid.x as usize]
+    }
+
+    fn is_resident(self, pid: PageId) -> bool {
+        let state = &self.states[pid.x as usize];
+        match state.load(Acquire) {
+            STATE_EVICTED => false,
+            STATE_COLD => {
+                let _ = state.compare_exchange(STATE_COLD, STATE_HOT, Relaxed, Relaxed);
+                true
+            }
+            // free pages are never loaded, optimistic readers may see arbitrary contents as with other buffer managers
+            _ => true,
+        }
+    }
+
+    fn fault_in(self, pid: PageId) -> Result<(), WouldBlock> {
+        let pid = pid.x as usize;
+        let version = loop {
+            if self.states[pid].load(Acquire) != STATE_EVICTED {
+                return Ok(());
+            }
+            if let Ok(Some(v)) = self.locks[pid].try_lock_exclusive(()) {
+                break v;
+            }
+            std::thread::yield_now();
+        };
+        if self.states[pid].load(Relaxed) == STATE_EVICTED {
+            if self.reserve_frame().is_err() {
+                self.locks[pid].unlock_exclusive();
+                return Err(WouldBlock);
+            }
+            let bytes = unsafe { &mut *self.page_bytes(pid) };
+            self.file.read_exact_at(bytes, EvictingBm::<P>::file_offset(pid)).expect("failed to read page");
+            // unlocking bumps the version
+            self.clean_versions[pid].store(version.x + 1, Relaxed);
+            self.states[pid].store(STATE_HOT, Release);
+            self.faults.fetch_add(1, Relaxed);
+        }
+        self.locks[pid].unlock_exclusive();
+        Ok(())
+    }
+
+    fn page_stats(self) -> Option<&'bm PageStats> {
+        self.page_stats.as_ref()
+    }
+}
@@ -0,0 +1,114 @@
+use crate::WrappingBm;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+
+/// Optimistic operations a [FaultBm] can make fail.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FaultSite {
+    /// Validating an optimistic guard, including when it is dropped.
+    Check,
+    /// Releasing an optimistic guard with `release`.
+    Release,
+    /// Upgrading an optimistic guard to a shared or exclusive guard.
+    Upgrade,
+    /// Locking a page at an expected version with `acquire_wait_version`.
+    AcquireVersion,
+}
+
+const SITES: [FaultSite; 4] = [FaultSite::Check, FaultSite::Release, FaultSite::Upgrade, FaultSite::AcquireVersion];
+
+/// Wraps another buffer manager and makes optimistic operations fail at random, as if the page had been modified
+/// concurrently.
+/// This exercises restart paths that are rarely taken under real contention.
+///
+/// Faults are drawn from a random number generator seeded on construction, so a single-threaded sequence of
+/// operations fails at the same points on every run.
+/// To target a single call site instead, [FaultBm::fail_call] makes one numbered call fail.
+/// No faults are injected until a rate or call is set.
+pub struct FaultBm<B> {
+    inner: B,
+    rng: AtomicU64,
+    /// faults are injected if the next random number is below the threshold
+    thresholds: [AtomicU64; SITES.len()],
+    /// number of the call that fails, 0 for none
+    fail_at: [AtomicU64; SITES.len()],
+    calls: [AtomicU64; SITES.len()],
+    injected: [AtomicU64; SITES.len()],
+}
+
+impl<B> FaultBm<B> {
+    pub fn new(inner: B, seed: u64) -> Self {
+        FaultBm {
+            inner,
+            rng: AtomicU64::new(seed),
+            thresholds: Default::default(),
+            fail_at: Default::default(),
+            calls: Default::default(),
+            injected: Default::default(),
+        }
+    }
+
+    /// Makes operations at `site` fail with probability `rate`, which is clamped to `0.0..=1.0`.
+    /// Operations are restarted until they get through, so a rate of 1 can keep them restarting forever.
+    pub fn set_rate(&self, site: FaultSite, rate: f64) {
+        let threshold = (rate.clamp(0.0, 1.0) * u64::MAX as f64) as u64;
+        self.thresholds[site as usize].store(threshold, Relaxed);
+    }
+
+    /// Sets the same rate for all sites.
+    pub fn set_rates(&self, rate: f64) {
+        for site in SITES {
+            self.set_rate(site, rate);
+        }
+    }
+
+    /// Makes the `n`th operation at `site` fail, counting from 1 like [FaultBm::calls].
+    /// This is independent of the rate, a later call replaces the target and 0 clears it.
+    pub fn fail_call(&self, site: FaultSite, n: u64) {
+        self.fail_at[site as usize].store(n, Relaxed);
+    }
+
+    /// Number of operations at `site` so far, whether they failed or not.
+    pub fn calls(&self, site: FaultSite) -> u64 {
+        self.calls[site as usize].load(Relaxed)
+    }
+
+    /// Number of faults injected at `site` so far.
+    pub fn injected(&self, site: FaultSite) -> u64 {
+        self.injected[site as usize].load(Relaxed)
+    }
+
+    fn roll(&self, site: FaultSite) -> bool {
+        let call = self.calls[site as usize].fetch_add(1, Relaxed) + 1;
+        let fail = call == self.fail_at[site as usize].load(Relaxed) || self.roll_random(site);
+        if fail {
+            self.injected[site as usize].fetch_add(1, Relaxed);
+        }
+        fail
+    }
+
+    fn roll_random(&self, site: FaultSite) -> bool {
+        let threshold = self.thresholds[site as usize].load(Relaxed);
+        if threshold == 0 {
+            return false;
+        }
+        // splitmix64
+        let mut x = self.rng.fetch_add(0x9e3779b97f4a7c15, Relaxed).wrapping_add(0x9e3779b97f4a7c15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+        x ^= x >> 31;
+        threshold == u64::MAX || x < threshold
+    }
+}
+
+impl<B: Sync> WrappingBm for FaultBm<B> {
+    type Inner = B;
+
+    fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    fn fault(&self, site: FaultSite) -> bool {
+        self.roll(site)
+    }
+}
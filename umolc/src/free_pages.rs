@@ -0,0 +1,125 @@
+use crate::OutOfPages;
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Mutex;
+
+/// number of pages moved between a shard and the shared pool at once
+const BATCH: usize = 32;
+const MAX_SHARDS: usize = 64;
+
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+std::thread_local! {
+    static THREAD_SHARD: usize = NEXT_SHARD.fetch_add(1, Relaxed);
+}
+
+/// separate cache lines, so threads working on different shards do not contend
+#[repr(align(128))]
+struct Shard(Mutex<VecDeque<usize>>);
+
+/// A free list of page ids split into per-thread shards backed by a shared pool.
+///
+/// Threads are assigned shards round-robin, so with no more threads than shards every thread has its own cache.
+/// A shard is refilled from the pool and returns pages to it in batches of [BATCH].
+/// Before reporting that no page is left, pages are taken from other shards.
+///
+/// Within each shard and in the pool, the back holds the most recently freed pages.
+pub(crate) struct FreePages {
+    pool: Mutex<VecDeque<usize>>,
+    shards: Box<[Shard]>,
+    prefer_recent: bool,
+}
+
+impl FreePages {
+    pub fn new(free: impl IntoIterator<Item = usize>) -> Self {
+        let shards = std::thread::available_parallelism().map_or(1, |n| n.get()).next_power_of_two().min(MAX_SHARDS);
+        FreePages {
+            pool: Mutex::new(free.into_iter().collect()),
+            shards: (0..shards).map(|_| Shard(Mutex::new(VecDeque::new()))).collect(),
+            prefer_recent: true,
+        }
+    }
+
+    /// If true, the most recently freed pages are handed out first, which tends to keep them in the CPU cache.
+    /// Otherwise, the least recently freed pages are.
+    pub fn set_prefer_recent(&mut self, prefer_recent: bool) {
+        self.prefer_recent = prefer_recent;
+    }
+
+    fn own_shard(&self) -> &Mutex<VecDeque<usize>> {
+        &self.shards[THREAD_SHARD.with(|s| *s) % self.shards.len()].0
+    }
+
+    fn take(&self, pages: &mut VecDeque<usize>) -> Option<usize> {
+        if self.prefer_recent {
+            pages.pop_back()
+        } else {
+            pages.pop_front()
+        }
+    }
+
+    /// Takes a free page.
+    /// If no page is free anywhere, `grow` is called with the locked pool to add more pages.
+    pub fn pop(
+        &self,
+        mut grow: impl FnMut(&mut VecDeque<usize>) -> Result<(), OutOfPages>,
+    ) -> Result<usize, OutOfPages> {
+        loop {
+            {
+                let mut shard = self.own_shard().lock().unwrap();
+                if let Some(pid) = self.take(&mut shard) {
+                    return Ok(pid);
+                }
+                let mut pool = self.pool.lock().unwrap();
+                for _ in 0..BATCH {
+                    let Some(pid) = self.take(&mut pool) else {
+                        break;
+                    };
+                    // keep the order in which they are taken
+                    if self.prefer_recent {
+                        shard.push_front(pid);
+                    } else {
+                        shard.push_back(pid);
+                    }
+                }
+                if let Some(pid) = self.take(&mut shard) {
+                    return Ok(pid);
+                }
+            }
+            if let Some(pid) = self.steal() {
+                return Ok(pid);
+            }
+            let mut pool = self.pool.lock().unwrap();
+            if pool.is_empty() {
+                grow(&mut pool)?;
+            }
+        }
+    }
+
+    /// Takes a page from any shard.
+    /// Must not be called while holding a shard lock.
+    fn steal(&self) -> Option<usize> {
+        self.shards.iter().find_map(|s| self.take(&mut s.0.lock().unwrap()))
+    }
+
+    pub fn push(&self, pid: usize) {
+        let mut shard = self.own_shard().lock().unwrap();
+        shard.push_back(pid);
+        if shard.len() > 2 * BATCH {
+            // the oldest pages are the least likely to be cached
+            let mut pool = self.pool.lock().unwrap();
+            pool.extend(shard.drain(..BATCH));
+        }
+    }
+
+    /// Collects all free pages.
+    /// The result is only exact if no pages are allocated or freed concurrently.
+    pub fn to_vec(&self) -> Vec<usize> {
+        let mut free: Vec<usize> = self.pool.lock().unwrap().iter().copied().collect();
+        for shard in &self.shards {
+            free.extend(shard.0.lock().unwrap().iter());
+        }
+        free
+    }
+}
@@ -6,38 +6,99 @@
 #![feature(maybe_uninit_slice)]
 
 use bytemuck::{Pod, Zeroable};
-pub use o_ptr::OPtr;
+pub use o_ptr::{Locked, OPtr, OPtrMode, Optimistic};
 pub use optimistic_error::{OlcErrorHandler, OptimisticError};
+use std::fmt::{Display, Formatter};
 use std::ops::{Deref, DerefMut};
+use std::time::Instant;
 
+mod anon_mmap;
 mod buffer_manager;
+mod evicting_bm;
+mod fault_bm;
+mod free_pages;
+mod local_bm;
+#[cfg(feature = "track-thread-locks")]
+mod lock_tracking;
+mod mmap_bm;
 mod o_ptr;
 mod optimistic_error;
+mod page_stats;
+mod restart_stats;
 mod seqlock;
+mod trace_bm;
+mod vm_bm;
 
 pub use buffer_manager::*;
-pub use optimistic_error::{PanicOlcEh, UnwindOlcEh};
+pub use evicting_bm::EvictingBm;
+pub use fault_bm::{FaultBm, FaultSite};
+pub use local_bm::{LocalBm, LocalGuardO, LocalGuardS, LocalGuardX};
+#[cfg(feature = "track-thread-locks")]
+pub use lock_tracking::{assert_no_locks_held, take_leaked_locks};
+pub use mmap_bm::MmapBm;
+pub use optimistic_error::{InfallibleOlcEh, PanicOlcEh, UnwindOlcEh};
+pub use page_stats::{PageHeat, PageStats};
+pub use restart_stats::{RestartStats, RESTART_BUCKETS};
+pub use seqlock::{set_wait_strategy, wait_strategy, WaitStrategy};
+pub use trace_bm::{LockMode, TraceBm, TraceEvent, TraceOp};
+pub use vm_bm::{VmBm, VmBmConfig};
 
 #[derive(Eq, PartialEq, Clone, Copy)]
 pub struct OlcVersion {
     pub x: u64,
 }
 
-#[derive(Debug, Zeroable, Copy, Clone, Eq, PartialEq, Pod)]
+#[derive(Debug, Zeroable, Copy, Clone, Eq, PartialEq, Hash, Pod)]
 #[repr(transparent)]
 pub struct PageId {
     pub x: u64,
 }
 
-pub trait   BufferManager<'bm>: 'bm + Copy + Send + Sync + Sized {
+/// Returned by fallible allocations when the buffer manager has no free page left.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct OutOfPages;
+
+impl Display for OutOfPages {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("out of pages")
+    }
+}
+
+impl std::error::Error for OutOfPages {}
+
+/// Returned by lock acquisitions that give up instead of waiting for a conflicting lock to be released.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct WouldBlock;
+
+impl Display for WouldBlock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("lock is held in a conflicting mode")
+    }
+}
+
+impl std::error::Error for WouldBlock {}
+
+/// Buffer managers that can be shared between threads are `Send + Sync`, but this is not required, see [LocalBm].
+pub trait BufferManager<'bm>: 'bm + Copy + Sized {
     type Page;
     type GuardO: OptimisticGuard<'bm, Self>
+        + BufferManagerGuard<'bm, Self, Mode = Optimistic>
         + BufferManageGuardUpgrade<'bm, Self, Self::GuardS>
         + BufferManageGuardUpgrade<'bm, Self, Self::GuardX>;
-    type GuardS: BufferManagerGuard<'bm, Self> + Deref<Target = Self::Page>;
-    type GuardX: ExclusiveGuard<'bm, Self> + Deref<Target = Self::Page> + DerefMut;
+    type GuardS: BufferManagerGuard<'bm, Self, Mode = Locked>
+        + BufferManageGuardUpgrade<'bm, Self, Self::GuardX>
+        + Deref<Target = Self::Page>;
+    type GuardX: ExclusiveGuard<'bm, Self>
+        + BufferManagerGuard<'bm, Self, Mode = Locked>
+        + BufferManageGuardDowngrade<'bm, Self, Self::GuardS>
+        + BufferManageGuardDowngrade<'bm, Self, Self::GuardO>
+        + Deref<Target = Self::Page>
+        + DerefMut;
     type OlcEH: OlcErrorHandler;
-    fn alloc(self) -> Self::GuardX;
+    fn try_alloc(self) -> Result<Self::GuardX, OutOfPages>;
+    fn alloc(self) -> Self::GuardX {
+        self.try_alloc().unwrap_or_else(|e| panic!("{e}"))
+    }
     #[deprecated]
     fn free(self, g: Self::GuardX) {
         g.dealloc();
@@ -53,6 +114,38 @@ pub trait BufferManagerExt<'bm>: BufferManager<'bm> {
         }
     }
 
+    /// Like [Self::repeat], but gives up and returns `None` once `f` failed more than `limit` times in a row,
+    /// so the caller can fall back to pessimistic locking.
+    /// The number of failures is recorded in `stats`.
+    /// Returns `None` without running `f` if [OlcErrorHandler::CAN_CATCH] is `false`.
+    fn repeat_limited<R>(stats: &RestartStats, limit: Option<u32>, mut f: impl FnMut() -> R) -> Option<R> {
+        if !Self::OlcEH::CAN_CATCH {
+            return None;
+        }
+        Self::repeat_limited_result(stats, limit, || Self::OlcEH::catch(&mut f))
+    }
+
+    /// Like [Self::repeat_limited], but for operations that return optimistic errors as `Err`.
+    /// These are restarted without unwinding.
+    fn repeat_limited_result<R>(
+        stats: &RestartStats,
+        limit: Option<u32>,
+        mut f: impl FnMut() -> Result<R, OptimisticError>,
+    ) -> Option<R> {
+        let mut failures = 0;
+        loop {
+            if let Ok(x) = f() {
+                stats.record(failures, false);
+                return Some(x);
+            }
+            failures += 1;
+            if limit.is_some_and(|l| failures > l) {
+                stats.record(failures, true);
+                return None;
+            }
+        }
+    }
+
     fn lock_optimistic(self, pid: PageId) -> Self::GuardO {
         Self::GuardO::acquire_wait(self, pid)
     }
@@ -67,11 +160,19 @@ pub trait BufferManagerExt<'bm>: BufferManager<'bm> {
 impl<'bm, BM: BufferManager<'bm>> BufferManagerExt<'bm> for BM {}
 
 pub trait BufferManagerGuard<'bm, B: BufferManager<'bm>>: Sized {
+    /// [Locked] if the guard holds a shared or exclusive lock, so reads through [Self::o_ptr] need no validation.
+    type Mode: OPtrMode;
     fn acquire_wait(bm: B, page_id: PageId) -> Self;
     fn acquire_wait_version(bm: B, page_id: PageId, v: OlcVersion) -> Option<Self>;
+    /// Like [Self::acquire_wait], but fails instead of waiting if the page is locked in a conflicting mode.
+    fn try_acquire(bm: B, page_id: PageId) -> Result<Self, WouldBlock>;
+    /// Like [Self::acquire_wait_version], but fails instead of waiting if the page is locked in a conflicting mode.
+    fn try_acquire_version(bm: B, page_id: PageId, v: OlcVersion) -> Result<Option<Self>, WouldBlock>;
+    /// Like [Self::acquire_wait], but gives up once `deadline` has passed.
+    fn acquire_with_deadline(bm: B, page_id: PageId, deadline: Instant) -> Result<Self, WouldBlock>;
     fn release(self) -> OlcVersion;
     fn page_id(&self) -> PageId;
-    fn o_ptr(&mut self) -> OPtr<'_, B::Page, B::OlcEH>;
+    fn o_ptr(&mut self) -> OPtr<'_, B::Page, B::OlcEH, Self::Mode>;
 }
 
 pub trait OptimisticGuard<'bm, BM: BufferManager<'bm>>: BufferManagerGuard<'bm, BM> + Clone {
@@ -79,7 +180,16 @@ pub trait OptimisticGuard<'bm, BM: BufferManager<'bm>>: BufferManagerGuard<'bm,
         std::mem::forget(self)
     }
     fn check(&self) -> OlcVersion {
-        self.clone().release()
+        BM::OlcEH::optmistic_fail_check(self.try_check())
+    }
+    /// Like [Self::check], but returns a failed validation as `Err` instead of failing through [BufferManager::OlcEH].
+    fn try_check(&self) -> Result<OlcVersion, OptimisticError>;
+    /// Validates and releases the guard.
+    /// Unlike dropping the guard, this never fails through [BufferManager::OlcEH].
+    fn try_release(self) -> Result<OlcVersion, OptimisticError> {
+        let r = self.try_check();
+        self.release_unchecked();
+        r
     }
     fn o_ptr_bm(&self) -> OPtr<'bm, BM::Page, BM::OlcEH>;
 }
@@ -90,5 +200,17 @@ pub trait ExclusiveGuard<'bm, BM: BufferManager<'bm>>: BufferManagerGuard<'bm, B
 }
 
 pub trait BufferManageGuardUpgrade<'bm, B: BufferManager<'bm>, Target>: Sized {
-    fn upgrade(self) -> Target;
+    fn upgrade(self) -> Target {
+        B::OlcEH::optmistic_fail_check(self.try_upgrade())
+    }
+    /// Like [Self::upgrade], but returns a failed validation as `Err` instead of failing through
+    /// [BufferManager::OlcEH].
+    fn try_upgrade(self) -> Result<Target, OptimisticError>;
+}
+
+/// Converts an exclusive guard into a weaker guard without unlocking in between.
+/// The target guard continues at the version that releasing the exclusive guard would have returned,
+/// so no other writer can modify the page between the two guards.
+pub trait BufferManageGuardDowngrade<'bm, B: BufferManager<'bm>, Target>: Sized {
+    fn downgrade(self) -> Target;
 }
@@ -0,0 +1,395 @@
+use crate::{
+    BufferManageGuardDowngrade, BufferManageGuardUpgrade, BufferManager, BufferManagerGuard, ExclusiveGuard,
+    InfallibleOlcEh, Locked, OPtr, OlcVersion, Optimistic, OptimisticError, OptimisticGuard, OutOfPages, PageId,
+    WouldBlock,
+};
+use bytemuck::Zeroable;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::mem::{forget, MaybeUninit};
+use std::ops::{Deref, DerefMut};
+use std::time::Instant;
+
+/// Lock state of a page, only ever accessed by the thread owning the buffer manager.
+#[derive(Default)]
+struct LocalLock {
+    version: Cell<u64>,
+    shared: Cell<u32>,
+    exclusive: Cell<bool>,
+}
+
+/// A buffer manager for trees that are only ever used by a single thread.
+///
+/// Locks are plain counters instead of atomics and optimistic guards never fail validation, so operations run
+/// without restarts or unwinding.
+/// `LocalBm` is `!Sync`, so neither it nor a tree using it can be shared with other threads.
+///
+/// As there is no other thread to wait for, acquiring a lock that conflicts with one held by the same thread panics
+/// instead of deadlocking.
+/// Non-blocking and deadline-bounded acquisitions fail immediately on such conflicts instead.
+pub struct LocalBm<P> {
+    pages: Box<[UnsafeCell<P>]>,
+    locks: Box<[LocalLock]>,
+    free_pages: RefCell<Vec<usize>>,
+}
+
+impl<P: Zeroable> LocalBm<P> {
+    pub fn new(capacity: usize) -> Self {
+        LocalBm {
+            pages: unsafe { Box::<[MaybeUninit<_>]>::assume_init(Box::new_zeroed_slice(capacity)) },
+            locks: (0..capacity).map(|_| LocalLock::default()).collect(),
+            // lowest page ids are handed out first
+            free_pages: RefCell::new((0..capacity).rev().collect()),
+        }
+    }
+}
+
+impl<P> LocalBm<P> {
+    pub fn capacity(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Number of pages currently allocated.
+    pub fn live_pages(&self) -> usize {
+        self.capacity() - self.free_pages.borrow().len()
+    }
+
+    fn lock(&self, pid: usize) -> &LocalLock {
+        &self.locks[pid]
+    }
+
+    fn page(&self, pid: usize) -> *mut P {
+        self.pages[pid].get()
+    }
+
+    fn lock_optimistic(&self, pid: usize) -> u64 {
+        let lock = self.lock(pid);
+        assert!(!lock.exclusive.get(), "cannot lock page {pid} optimistically while it is locked exclusively");
+        lock.version.get()
+    }
+
+    fn lock_shared(&self, pid: usize) {
+        let lock = self.lock(pid);
+        assert!(!lock.exclusive.get(), "cannot lock page {pid} shared while it is locked exclusively");
+        lock.shared.set(lock.shared.get() + 1);
+    }
+
+    fn lock_exclusive(&self, pid: usize) {
+        let lock = self.lock(pid);
+        assert!(
+            !lock.exclusive.get() && lock.shared.get() == 0,
+            "cannot lock page {pid} exclusively while it is locked"
+        );
+        lock.exclusive.set(true);
+    }
+
+    /// Whether `pid` can be locked in the given mode, `Some(true)` being exclusive and `None` optimistic.
+    fn can_lock(&self, pid: usize, exclusive: Option<bool>) -> Result<(), WouldBlock> {
+        let lock = self.lock(pid);
+        let conflict = lock.exclusive.get() || (exclusive == Some(true) && lock.shared.get() != 0);
+        if conflict {
+            Err(WouldBlock)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn unlock_shared(&self, pid: usize) -> OlcVersion {
+        let lock = self.lock(pid);
+        lock.shared.set(lock.shared.get() - 1);
+        OlcVersion { x: lock.version.get() }
+    }
+
+    fn unlock_exclusive(&self, pid: usize) -> OlcVersion {
+        let lock = self.lock(pid);
+        debug_assert!(lock.exclusive.get());
+        lock.exclusive.set(false);
+        lock.version.set(lock.version.get() + 1);
+        OlcVersion { x: lock.version.get() }
+    }
+}
+
+impl<'bm, P: Zeroable + 'bm> BufferManager<'bm> for &'bm LocalBm<P> {
+    type Page = P;
+    type GuardO = LocalGuardO<'bm, P>;
+    type GuardS = LocalGuardS<'bm, P>;
+    type GuardX = LocalGuardX<'bm, P>;
+    type OlcEH = InfallibleOlcEh;
+
+    fn try_alloc(self) -> Result<Self::GuardX, OutOfPages> {
+        let pid = self.free_pages.borrow_mut().pop().ok_or(OutOfPages)?;
+        self.lock_exclusive(pid);
+        Ok(LocalGuardX { bm: self, pid })
+    }
+}
+
+pub struct LocalGuardO<'bm, P> {
+    bm: &'bm LocalBm<P>,
+    pid: usize,
+    version: u64,
+}
+
+impl<P> Clone for LocalGuardO<'_, P> {
+    fn clone(&self) -> Self {
+        LocalGuardO { bm: self.bm, pid: self.pid, version: self.version }
+    }
+}
+
+impl<'bm, P: Zeroable + 'bm> BufferManagerGuard<'bm, &'bm LocalBm<P>> for LocalGuardO<'bm, P> {
+    type Mode = Optimistic;
+
+    fn acquire_wait(bm: &'bm LocalBm<P>, page_id: PageId) -> Self {
+        let pid = page_id.x as usize;
+        LocalGuardO { bm, pid, version: bm.lock_optimistic(pid) }
+    }
+
+    fn acquire_wait_version(bm: &'bm LocalBm<P>, page_id: PageId, v: OlcVersion) -> Option<Self> {
+        let guard = Self::acquire_wait(bm, page_id);
+        (guard.version == v.x).then_some(guard)
+    }
+
+    fn try_acquire(bm: &'bm LocalBm<P>, page_id: PageId) -> Result<Self, WouldBlock> {
+        bm.can_lock(page_id.x as usize, None)?;
+        Ok(Self::acquire_wait(bm, page_id))
+    }
+
+    fn try_acquire_version(bm: &'bm LocalBm<P>, page_id: PageId, v: OlcVersion) -> Result<Option<Self>, WouldBlock> {
+        bm.can_lock(page_id.x as usize, None)?;
+        Ok(Self::acquire_wait_version(bm, page_id, v))
+    }
+
+    fn acquire_with_deadline(bm: &'bm LocalBm<P>, page_id: PageId, _deadline: Instant) -> Result<Self, WouldBlock> {
+        Self::try_acquire(bm, page_id)
+    }
+
+    fn release(self) -> OlcVersion {
+        self.check()
+    }
+
+    fn page_id(&self) -> PageId {
+        PageId { x: self.pid as u64 }
+    }
+
+    fn o_ptr(&mut self) -> OPtr<'_, P, InfallibleOlcEh> {
+        self.o_ptr_bm()
+    }
+}
+
+impl<'bm, P: Zeroable + 'bm> OptimisticGuard<'bm, &'bm LocalBm<P>> for LocalGuardO<'bm, P> {
+    fn try_check(&self) -> Result<OlcVersion, OptimisticError> {
+        if self.bm.lock(self.pid).version.get() != self.version {
+            return Err(OptimisticError::new());
+        }
+        Ok(OlcVersion { x: self.version })
+    }
+
+    fn o_ptr_bm(&self) -> OPtr<'bm, P, InfallibleOlcEh> {
+        unsafe { OPtr::from_raw(self.bm.page(self.pid)) }
+    }
+}
+
+impl<'bm, P: Zeroable + 'bm> BufferManageGuardUpgrade<'bm, &'bm LocalBm<P>, LocalGuardS<'bm, P>>
+    for LocalGuardO<'bm, P>
+{
+    fn try_upgrade(self) -> Result<LocalGuardS<'bm, P>, OptimisticError> {
+        self.try_check()?;
+        self.bm.lock_shared(self.pid);
+        Ok(LocalGuardS { bm: self.bm, pid: self.pid })
+    }
+}
+
+impl<'bm, P: Zeroable + 'bm> BufferManageGuardUpgrade<'bm, &'bm LocalBm<P>, LocalGuardX<'bm, P>>
+    for LocalGuardO<'bm, P>
+{
+    fn try_upgrade(self) -> Result<LocalGuardX<'bm, P>, OptimisticError> {
+        self.try_check()?;
+        self.bm.lock_exclusive(self.pid);
+        Ok(LocalGuardX { bm: self.bm, pid: self.pid })
+    }
+}
+
+impl<'bm, P: Zeroable + 'bm> BufferManageGuardUpgrade<'bm, &'bm LocalBm<P>, LocalGuardX<'bm, P>>
+    for LocalGuardS<'bm, P>
+{
+    /// Only succeeds if this is the only shared guard of the page, the shared lock is released on failure.
+    fn try_upgrade(self) -> Result<LocalGuardX<'bm, P>, OptimisticError> {
+        let lock = self.bm.lock(self.pid);
+        if lock.shared.get() != 1 {
+            return Err(OptimisticError::new());
+        }
+        lock.shared.set(0);
+        lock.exclusive.set(true);
+        let guard = LocalGuardX { bm: self.bm, pid: self.pid };
+        forget(self);
+        Ok(guard)
+    }
+}
+
+pub struct LocalGuardS<'bm, P> {
+    bm: &'bm LocalBm<P>,
+    pid: usize,
+}
+
+impl<'bm, P: Zeroable + 'bm> BufferManagerGuard<'bm, &'bm LocalBm<P>> for LocalGuardS<'bm, P> {
+    type Mode = Locked;
+
+    fn acquire_wait(bm: &'bm LocalBm<P>, page_id: PageId) -> Self {
+        let pid = page_id.x as usize;
+        bm.lock_shared(pid);
+        LocalGuardS { bm, pid }
+    }
+
+    fn acquire_wait_version(bm: &'bm LocalBm<P>, page_id: PageId, v: OlcVersion) -> Option<Self> {
+        let pid = page_id.x as usize;
+        if bm.lock(pid).version.get() != v.x {
+            return None;
+        }
+        bm.lock_shared(pid);
+        Some(LocalGuardS { bm, pid })
+    }
+
+    fn try_acquire(bm: &'bm LocalBm<P>, page_id: PageId) -> Result<Self, WouldBlock> {
+        bm.can_lock(page_id.x as usize, Some(false))?;
+        Ok(Self::acquire_wait(bm, page_id))
+    }
+
+    fn try_acquire_version(bm: &'bm LocalBm<P>, page_id: PageId, v: OlcVersion) -> Result<Option<Self>, WouldBlock> {
+        bm.can_lock(page_id.x as usize, Some(false))?;
+        Ok(Self::acquire_wait_version(bm, page_id, v))
+    }
+
+    fn acquire_with_deadline(bm: &'bm LocalBm<P>, page_id: PageId, _deadline: Instant) -> Result<Self, WouldBlock> {
+        Self::try_acquire(bm, page_id)
+    }
+
+    fn release(self) -> OlcVersion {
+        let version = self.bm.unlock_shared(self.pid);
+        forget(self);
+        version
+    }
+
+    fn page_id(&self) -> PageId {
+        PageId { x: self.pid as u64 }
+    }
+
+    fn o_ptr(&mut self) -> OPtr<'_, P, InfallibleOlcEh, Locked> {
+        OPtr::locked(&**self)
+    }
+}
+
+impl<P> Deref for LocalGuardS<'_, P> {
+    type Target = P;
+
+    fn deref(&self) -> &P {
+        unsafe { &*self.bm.page(self.pid) }
+    }
+}
+
+impl<P> Drop for LocalGuardS<'_, P> {
+    fn drop(&mut self) {
+        self.bm.unlock_shared(self.pid);
+    }
+}
+
+pub struct LocalGuardX<'bm, P> {
+    bm: &'bm LocalBm<P>,
+    pid: usize,
+}
+
+impl<'bm, P: Zeroable + 'bm> BufferManagerGuard<'bm, &'bm LocalBm<P>> for LocalGuardX<'bm, P> {
+    type Mode = Locked;
+
+    fn acquire_wait(bm: &'bm LocalBm<P>, page_id: PageId) -> Self {
+        let pid = page_id.x as usize;
+        bm.lock_exclusive(pid);
+        LocalGuardX { bm, pid }
+    }
+
+    fn acquire_wait_version(bm: &'bm LocalBm<P>, page_id: PageId, v: OlcVersion) -> Option<Self> {
+        let pid = page_id.x as usize;
+        if bm.lock(pid).version.get() != v.x {
+            return None;
+        }
+        bm.lock_exclusive(pid);
+        Some(LocalGuardX { bm, pid })
+    }
+
+    fn try_acquire(bm: &'bm LocalBm<P>, page_id: PageId) -> Result<Self, WouldBlock> {
+        bm.can_lock(page_id.x as usize, Some(true))?;
+        Ok(Self::acquire_wait(bm, page_id))
+    }
+
+    fn try_acquire_version(bm: &'bm LocalBm<P>, page_id: PageId, v: OlcVersion) -> Result<Option<Self>, WouldBlock> {
+        bm.can_lock(page_id.x as usize, Some(true))?;
+        Ok(Self::acquire_wait_version(bm, page_id, v))
+    }
+
+    fn acquire_with_deadline(bm: &'bm LocalBm<P>, page_id: PageId, _deadline: Instant) -> Result<Self, WouldBlock> {
+        Self::try_acquire(bm, page_id)
+    }
+
+    fn release(self) -> OlcVersion {
+        let version = self.bm.unlock_exclusive(self.pid);
+        forget(self);
+        version
+    }
+
+    fn page_id(&self) -> PageId {
+        PageId { x: self.pid as u64 }
+    }
+
+    fn o_ptr(&mut self) -> OPtr<'_, P, InfallibleOlcEh, Locked> {
+        OPtr::locked(&**self)
+    }
+}
+
+impl<'bm, P: Zeroable + 'bm> ExclusiveGuard<'bm, &'bm LocalBm<P>> for LocalGuardX<'bm, P> {
+    fn reset_written(&mut self) {}
+
+    fn dealloc(self) {
+        self.bm.unlock_exclusive(self.pid);
+        self.bm.free_pages.borrow_mut().push(self.pid);
+        forget(self);
+    }
+}
+
+impl<'bm, P: Zeroable + 'bm> BufferManageGuardDowngrade<'bm, &'bm LocalBm<P>, LocalGuardS<'bm, P>>
+    for LocalGuardX<'bm, P>
+{
+    fn downgrade(self) -> LocalGuardS<'bm, P> {
+        // no other thread can lock the page in between
+        let (bm, pid) = (self.bm, self.pid);
+        self.release();
+        bm.lock_shared(pid);
+        LocalGuardS { bm, pid }
+    }
+}
+
+impl<'bm, P: Zeroable + 'bm> BufferManageGuardDowngrade<'bm, &'bm LocalBm<P>, LocalGuardO<'bm, P>>
+    for LocalGuardX<'bm, P>
+{
+    fn downgrade(self) -> LocalGuardO<'bm, P> {
+        let (bm, pid) = (self.bm, self.pid);
+        let version = self.release();
+        LocalGuardO { bm, pid, version: version.x }
+    }
+}
+
+impl<P> Deref for LocalGuardX<'_, P> {
+    type Target = P;
+
+    fn deref(&self) -> &P {
+        unsafe { &*self.bm.page(self.pid) }
+    }
+}
+
+impl<P> DerefMut for LocalGuardX<'_, P> {
+    fn deref_mut(&mut self) -> &mut P {
+        unsafe { &mut *self.bm.page(self.pid) }
+    }
+}
+
+impl<P> Drop for LocalGuardX<'_, P> {
+    fn drop(&mut self) {
+        self.bm.unlock_exclusive(self.pid);
+    }
+}
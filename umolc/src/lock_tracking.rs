@@ -0,0 +1,213 @@
+//! Lock usage checks enabled by the `track-thread-locks` feature.
+//!
+//! Every thread records the locks it holds in the order it acquired them.
+//! Whenever a thread may wait for a lock while holding others, an edge from each held lock to the awaited lock is
+//! added to a global lock-order graph.
+//! A cycle of edges in which every awaited lock conflicts with the mode it is held in elsewhere in the cycle means
+//! threads can deadlock, even if they never actually did, and is reported by panicking.
+//! Locks still held when a thread exits belong to leaked or forgotten guards, they are collected in
+//! [take_leaked_locks].
+
+use crate::seqlock::SeqLock;
+use crate::PageId;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::{LazyLock, Mutex, MutexGuard, PoisonError};
+
+/// `None` is optimistic, `Some(false)` shared and `Some(true)` exclusive.
+type Mode = Option<bool>;
+
+#[derive(Clone, Copy)]
+struct Label {
+    pid: PageId,
+    tag: Option<u8>,
+}
+
+#[derive(Default)]
+struct LockGraph {
+    /// held lock -> awaited lock -> (held exclusively, awaited mode)
+    edges: HashMap<usize, HashMap<usize, HashSet<(bool, Mode)>>>,
+    labels: HashMap<usize, Label>,
+    leaked: Vec<String>,
+}
+
+static GRAPH: LazyLock<Mutex<LockGraph>> = LazyLock::new(Default::default);
+
+fn graph() -> MutexGuard<'static, LockGraph> {
+    // a panicking report must not disable the checks of other threads
+    GRAPH.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Locks held by a thread in acquisition order, along with whether they are held exclusively.
+#[derive(Default)]
+struct HeldLocks(Vec<(usize, bool)>);
+
+impl Drop for HeldLocks {
+    fn drop(&mut self) {
+        if self.0.is_empty() {
+            return;
+        }
+        let mut graph = graph();
+        // panicking in a thread-local destructor aborts, so the report is only returned by `take_leaked_locks`
+        let report = format!("thread exited while holding {}", graph.describe_held(&self.0));
+        graph.leaked.push(report);
+    }
+}
+
+std::thread_local! {
+    static THREAD_LOCKS: RefCell<HeldLocks> = Default::default();
+}
+
+fn conflicts(held_exclusive: bool, awaited: Mode) -> bool {
+    held_exclusive || awaited == Some(true)
+}
+
+fn lock_name(mode: Mode) -> &'static str {
+    match mode {
+        None => "optimistic",
+        Some(false) => "shared",
+        Some(true) => "exclusive",
+    }
+}
+
+impl LockGraph {
+    /// Returns true if the edge is new.
+    fn add_edge(&mut self, held: usize, held_exclusive: bool, awaited: usize, mode: Mode) -> bool {
+        self.edges.entry(held).or_default().entry(awaited).or_default().insert((held_exclusive, mode))
+    }
+
+    /// Searches a conflicting cycle through the edge from `held` to `awaited`, returns the locks along it.
+    fn find_cycle(&self, held: usize, held_exclusive: bool, awaited: usize, mode: Mode) -> Option<Vec<usize>> {
+        let mut predecessors: HashMap<(usize, Mode), (usize, Mode)> = HashMap::new();
+        let mut stack = vec![(awaited, mode)];
+        let mut visited: HashSet<(usize, Mode)> = stack.iter().copied().collect();
+        while let Some((lock, awaited_mode)) = stack.pop() {
+            for (&next, modes) in self.edges.get(&lock).into_iter().flatten() {
+                for &(exclusive, next_mode) in modes {
+                    if !conflicts(exclusive, awaited_mode) {
+                        continue;
+                    }
+                    if next == held && conflicts(held_exclusive, next_mode) {
+                        let mut cycle = vec![lock];
+                        let mut state = (lock, awaited_mode);
+                        while let Some(&p) = predecessors.get(&state) {
+                            cycle.push(p.0);
+                            state = p;
+                        }
+                        cycle.push(held);
+                        cycle.reverse();
+                        return Some(cycle);
+                    }
+                    if visited.insert((next, next_mode)) {
+                        predecessors.insert((next, next_mode), (lock, awaited_mode));
+                        stack.push((next, next_mode));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn describe(&self, lock: usize) -> String {
+        match self.labels.get(&lock) {
+            Some(Label { pid, tag: Some(tag) }) => format!("page {} (tag {tag})", pid.x),
+            Some(Label { pid, tag: None }) => format!("page {}", pid.x),
+            None => format!("lock {lock:#x}"),
+        }
+    }
+
+    fn describe_held(&self, held: &[(usize, bool)]) -> String {
+        let locks: Vec<String> = held
+            .iter()
+            .map(|&(lock, exclusive)| format!("{} {}", lock_name(Some(exclusive)), self.describe(lock)))
+            .collect();
+        locks.join(", ")
+    }
+}
+
+fn address(lock: &SeqLock) -> usize {
+    (lock as *const SeqLock).addr()
+}
+
+pub fn lock_track_check(lock: &SeqLock, mode: Mode) {
+    let addr = address(lock);
+    let existing = THREAD_LOCKS.with_borrow(|h| h.0.iter().find(|l| l.0 == addr).map(|l| l.1));
+    if existing.is_some() {
+        panic!("cannot acquire {} lock because {} is held by same thread", lock_name(mode), lock_name(existing))
+    }
+}
+
+/// Records that the calling thread may wait for `lock` while holding its current locks.
+pub fn lock_track_order(lock: &SeqLock, mode: Mode) {
+    let awaited = address(lock);
+    let held = THREAD_LOCKS.with_borrow(|h| h.0.clone());
+    if held.is_empty() {
+        return;
+    }
+    let mut graph = graph();
+    for &(lock, exclusive) in &held {
+        if !graph.add_edge(lock, exclusive, awaited, mode) {
+            continue;
+        }
+        if let Some(cycle) = graph.find_cycle(lock, exclusive, awaited, mode) {
+            let path: Vec<String> = cycle.iter().chain(&cycle[..1]).map(|&l| graph.describe(l)).collect();
+            let cycle = path.join(" -> ");
+            let current = format!("{} {}", lock_name(mode), graph.describe(awaited));
+            let held = graph.describe_held(&held);
+            drop(graph);
+            panic!(
+                "possible deadlock, locks are acquired in conflicting orders: {cycle}\n\
+                acquiring {current} while holding {held}"
+            );
+        }
+    }
+}
+
+pub fn lock_track_set(lock: &SeqLock, mode: Mode) {
+    let addr = address(lock);
+    THREAD_LOCKS.with_borrow_mut(|h| {
+        let position = h.0.iter().position(|l| l.0 == addr);
+        match (mode, position) {
+            // upgrades and downgrades keep the position of the lock
+            (Some(exclusive), Some(i)) => h.0[i].1 = exclusive,
+            (Some(exclusive), None) => h.0.push((addr, exclusive)),
+            (None, Some(i)) => {
+                h.0.remove(i);
+            }
+            (None, None) => (),
+        }
+    });
+}
+
+/// Names `lock` in reports, `tag` is kept from earlier calls if it is `None`.
+pub fn label_lock(lock: &SeqLock, pid: PageId, tag: Option<u8>) {
+    let mut graph = graph();
+    let label = graph.labels.entry(address(lock)).or_insert(Label { pid, tag });
+    if label.pid != pid || tag.is_some() {
+        *label = Label { pid, tag };
+    }
+}
+
+/// Removes the locks from the lock-order graph, as their memory may be reused by unrelated locks.
+pub fn forget_locks(first: *const SeqLock, count: usize) {
+    let range = first.addr()..first.wrapping_add(count).addr();
+    let mut graph = graph();
+    graph.edges.retain(|lock, _| !range.contains(lock));
+    for awaited in graph.edges.values_mut() {
+        awaited.retain(|lock, _| !range.contains(lock));
+    }
+    graph.labels.retain(|lock, _| !range.contains(lock));
+}
+
+/// Panics if the calling thread holds any shared or exclusive lock, e.g. because a guard was forgotten.
+pub fn assert_no_locks_held() {
+    let held = THREAD_LOCKS.with_borrow(|h| h.0.clone());
+    if !held.is_empty() {
+        panic!("thread is still holding {}", graph().describe_held(&held));
+    }
+}
+
+/// Returns and clears the locks threads held when they exited, each entry describes one thread.
+pub fn take_leaked_locks() -> Vec<String> {
+    std::mem::take(&mut graph().leaked)
+}
@@ -0,0 +1,274 @@
+use crate::seqlock::{forget_locks, SeqLock};
+use crate::{CommonSeqLockBM, OutOfPages, PageId, PageStats, UnwindOlcEh};
+use bytemuck::Zeroable;
+use memmap2::MmapRaw;
+use std::cell::UnsafeCell;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::path::Path;
+use std::sync::Mutex;
+
+const MAGIC: u64 = u64::from_le_bytes(*b"umolcbm1");
+const FILE_PAGE_ALIGN: usize = 4096;
+
+#[repr(C)]
+struct MmapHeader {
+    magic: u64,
+    page_size: u64,
+    capacity: u64,
+    free_count: u64,
+}
+
+/// A buffer manager that keeps its pages in a memory mapped file.
+///
+/// The file starts with a header holding page size, capacity and the free list, followed by the page array.
+/// Allocation and deallocation update the free list in place, so after reopening the file with [MmapBm::open],
+/// every page that was allocated before is still allocated and holds its last flushed contents.
+/// After a crash, pages may hold any contents written since the last flush, and the free list may not match them,
+/// so the file is opened with [MmapBm::recover] instead.
+/// Locks are not persisted, they are all released when the file is opened.
+pub struct MmapBm<P> {
+    map: MmapRaw,
+    capacity: usize,
+    pages_offset: usize,
+    locks: Box<[SeqLock]>,
+    /// protects the free list stored in the header
+    free_list: Mutex<()>,
+    page_stats: Option<PageStats>,
+    _p: PhantomData<P>,
+}
+
+unsafe impl<P> Sync for MmapBm<P> {}
+unsafe impl<P> Send for MmapBm<P> {}
+
+impl<P: Zeroable> MmapBm<P> {
+    /// Creates a new file with room for `capacity` pages, truncating any existing file at `path`.
+    pub fn create(path: impl AsRef<Path>, capacity: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        let file_len = Self::file_len(capacity).expect("capacity too large");
+        file.set_len(file_len as u64)?;
+        let bm = Self::map(&file, capacity)?;
+        unsafe {
+            for (i, x) in bm.free_slots().iter_mut().enumerate() {
+                x.write(i as u64);
+            }
+            bm.header().write(MmapHeader {
+                magic: MAGIC,
+                page_size: size_of::<P>() as u64,
+                capacity: capacity as u64,
+                free_count: capacity as u64,
+            });
+        }
+        bm.flush()?;
+        Ok(bm)
+    }
+
+    /// Opens a file previously created by [MmapBm::create].
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let (file, header) = Self::open_file(path)?;
+        if header.free_count > header.capacity {
+            return Err(invalid("bad free list"));
+        }
+        let bm = Self::map(&file, header.capacity as usize)?;
+        // every free page must be handed out at most once and lie within the page array
+        let mut free = vec![false; bm.capacity];
+        let free_slots = unsafe { &bm.free_slots()[..header.free_count as usize] };
+        for slot in free_slots {
+            let pid = unsafe { slot.assume_init() };
+            match free.get_mut(pid as usize) {
+                Some(seen @ false) => *seen = true,
+                _ => return Err(invalid("bad free list entry")),
+            }
+        }
+        Ok(bm)
+    }
+
+    /// Opens a file after a crash, when its free list may not match the pages in use.
+    /// All pages are allocated until [MmapBm::rebuild_free_list] is called.
+    pub fn recover(path: impl AsRef<Path>) -> io::Result<Self> {
+        let (file, header) = Self::open_file(path)?;
+        let bm = Self::map(&file, header.capacity as usize)?;
+        unsafe { (*bm.header()).free_count = 0 };
+        Ok(bm)
+    }
+
+    fn open_file(path: impl AsRef<Path>) -> io::Result<(File, MmapHeader)> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        if file.metadata()?.len() < size_of::<MmapHeader>() as u64 {
+            return Err(invalid("file too short for header"));
+        }
+        let header = {
+            let map = MmapRaw::map_raw(&file)?;
+            unsafe { (map.as_ptr() as *const MmapHeader).read() }
+        };
+        if header.magic != MAGIC {
+            return Err(invalid("bad magic"));
+        }
+        if header.page_size != size_of::<P>() as u64 {
+            return Err(invalid("page size mismatch"));
+        }
+        let file_len = usize::try_from(header.capacity).ok().and_then(Self::file_len);
+        let Some(file_len) = file_len else {
+            return Err(invalid("bad capacity"));
+        };
+        if file.metadata()?.len() < file_len as u64 {
+            return Err(invalid("file too short for pages"));
+        }
+        Ok((file, header))
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl<P> MmapBm<P> {
+    fn pages_offset(capacity: usize) -> usize {
+        Self::checked_pages_offset(capacity).unwrap()
+    }
+
+    fn checked_pages_offset(capacity: usize) -> Option<usize> {
+        let free_list_len = capacity.checked_mul(size_of::<u64>())?;
+        free_list_len
+            .checked_add(size_of::<MmapHeader>())?
+            .checked_next_multiple_of(FILE_PAGE_ALIGN.max(align_of::<P>()))
+    }
+
+    /// The size of a file holding `capacity` pages, `None` if it does not fit into `usize`.
+    fn file_len(capacity: usize) -> Option<usize> {
+        Self::checked_pages_offset(capacity)?.checked_add(capacity.checked_mul(size_of::<P>())?)
+    }
+
+    fn map(file: &File, capacity: usize) -> io::Result<Self> {
+        let map = MmapRaw::map_raw(file)?;
+        assert_eq!(map.as_ptr().addr() % align_of::<P>(), 0);
+        Ok(MmapBm {
+            map,
+            capacity,
+            pages_offset: Self::pages_offset(capacity),
+            locks: unsafe { Box::<[MaybeUninit<_>]>::assume_init(Box::new_zeroed_slice(capacity)) },
+            free_list: Mutex::new(()),
+            page_stats: None,
+            _p: PhantomData,
+        })
+    }
+
+    fn header(&self) -> *mut MmapHeader {
+        self.map.as_mut_ptr() as *mut MmapHeader
+    }
+
+    /// must only be accessed while holding the free list lock
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn free_slots(&self) -> &mut [MaybeUninit<u64>] {
+        std::slice::from_raw_parts_mut(
+            self.map.as_mut_ptr().add(size_of::<MmapHeader>()) as *mut MaybeUninit<u64>,
+            self.capacity,
+        )
+    }
+
+    fn pages(&self) -> &[UnsafeCell<P>] {
+        unsafe {
+            std::slice::from_raw_parts(self.map.as_ptr().add(self.pages_offset) as *const UnsafeCell<P>, self.capacity)
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn free_page_count(&self) -> usize {
+        let _guard = self.free_list.lock().unwrap();
+        unsafe { (*self.header()).free_count as usize }
+    }
+
+    /// Starts counting lock acquisitions, optimistic failures and exclusive wait time for every page,
+    /// see [CommonSeqLockBM::page_stats].
+    pub fn enable_page_stats(&mut self) {
+        self.page_stats = Some(PageStats::new(self.capacity));
+    }
+
+    /// Frees every page that is not in `in_use`, e.g. after [MmapBm::recover].
+    /// No pages may be allocated or freed concurrently.
+    pub fn rebuild_free_list(&self, in_use: impl IntoIterator<Item = PageId>) {
+        let mut used = vec![false; self.capacity];
+        for pid in in_use {
+            used[pid.x as usize] = true;
+        }
+        let _guard = self.free_list.lock().unwrap();
+        unsafe {
+            let free_slots = self.free_slots();
+            let mut free_count = 0;
+            for pid in (0..self.capacity).filter(|&pid| !used[pid]) {
+                free_slots[free_count].write(pid as u64);
+                free_count += 1;
+            }
+            (*self.header()).free_count = free_count as u64;
+        }
+    }
+
+    /// Writes all modified pages and the free list back to the file.
+    pub fn flush(&self) -> io::Result<()> {
+        self.map.flush()
+    }
+}
+
+impl<P> Drop for MmapBm<P> {
+    fn drop(&mut self) {
+        forget_locks(self.locks.as_ptr(), self.locks.len());
+        let _ = self.flush();
+    }
+}
+
+impl<'bm, P> CommonSeqLockBM<'bm> for &'bm MmapBm<P> {
+    type Page = P;
+    type OlcEH = UnwindOlcEh;
+
+    fn pid_from_address(self, address: usize) -> PageId {
+        let start = self.pages().as_ptr().addr();
+        debug_assert!(address >= start);
+        debug_assert!(address < start + size_of::<P>() * self.capacity);
+        let offset = address - start;
+        assert_eq!(offset % size_of::<P>(), 0);
+        PageId { x: (offset / size_of::<P>()) as u64 }
+    }
+
+    fn try_alloc(self) -> Result<PageId, OutOfPages> {
+        let pid = {
+            let _guard = self.free_list.lock().unwrap();
+            unsafe {
+                let header = &mut *self.header();
+                if header.free_count == 0 {
+                    return Err(OutOfPages);
+                }
+                header.free_count -= 1;
+                self.free_slots()[header.free_count as usize].assume_init()
+            }
+        } as usize;
+        self.locks[pid].force_lock_exclusive();
+        Ok(PageId { x: pid as u64 })
+    }
+
+    fn dealloc(self, pid: PageId) {
+        self.locks[pid.x as usize].unlock_exclusive();
+        let _guard = self.free_list.lock().unwrap();
+        unsafe {
+            let header = &mut *self.header();
+            self.free_slots()[header.free_count as usize].write(pid.x);
+            header.free_count += 1;
+        }
+    }
+
+    fn page(self, pid: PageId) -> &'bm UnsafeCell<Self::Page> {
+        &self.pages()[pid.x as usize]
+    }
+
+    fn lock(self, pid: PageId) -> &'bm SeqLock {
+        &self.locks[pid.x as usize]
+    }
+
+    fn page_stats(self) -> Option<&'bm PageStats> {
+        self.page_stats.as_ref()
+    }
+}
@@ -1,4 +1,4 @@
-use crate::optimistic_error::OlcErrorHandler;
+use crate::optimistic_error::{OlcErrorHandler, OptimisticError};
 use bytemuck::Pod;
 use radium::marker::Atomic;
 use radium::Radium;
@@ -11,26 +11,59 @@ use std::ptr::slice_from_raw_parts;
 use std::slice::SliceIndex;
 use std::sync::atomic::Ordering::Relaxed;
 
-impl<T: ?Sized, O: OlcErrorHandler> Copy for OPtr<'_, T, O> {}
-impl<T: ?Sized, O: OlcErrorHandler> Clone for OPtr<'_, T, O> {
+impl<T: ?Sized, O: OlcErrorHandler, M: OPtrMode> Copy for OPtr<'_, T, O, M> {}
+impl<T: ?Sized, O: OlcErrorHandler, M: OPtrMode> Clone for OPtr<'_, T, O, M> {
     fn clone(&self) -> Self {
         *self
     }
 }
 
-// TODO add type parameter that makes OPtr not optimistic
+/// Whether the data behind an [OPtr] may be modified concurrently.
+pub trait OPtrMode: Copy + 'static {
+    const OPTIMISTIC: bool;
+}
+
+/// The data may be modified concurrently, it is read atomically and out of bounds accesses are optimistic errors.
+#[derive(Clone, Copy)]
+pub struct Optimistic;
+
+/// The data is protected by a shared or exclusive lock, it is read with plain loads.
+/// Out of bounds accesses can only be caused by bugs and panic.
+#[derive(Clone, Copy)]
+pub struct Locked;
 
-pub struct OPtr<'a, T: ?Sized, O: OlcErrorHandler> {
+impl OPtrMode for Optimistic {
+    const OPTIMISTIC: bool = true;
+}
+
+impl OPtrMode for Locked {
+    const OPTIMISTIC: bool = false;
+}
+
+fn check_bounds<M: OPtrMode>(in_bounds: bool) -> Result<(), OptimisticError> {
+    if in_bounds {
+        Ok(())
+    } else if M::OPTIMISTIC {
+        Err(OptimisticError::new())
+    } else {
+        panic!("out of bounds access to locked page")
+    }
+}
+
+pub struct OPtr<'a, T: ?Sized, O: OlcErrorHandler, M: OPtrMode = Optimistic> {
     p: *const T,
     _p: PhantomData<&'a T>,
-    _bm: PhantomData<O>,
+    _bm: PhantomData<(O, M)>,
 }
 
-impl<'a, T, O: OlcErrorHandler> OPtr<'a, T, O> {
-    pub fn to_raw(self) -> *const T {
-        self.p
+impl<'a, T, O: OlcErrorHandler> OPtr<'a, T, O, Locked> {
+    /// The caller must hold a shared or exclusive lock on `x` for `'a`, which borrowing it guarantees.
+    pub fn locked(x: &'a T) -> Self {
+        OPtr { p: x as *const T, _p: PhantomData, _bm: PhantomData }
     }
+}
 
+impl<'a, T, O: OlcErrorHandler> OPtr<'a, T, O> {
     pub fn from_mut(x: &'a mut T) -> Self {
         OPtr { p: x as *const T, _p: PhantomData, _bm: PhantomData }
     }
@@ -44,27 +77,43 @@ impl<'a, T, O: OlcErrorHandler> OPtr<'a, T, O> {
     pub unsafe fn from_raw(p: *const T) -> Self {
         OPtr { p, _p: PhantomData, _bm: PhantomData }
     }
+}
 
+impl<'a, T: ?Sized, O: OlcErrorHandler, M: OPtrMode> OPtr<'a, T, O, M> {
+    /// Forgets that the data is locked, e.g. to pass it to code that only accepts optimistic pointers.
+    pub fn optimistic(self) -> OPtr<'a, T, O> {
+        OPtr { p: self.p, _p: PhantomData, _bm: PhantomData }
+    }
+
+    pub fn to_raw(self) -> *const T {
+        self.p
+    }
+}
+
+impl<'a, T, O: OlcErrorHandler, M: OPtrMode> OPtr<'a, T, O, M> {
     #[allow(clippy::missing_safety_doc)]
-    pub unsafe fn project<R>(self, f: impl FnOnce(*const T) -> *const R) -> OPtr<'a, R, O> {
+    pub unsafe fn project<R>(self, f: impl FnOnce(*const T) -> *const R) -> OPtr<'a, R, O, M> {
         OPtr { p: f(self.p), _p: PhantomData, _bm: PhantomData }
     }
 
-    pub fn cast<U>(self) -> OPtr<'a, U, O> {
+    pub fn cast<U>(self) -> OPtr<'a, U, O, M> {
         assert_eq!(size_of::<T>(), size_of::<U>());
         assert!(align_of::<T>() >= align_of::<U>());
         OPtr { p: self.p as *const U, _p: PhantomData, _bm: PhantomData }
     }
 
-    pub fn array_slice<const L: usize>(self, offset: usize) -> OPtr<'a, [u8; L], O> {
+    pub fn array_slice<const L: usize>(self, offset: usize) -> OPtr<'a, [u8; L], O, M> {
+        O::optmistic_fail_check(self.try_array_slice(offset))
+    }
+
+    pub fn try_array_slice<const L: usize>(self, offset: usize) -> Result<OPtr<'a, [u8; L], O, M>, OptimisticError> {
         assert!(L <= size_of::<T>());
-        if offset > size_of::<T>() - L {
-            O::optimistic_fail()
-        }
-        unsafe { OPtr { p: (self.p as *const u8).add(offset) as *const [u8; L], _bm: PhantomData, _p: PhantomData } }
+        check_bounds::<M>(offset <= size_of::<T>() - L)?;
+        let p = unsafe { (self.p as *const u8).add(offset) as *const [u8; L] };
+        Ok(OPtr { p, _bm: PhantomData, _p: PhantomData })
     }
 
-    pub fn as_slice<U: Pod>(self) -> OPtr<'a, [U], O> {
+    pub fn as_slice<U: Pod>(self) -> OPtr<'a, [U], O, M> {
         assert_eq!(size_of::<T>() % size_of::<U>(), 0);
         assert!(align_of::<T>() >= align_of::<U>());
         OPtr {
@@ -75,61 +124,77 @@ impl<'a, T, O: OlcErrorHandler> OPtr<'a, T, O> {
     }
 
     pub fn read_unaligned_nonatomic_u16(self, offset: usize) -> usize {
-        if offset + 2 <= size_of::<T>() {
-            unsafe { ((self.p as *const u8).add(offset) as *const u16).read_unaligned() as usize }
-        } else {
-            O::optimistic_fail()
-        }
+        O::optmistic_fail_check(self.try_read_unaligned_nonatomic_u16(offset))
+    }
+
+    pub fn try_read_unaligned_nonatomic_u16(self, offset: usize) -> Result<usize, OptimisticError> {
+        check_bounds::<M>(offset + 2 <= size_of::<T>())?;
+        unsafe { Ok(((self.p as *const u8).add(offset) as *const u16).read_unaligned() as usize) }
     }
 
     pub fn read_unaligned_nonatomic_u64(self, offset: usize) -> u64 {
-        if offset + 8 <= size_of::<T>() {
-            unsafe { ((self.p as *const u8).add(offset) as *const u64).read_unaligned() }
-        } else {
-            O::optimistic_fail()
-        }
+        O::optmistic_fail_check(self.try_read_unaligned_nonatomic_u64(offset))
+    }
+
+    pub fn try_read_unaligned_nonatomic_u64(self, offset: usize) -> Result<u64, OptimisticError> {
+        check_bounds::<M>(offset + 8 <= size_of::<T>())?;
+        unsafe { Ok(((self.p as *const u8).add(offset) as *const u64).read_unaligned()) }
     }
 
     pub fn r(self) -> T
     where
         T: Atomic + Pod,
     {
-        unsafe { (*(self.p as *const T::Atom)).load(Relaxed) }
+        if M::OPTIMISTIC {
+            unsafe { (*(self.p as *const T::Atom)).load(Relaxed) }
+        } else {
+            unsafe { self.p.read() }
+        }
     }
 }
 
-impl<'a, T: Pod, O: OlcErrorHandler> OPtr<'a, [T], O> {
+impl<'a, T: Pod, O: OlcErrorHandler, M: OPtrMode> OPtr<'a, [T], O, M> {
     pub fn i<I: Clone + SliceIndex<[T]> + SliceIndex<[UnsafeCell<T>]>>(
         self,
         i: I,
-    ) -> OPtr<'a, <I as SliceIndex<[T]>>::Output, O> {
+    ) -> OPtr<'a, <I as SliceIndex<[T]>>::Output, O, M> {
+        O::optmistic_fail_check(self.try_i(i))
+    }
+
+    /// Like [Self::i], but returns an out of bounds index as `Err` instead of failing through `O`.
+    #[allow(clippy::type_complexity)]
+    pub fn try_i<I: Clone + SliceIndex<[T]> + SliceIndex<[UnsafeCell<T>]>>(
+        self,
+        i: I,
+    ) -> Result<OPtr<'a, <I as SliceIndex<[T]>>::Output, O, M>, OptimisticError> {
         unsafe {
             let p = slice_from_raw_parts(self.p as *const UnsafeCell<T>, self.p.len());
-            if (&(*p)).get(i.clone()).is_none() {
-                // bounds check
-                O::optimistic_fail()
-            };
-            OPtr { p: i.get_unchecked(self.p), _p: PhantomData, _bm: PhantomData }
+            check_bounds::<M>((&(*p)).get(i.clone()).is_some())?;
+            Ok(OPtr { p: i.get_unchecked(self.p), _p: PhantomData, _bm: PhantomData })
         }
     }
 
-    pub fn sub(self, offset: usize, len: usize) -> OPtr<'a, [T], O> {
+    pub fn sub(self, offset: usize, len: usize) -> OPtr<'a, [T], O, M> {
         self.i(offset..offset + len)
     }
 
+    pub fn try_sub(self, offset: usize, len: usize) -> Result<OPtr<'a, [T], O, M>, OptimisticError> {
+        self.try_i(offset..offset + len)
+    }
+
     #[allow(clippy::len_without_is_empty)]
     pub fn len(self) -> usize {
         self.p.len()
     }
 }
 
-impl<'a, T: Pod, O: OlcErrorHandler, const N: usize> OPtr<'a, [T; N], O> {
-    pub fn unsize(self) -> OPtr<'a, [T], O> {
+impl<'a, T: Pod, O: OlcErrorHandler, M: OPtrMode, const N: usize> OPtr<'a, [T; N], O, M> {
+    pub fn unsize(self) -> OPtr<'a, [T], O, M> {
         OPtr { p: self.p.as_slice(), _p: PhantomData, _bm: PhantomData }
     }
 }
 
-impl<O: OlcErrorHandler> OPtr<'_, [u8], O> {
+impl<O: OlcErrorHandler, M: OPtrMode> OPtr<'_, [u8], O, M> {
     pub fn load_bytes(self, dst: &mut [u8]) {
         assert_eq!(self.p.len(), dst.len());
         unsafe { std::ptr::copy(self.p as *const u8, dst.as_mut_ptr(), self.p.len()) }
@@ -163,7 +228,7 @@ impl<O: OlcErrorHandler> OPtr<'_, [u8], O> {
 macro_rules! o_project {
     ($this:ident$(.$member:ident)+) => {
         {
-            let ptr:OPtr<_,_> = $this;
+            let ptr:OPtr<_,_,_> = $this;
             unsafe{ptr.project(|p|{
                 // TODO make sure you cannot sneak in a union field access here
                 // would be crazy if I actully ever added this.
@@ -7,20 +7,23 @@ impl Display for OptimisticError {
     }
 }
 
+#[derive(Debug, Default)]
 pub struct OptimisticError {
     _private: (),
 }
 
 impl OptimisticError {
-    pub(crate) fn new() -> Self {
+    /// For read paths that report inconsistent data as `Err` instead of through an [OlcErrorHandler].
+    pub fn new() -> Self {
         OptimisticError { _private: () }
     }
 }
 
 pub trait OlcErrorHandler {
-    fn optmistic_fail_check(r: Result<(), OptimisticError>) {
-        if let Err(e) = r {
-            Self::optimistic_fail_with(e)
+    fn optmistic_fail_check<T>(r: Result<T, OptimisticError>) -> T {
+        match r {
+            Ok(x) => x,
+            Err(e) => Self::optimistic_fail_with(e),
         }
     }
     fn optimistic_fail_with(e: OptimisticError) -> !;
@@ -31,6 +34,10 @@ pub trait OlcErrorHandler {
     // TODO consider adding a marker type that is returned by functions that may unwind and marked must_use
     fn catch<R>(f: impl FnOnce() -> R) -> Result<R, OptimisticError>;
 
+    /// Whether code run in [Self::catch] may fail optimistically.
+    /// If `false`, operations that restart through [Self::catch] must use pessimistic locking instead.
+    const CAN_CATCH: bool = true;
+
     /// Returns `true` if currently unwinding due to an optimistic error.
     /// Lock guards should use this for poisoning and to avoid calling one of the fail methods while already unwinding
     fn is_unwinding() -> bool;
@@ -38,8 +45,17 @@ pub trait OlcErrorHandler {
 
 pub struct UnwindOlcEh;
 
+/// For builds with `panic = "abort"`, where optimistic errors cannot unwind.
+///
+/// [OlcErrorHandler::catch] runs the closure directly and an optimistic error that reaches this handler panics.
+/// Only read paths that return [OptimisticError] as `Err`, like `o_ptr_lookup_leaf`, run optimistically.
+/// [crate::BufferManagerExt::repeat_limited] falls back to pessimistic locking right away.
 pub struct PanicOlcEh;
 
+/// For buffer managers whose optimistic guards cannot fail, like [crate::LocalBm].
+/// [OlcErrorHandler::catch] runs the closure directly, an optimistic error is a bug and panics.
+pub struct InfallibleOlcEh;
+
 impl OlcErrorHandler for UnwindOlcEh {
     fn optimistic_fail_with(error: OptimisticError) -> ! {
         resume_unwind(Box::new(error));
@@ -72,8 +88,24 @@ impl OlcErrorHandler for PanicOlcEh {
         panic!("{e}")
     }
 
-    fn catch<R>(_f: impl FnOnce() -> R) -> Result<R, OptimisticError> {
-        unimplemented!()
+    fn catch<R>(f: impl FnOnce() -> R) -> Result<R, OptimisticError> {
+        Ok(f())
+    }
+
+    const CAN_CATCH: bool = false;
+
+    fn is_unwinding() -> bool {
+        std::thread::panicking()
+    }
+}
+
+impl OlcErrorHandler for InfallibleOlcEh {
+    fn optimistic_fail_with(e: OptimisticError) -> ! {
+        panic!("{e} in a buffer manager that cannot fail optimistically")
+    }
+
+    fn catch<R>(f: impl FnOnce() -> R) -> Result<R, OptimisticError> {
+        Ok(f())
     }
 
     fn is_unwinding() -> bool {
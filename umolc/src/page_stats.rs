@@ -0,0 +1,124 @@
+use crate::PageId;
+use std::collections::HashMap;
+use std::mem::MaybeUninit;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+
+struct PageCounters {
+    optimistic_failures: AtomicU64,
+    exclusive_wait_nanos: AtomicU64,
+    shared_acquisitions: AtomicU64,
+    exclusive_acquisitions: AtomicU64,
+    last_access_epoch: AtomicU64,
+}
+
+/// Lock events of a page, recorded by the guards of [crate::CommonSeqLockBM] implementations.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum PageEvent {
+    Optimistic,
+    Shared,
+    /// carries the time spent waiting for the lock
+    Exclusive(Duration),
+    /// an optimistic guard failed validation or an acquisition expected an outdated version
+    OptimisticFailure,
+}
+
+/// Contention and access counters for every page of a buffer manager.
+///
+/// Buffer managers only keep these if enabled, e.g. with [crate::SimpleBm::enable_page_stats].
+/// Accesses are stamped with the current epoch, which starts at 1 and only moves on through [Self::advance_epoch].
+pub struct PageStats {
+    epoch: AtomicU64,
+    pages: Box<[PageCounters]>,
+}
+
+/// Counters of a single page, as returned by [PageStats::heat_map].
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct PageHeat {
+    /// Failed validations of optimistic guards, including acquisitions and upgrades at an outdated version.
+    pub optimistic_failures: u64,
+    /// Total time spent waiting for exclusive locks.
+    pub exclusive_wait: Duration,
+    pub shared_acquisitions: u64,
+    pub exclusive_acquisitions: u64,
+    /// Epoch of the most recent access in any mode, 0 if the page was not accessed since the last reset.
+    pub last_access_epoch: u64,
+}
+
+impl PageStats {
+    pub(crate) fn new(capacity: usize) -> Self {
+        PageStats {
+            epoch: AtomicU64::new(1),
+            pages: unsafe { Box::<[MaybeUninit<_>]>::assume_init(Box::new_zeroed_slice(capacity)) },
+        }
+    }
+
+    pub(crate) fn record(&self, pid: PageId, event: PageEvent) {
+        let Some(page) = self.pages.get(pid.x as usize) else {
+            return;
+        };
+        match event {
+            PageEvent::Optimistic => {}
+            PageEvent::Shared => {
+                page.shared_acquisitions.fetch_add(1, Relaxed);
+            }
+            PageEvent::Exclusive(wait) => {
+                page.exclusive_acquisitions.fetch_add(1, Relaxed);
+                page.exclusive_wait_nanos.fetch_add(wait.as_nanos() as u64, Relaxed);
+            }
+            PageEvent::OptimisticFailure => {
+                page.optimistic_failures.fetch_add(1, Relaxed);
+            }
+        }
+        let epoch = self.epoch.load(Relaxed);
+        // avoid writing a shared cache line on every optimistic read
+        if page.last_access_epoch.load(Relaxed) != epoch {
+            page.last_access_epoch.store(epoch, Relaxed);
+        }
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Relaxed)
+    }
+
+    /// Starts a new epoch and returns it, so later heat maps tell pages accessed since then apart.
+    pub fn advance_epoch(&self) -> u64 {
+        self.epoch.fetch_add(1, Relaxed) + 1
+    }
+
+    /// Counters of all pages accessed since the last reset.
+    /// Counters are read one by one, so concurrent accesses may be partially included.
+    pub fn heat_map(&self) -> HashMap<PageId, PageHeat> {
+        self.pages
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.last_access_epoch.load(Relaxed) != 0)
+            .map(|(pid, p)| {
+                let heat = PageHeat {
+                    optimistic_failures: p.optimistic_failures.load(Relaxed),
+                    exclusive_wait: Duration::from_nanos(p.exclusive_wait_nanos.load(Relaxed)),
+                    shared_acquisitions: p.shared_acquisitions.load(Relaxed),
+                    exclusive_acquisitions: p.exclusive_acquisitions.load(Relaxed),
+                    last_access_epoch: p.last_access_epoch.load(Relaxed),
+                };
+                (PageId { x: pid as u64 }, heat)
+            })
+            .collect()
+    }
+
+    /// Clears the counters of all pages, the epoch is kept.
+    pub fn reset(&self) {
+        for p in self.pages.iter() {
+            for c in [
+                &p.optimistic_failures,
+                &p.exclusive_wait_nanos,
+                &p.shared_acquisitions,
+                &p.exclusive_acquisitions,
+                &p.last_access_epoch,
+            ] {
+                c.store(0, Relaxed);
+            }
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+
+pub const RESTART_BUCKETS: usize = 32;
+
+/// Counts restarts of optimistic operations, see [crate::BufferManagerExt::repeat_limited].
+///
+/// Operations that succeed on the first attempt are not recorded, so threads only touch the shared counters when
+/// they are already contending.
+#[derive(Default)]
+pub struct RestartStats {
+    /// bucket `i` counts operations that failed between `2^i` and `2^(i+1)-1` times
+    histogram: [AtomicU64; RESTART_BUCKETS],
+    restarts: AtomicU64,
+    fallbacks: AtomicU64,
+}
+
+impl RestartStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records an operation that failed validation `failures` times, and whether it then gave up on optimistic
+    /// execution.
+    pub fn record(&self, failures: u32, fell_back: bool) {
+        if failures == 0 {
+            return;
+        }
+        self.histogram[failures.ilog2() as usize].fetch_add(1, Relaxed);
+        self.restarts.fetch_add(failures as u64, Relaxed);
+        if fell_back {
+            self.fallbacks.fetch_add(1, Relaxed);
+        }
+    }
+
+    /// Number of operations that failed validation at least once.
+    pub fn restarted_operations(&self) -> u64 {
+        self.histogram.iter().map(|b| b.load(Relaxed)).sum()
+    }
+
+    /// Total number of failed attempts.
+    pub fn restarts(&self) -> u64 {
+        self.restarts.load(Relaxed)
+    }
+
+    /// Number of operations that exceeded their restart limit.
+    pub fn fallbacks(&self) -> u64 {
+        self.fallbacks.load(Relaxed)
+    }
+
+    /// Entry `i` is the number of operations that failed between `2^i` and `2^(i+1)-1` times.
+    pub fn histogram(&self) -> [u64; RESTART_BUCKETS] {
+        std::array::from_fn(|i| self.histogram[i].load(Relaxed))
+    }
+
+    pub fn reset(&self) {
+        for b in &self.histogram {
+            b.store(0, Relaxed);
+        }
+        self.restarts.store(0, Relaxed);
+        self.fallbacks.store(0, Relaxed);
+    }
+}
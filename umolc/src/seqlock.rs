@@ -1,15 +1,136 @@
-use crate::{OlcVersion, OptimisticError};
+use crate::{OlcVersion, OptimisticError, WouldBlock};
 use bytemuck::Zeroable;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
-use std::sync::atomic::{fence, AtomicU64};
+use std::sync::atomic::{fence, AtomicU64, AtomicU8};
+use std::time::{Duration, Instant};
 
 #[derive(Zeroable)]
 pub struct SeqLock(AtomicU64);
 
 const COUNT_BITS: u32 = 10;
 const COUNT_MASK: u64 = (1 << COUNT_BITS) - 1;
-const EXCLUSIVE_MASK: u64 = 1 << COUNT_BITS;
-const VERSION_SHIFT: u32 = COUNT_BITS + 1;
+/// some thread is parked on this lock
+const WAITERS_MASK: u64 = 1 << COUNT_BITS;
+/// an exclusive locker is queued, new shared lockers must wait
+const INTENT_MASK: u64 = 1 << (COUNT_BITS + 1);
+/// must be directly below the version, so unlocking increments it
+const EXCLUSIVE_MASK: u64 = 1 << (COUNT_BITS + 2);
+const VERSION_SHIFT: u32 = COUNT_BITS + 3;
+
+/// rounds of exponential backoff spinning before yielding
+const SPIN_ROUNDS: u32 = 7;
+/// rounds of yielding before parking
+const YIELD_ROUNDS: u32 = 4;
+/// upper bound on a single park, guards against missed wakeups
+const PARK_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// How threads wait for a lock held by another thread.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum WaitStrategy {
+    /// Yield to the scheduler after every failed attempt.
+    Yield,
+    /// Spin with exponential backoff, never give up the CPU.
+    Spin,
+    /// Spin briefly, then yield, then park the thread until the lock is released.
+    /// Exclusive lockers that have to wait long block new shared lockers so they are not starved.
+    SpinThenPark,
+}
+
+static WAIT_STRATEGY: AtomicU8 = AtomicU8::new(WaitStrategy::SpinThenPark as u8);
+
+/// Selects how all threads wait for locks.
+/// Intended to be called once at startup, e.g. to compare strategies in benchmarks.
+pub fn set_wait_strategy(strategy: WaitStrategy) {
+    WAIT_STRATEGY.store(strategy as u8, Relaxed);
+}
+
+pub fn wait_strategy() -> WaitStrategy {
+    match WAIT_STRATEGY.load(Relaxed) {
+        0 => WaitStrategy::Yield,
+        1 => WaitStrategy::Spin,
+        _ => WaitStrategy::SpinThenPark,
+    }
+}
+
+impl Display for WaitStrategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            WaitStrategy::Yield => "yield",
+            WaitStrategy::Spin => "spin",
+            WaitStrategy::SpinThenPark => "park",
+        })
+    }
+}
+
+impl FromStr for WaitStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "yield" => Ok(WaitStrategy::Yield),
+            "spin" => Ok(WaitStrategy::Spin),
+            "park" => Ok(WaitStrategy::SpinThenPark),
+            _ => Err(format!("unknown wait strategy: {s:?}")),
+        }
+    }
+}
+
+/// Per acquisition state of a waiting thread.
+struct Backoff {
+    round: u32,
+    /// waiting fails once this has passed
+    deadline: Option<Instant>,
+}
+
+impl Backoff {
+    fn new(deadline: Option<Instant>) -> Self {
+        Backoff { round: 0, deadline }
+    }
+
+    /// Whether the thread has waited long enough to ask for priority.
+    fn is_patient(&self) -> bool {
+        self.round >= SPIN_ROUNDS
+    }
+
+    /// Waits for `lock` to change from `observed`.
+    /// May return early, fails without waiting if the deadline has passed.
+    fn wait(&mut self, lock: &SeqLock, observed: u64) -> Result<(), WouldBlock> {
+        let park_timeout = match self.deadline {
+            None => PARK_TIMEOUT,
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(WouldBlock);
+                }
+                PARK_TIMEOUT.min(deadline - now)
+            }
+        };
+        match wait_strategy() {
+            WaitStrategy::Yield => std::thread::yield_now(),
+            WaitStrategy::Spin => spin(self.round.min(SPIN_ROUNDS)),
+            WaitStrategy::SpinThenPark => {
+                if self.round < SPIN_ROUNDS {
+                    spin(self.round)
+                } else if self.round < SPIN_ROUNDS + YIELD_ROUNDS {
+                    std::thread::yield_now()
+                } else {
+                    lock.park(observed, park_timeout)
+                }
+            }
+        }
+        self.round = self.round.saturating_add(1);
+        Ok(())
+    }
+}
+
+fn spin(round: u32) {
+    for _ in 0..1 << round {
+        std::hint::spin_loop();
+    }
+}
 
 pub trait VersionFilter: Copy {
     type E;
@@ -58,20 +179,58 @@ impl SeqLock {
         SeqLock(AtomicU64::new(0))
     }
     pub fn lock_shared<F: VersionFilter>(&self, f: F) -> Result<F::R, F::E> {
+        self.lock_shared_inner(f, None).map(|r| r.unwrap_or_else(|WouldBlock| unreachable!()))
+    }
+
+    /// Like [Self::lock_shared], but gives up with `Ok(None)` once `deadline` has passed.
+    pub fn lock_shared_until<F: VersionFilter>(&self, f: F, deadline: Instant) -> Result<Option<F::R>, F::E> {
+        self.lock_shared_inner(f, Some(deadline)).map(Result::ok)
+    }
+
+    /// Like [Self::lock_shared], but returns `Ok(None)` instead of waiting.
+    /// Never waits, so it may be used while holding arbitrary other locks.
+    pub fn try_lock_shared<F: VersionFilter>(&self, f: F) -> Result<Option<F::R>, F::E> {
+        lock_track_check(self, Some(false));
+        let mut x = self.0.load(Relaxed);
+        loop {
+            f.check(x >> VERSION_SHIFT)?;
+            if x & (COUNT_MASK | INTENT_MASK | EXCLUSIVE_MASK) >= COUNT_MASK {
+                return Ok(None);
+            }
+            match self.0.compare_exchange_weak(x, x + 1, Acquire, Relaxed) {
+                Ok(_) => {
+                    lock_track_set(self, Some(false));
+                    return Ok(Some(f.map_r(x >> VERSION_SHIFT)));
+                }
+                Err(v) => x = v,
+            }
+        }
+    }
+
+    fn lock_shared_inner<F: VersionFilter>(
+        &self,
+        f: F,
+        deadline: Option<Instant>,
+    ) -> Result<Result<F::R, WouldBlock>, F::E> {
         lock_track_check(self, Some(false));
+        lock_track_order(self, Some(false));
+        let mut backoff = Backoff::new(deadline);
         let mut x = self.0.load(Relaxed);
         loop {
             f.check(x >> VERSION_SHIFT)?;
-            if x & (COUNT_MASK | EXCLUSIVE_MASK) < COUNT_MASK {
+            if x & (COUNT_MASK | INTENT_MASK | EXCLUSIVE_MASK) < COUNT_MASK {
                 match self.0.compare_exchange_weak(x, x + 1, Acquire, Relaxed) {
                     Ok(_) => {
                         lock_track_set(self, Some(false));
-                        return Ok(f.map_r(x >> VERSION_SHIFT));
+                        return Ok(Ok(f.map_r(x >> VERSION_SHIFT)));
                     }
                     Err(v) => x = v,
                 }
             } else {
-                self.wait();
+                if let Err(e) = backoff.wait(self, x) {
+                    return Ok(Err(e));
+                }
+                x = self.0.load(Relaxed);
             }
         }
     }
@@ -80,42 +239,105 @@ impl SeqLock {
         lock_track_set(self, None);
         let fetched = self.0.fetch_sub(1, Release);
         debug_assert!(fetched & COUNT_MASK != 0);
+        self.wake_waiters(fetched);
         OlcVersion { x: fetched >> VERSION_SHIFT }
     }
 
-    fn wait(&self) {
-        //TODO
-        std::thread::yield_now();
+    /// Parks the calling thread until the lock word changes from `observed`, a timeout elapses or a spurious wakeup.
+    fn park(&self, observed: u64, timeout: Duration) {
+        let expected = observed | WAITERS_MASK;
+        if observed != expected && self.0.compare_exchange(observed, expected, Relaxed, Relaxed).is_err() {
+            return;
+        }
+        futex::wait(self.futex_word(), expected as u32, timeout);
+    }
+
+    /// Must be called by every operation that may allow a waiting thread to make progress, with the prior lock word.
+    fn wake_waiters(&self, old: u64) {
+        if old & WAITERS_MASK != 0 {
+            self.0.fetch_and(!WAITERS_MASK, Relaxed);
+            futex::wake_all(self.futex_word());
+        }
+    }
+
+    /// The futex syscall operates on 32 bits, the lower half of the lock word contains all lock bits.
+    fn futex_word(&self) -> *const u32 {
+        let ptr = self.0.as_ptr() as *const u32;
+        if cfg!(target_endian = "big") {
+            ptr.wrapping_add(1)
+        } else {
+            ptr
+        }
+    }
+
+    /// Withdraws a queued exclusive locker's request for priority.
+    /// If several exclusive lockers are queued, the others set it again the next time they wait.
+    fn clear_intent(&self) {
+        let old = self.0.fetch_and(!INTENT_MASK, Relaxed);
+        if old & INTENT_MASK != 0 {
+            self.wake_waiters(old);
+        }
     }
 
     /// returns version before locking
     pub fn lock_exclusive<F: VersionFilter>(&self, f: F) -> Result<F::R, F::E> {
+        self.lock_exclusive_inner(f, None).map(|r| r.unwrap_or_else(|WouldBlock| unreachable!()))
+    }
+
+    /// Like [Self::lock_exclusive], but gives up with `Ok(None)` once `deadline` has passed.
+    pub fn lock_exclusive_until<F: VersionFilter>(&self, f: F, deadline: Instant) -> Result<Option<F::R>, F::E> {
+        self.lock_exclusive_inner(f, Some(deadline)).map(Result::ok)
+    }
+
+    fn lock_exclusive_inner<F: VersionFilter>(
+        &self,
+        f: F,
+        deadline: Option<Instant>,
+    ) -> Result<Result<F::R, WouldBlock>, F::E> {
         lock_track_check(self, Some(true));
+        lock_track_order(self, Some(true));
+        let mut backoff = Backoff::new(deadline);
+        let mut intent = false;
         loop {
             let mut x = self.0.load(Relaxed);
-            f.check(x >> VERSION_SHIFT)?;
-            if x & EXCLUSIVE_MASK == 0 {
-                x = self.0.fetch_or(EXCLUSIVE_MASK, Acquire);
-                if x & EXCLUSIVE_MASK != 0 {
-                    self.wait();
-                    continue;
+            if let Err(e) = f.check(x >> VERSION_SHIFT) {
+                if intent {
+                    self.clear_intent();
                 }
-                if f.check(x >> VERSION_SHIFT).is_err() {
-                    self.0.fetch_and(!EXCLUSIVE_MASK, Relaxed);
-                    self.wait();
+                return Err(e);
+            }
+            if x & EXCLUSIVE_MASK == 0 {
+                // the bit is only set at the checked version, so a page freed in the meantime, which may be
+                // allocated again by [Self::force_lock_exclusive], is never locked
+                if self.0.compare_exchange_weak(x, x | EXCLUSIVE_MASK, Acquire, Relaxed).is_err() {
                     continue;
                 }
-                if x & (EXCLUSIVE_MASK | COUNT_MASK) == 0 {
-                    lock_track_set(self, Some(true));
-                    return Ok(f.map_r(x >> VERSION_SHIFT));
+                if x & INTENT_MASK != 0 {
+                    // shared lockers are kept out by the exclusive bit from now on
+                    self.0.fetch_and(!INTENT_MASK, Relaxed);
                 }
-                loop {
-                    self.wait();
+                let mut drain_backoff = Backoff::new(deadline);
+                while x & COUNT_MASK != 0 {
+                    if let Err(e) = drain_backoff.wait(self, x) {
+                        // nothing was written, so the version is left unchanged
+                        let old = self.0.fetch_and(!EXCLUSIVE_MASK, Release);
+                        self.wake_waiters(old);
+                        return Ok(Err(e));
+                    }
                     x = self.0.load(Acquire);
-                    if x & COUNT_MASK == 0 {
-                        lock_track_set(self, Some(true));
-                        return Ok(f.map_r(x >> VERSION_SHIFT));
+                }
+                lock_track_set(self, Some(true));
+                return Ok(Ok(f.map_r(x >> VERSION_SHIFT)));
+            } else {
+                if x & INTENT_MASK == 0 && backoff.is_patient() && wait_strategy() == WaitStrategy::SpinThenPark {
+                    x = self.0.fetch_or(INTENT_MASK, Relaxed) | INTENT_MASK;
+                    intent = true;
+                }
+                if let Err(e) = backoff.wait(self, x) {
+                    if intent {
+                        self.clear_intent();
                     }
+                    return Ok(Err(e));
                 }
             }
         }
@@ -129,23 +351,114 @@ impl SeqLock {
         OlcVersion { x: x >> VERSION_SHIFT }
     }
 
+    /// Returns the raw lock word.
+    /// Comparing two snapshots detects exclusive locking in between, like an optimistic lock would.
+    pub(crate) fn snapshot(&self) -> u64 {
+        self.0.load(Acquire)
+    }
+
+    pub(crate) fn is_unlocked(word: u64) -> bool {
+        word & (EXCLUSIVE_MASK | COUNT_MASK) == 0
+    }
+
+    /// Acquires the exclusive lock only if it is neither exclusively nor shared locked, returns `Ok(None)` otherwise.
+    /// Never waits, so it may be used while holding arbitrary other locks.
+    /// Returns version before locking.
+    pub fn try_lock_exclusive<F: VersionFilter>(&self, f: F) -> Result<Option<F::R>, F::E> {
+        let mut x = self.0.load(Relaxed);
+        loop {
+            f.check(x >> VERSION_SHIFT)?;
+            if x & (EXCLUSIVE_MASK | COUNT_MASK) != 0 {
+                return Ok(None);
+            }
+            match self.0.compare_exchange_weak(x, x | EXCLUSIVE_MASK, Acquire, Relaxed) {
+                Ok(_) => {
+                    lock_track_set(self, Some(true));
+                    return Ok(Some(f.map_r(x >> VERSION_SHIFT)));
+                }
+                Err(v) => x = v,
+            }
+        }
+    }
+
     /// returns version after unlocking
     pub fn unlock_exclusive(&self) -> OlcVersion {
         lock_track_set(self, None);
         let fetched = self.0.fetch_add(EXCLUSIVE_MASK, Release);
         debug_assert!(fetched & EXCLUSIVE_MASK != 0);
+        self.wake_waiters(fetched);
+        OlcVersion { x: (fetched + EXCLUSIVE_MASK) >> VERSION_SHIFT }
+    }
+
+    /// Turns a held exclusive lock into a shared lock without unlocking in between.
+    /// returns version after unlocking, like [Self::unlock_exclusive]
+    pub fn downgrade_exclusive(&self) -> OlcVersion {
+        lock_track_set(self, Some(false));
+        // clearing the exclusive bit increments the version, the count is zero while locked exclusively
+        let fetched = self.0.fetch_add(EXCLUSIVE_MASK + 1, Release);
+        debug_assert!(fetched & (EXCLUSIVE_MASK | COUNT_MASK) == EXCLUSIVE_MASK);
+        self.wake_waiters(fetched);
         OlcVersion { x: (fetched + EXCLUSIVE_MASK) >> VERSION_SHIFT }
     }
 
+    /// Turns a held shared lock into an exclusive lock without unlocking in between.
+    /// Only succeeds if the caller is the only shared locker and no exclusive locker is draining readers.
+    /// Never waits, as two upgrading readers would wait for each other.
+    /// The shared lock is still held on failure, releasing it is up to the caller.
+    pub fn try_upgrade_shared(&self) -> Result<(), OptimisticError> {
+        let mut x = self.0.load(Relaxed);
+        loop {
+            if x & (EXCLUSIVE_MASK | COUNT_MASK) != 1 {
+                return Err(OptimisticError::new());
+            }
+            // shared lockers are kept out by the exclusive bit from now on
+            let locked = ((x - 1) & !INTENT_MASK) | EXCLUSIVE_MASK;
+            match self.0.compare_exchange_weak(x, locked, Acquire, Relaxed) {
+                Ok(_) => {
+                    lock_track_set(self, Some(true));
+                    return Ok(());
+                }
+                Err(v) => x = v,
+            }
+        }
+    }
+
     pub fn lock_optimistic<F: VersionFilter>(&self, f: F) -> Result<F::R, F::E> {
+        self.lock_optimistic_inner(f, None).map(|r| r.unwrap_or_else(|WouldBlock| unreachable!()))
+    }
+
+    /// Like [Self::lock_optimistic], but gives up with `Ok(None)` once `deadline` has passed.
+    pub fn lock_optimistic_until<F: VersionFilter>(&self, f: F, deadline: Instant) -> Result<Option<F::R>, F::E> {
+        self.lock_optimistic_inner(f, Some(deadline)).map(Result::ok)
+    }
+
+    /// Like [Self::lock_optimistic], but returns `Ok(None)` instead of waiting.
+    pub fn try_lock_optimistic<F: VersionFilter>(&self, f: F) -> Result<Option<F::R>, F::E> {
         lock_track_check(self, None);
+        let x = self.0.load(Acquire);
+        f.check(x >> VERSION_SHIFT)?;
+        if x & EXCLUSIVE_MASK == 0 {
+            Ok(Some(f.map_r(x >> VERSION_SHIFT)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn lock_optimistic_inner<F: VersionFilter>(
+        &self,
+        f: F,
+        deadline: Option<Instant>,
+    ) -> Result<Result<F::R, WouldBlock>, F::E> {
+        lock_track_check(self, None);
+        lock_track_order(self, None);
+        let mut backoff = Backoff::new(deadline);
         loop {
             let x = self.0.load(Acquire);
             f.check(x >> VERSION_SHIFT)?;
             if x & EXCLUSIVE_MASK == 0 {
-                return Ok(f.map_r(x >> VERSION_SHIFT));
-            } else {
-                self.wait();
+                return Ok(Ok(f.map_r(x >> VERSION_SHIFT)));
+            } else if let Err(e) = backoff.wait(self, x) {
+                return Ok(Err(e));
             }
         }
     }
@@ -153,7 +466,7 @@ impl SeqLock {
     pub fn try_unlock_optimistic(&self, v: OlcVersion) -> Result<(), OptimisticError> {
         fence(Acquire);
         let x = self.0.load(Relaxed);
-        if (x & !COUNT_MASK) == v.x << VERSION_SHIFT {
+        if (x & !(COUNT_MASK | WAITERS_MASK | INTENT_MASK)) == v.x << VERSION_SHIFT {
             Ok(())
         } else {
             Err(OptimisticError::new())
@@ -161,48 +474,54 @@ impl SeqLock {
     }
 }
 
-#[cfg(not(feature = "track-thread-locks"))]
-fn lock_track_check(_lock: &SeqLock, _mode: Option<bool>) {}
-#[cfg(not(feature = "track-thread-locks"))]
-fn lock_track_set(_lock: &SeqLock, _mode: Option<bool>) {}
-
-#[cfg(feature = "track-thread-locks")]
-use track_tread_locks::*;
+#[cfg(target_os = "linux")]
+mod futex {
+    use std::ptr::null;
+    use std::time::Duration;
 
-#[cfg(feature = "track-thread-locks")]
-mod track_tread_locks {
-    use super::SeqLock;
-    use std::cell::RefCell;
-    use std::collections::HashMap;
-
-    std::thread_local! {
-        static THREAD_LOCKS:RefCell<HashMap<usize,bool>>=Default::default();
+    pub fn wait(word: *const u32, expected: u32, timeout: Duration) {
+        let timeout = libc::timespec { tv_sec: timeout.as_secs() as _, tv_nsec: timeout.subsec_nanos() as _ };
+        unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                word,
+                libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+                expected,
+                &timeout as *const libc::timespec,
+            );
+        }
     }
 
-    pub fn lock_track_check(lock: &SeqLock, mode: Option<bool>) {
-        let addr = (lock as *const SeqLock).addr();
-        let existing = THREAD_LOCKS.with_borrow(|m| m.get(&addr).copied());
-        if existing.is_some() {
-            panic!("cannot acquire {} lock because {} is held by same thread", lock_name(mode), lock_name(existing))
+    pub fn wake_all(word: *const u32) {
+        unsafe {
+            libc::syscall(libc::SYS_futex, word, libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG, i32::MAX, null::<()>());
         }
     }
+}
 
-    pub fn lock_track_set(lock: &SeqLock, mode: Option<bool>) {
-        let addr = (lock as *const SeqLock).addr();
-        THREAD_LOCKS.with_borrow_mut(|m| {
-            if let Some(mode) = mode {
-                m.insert(addr, mode);
-            } else {
-                m.remove(&addr);
-            }
-        });
-    }
+#[cfg(not(target_os = "linux"))]
+mod futex {
+    use std::time::Duration;
 
-    fn lock_name(mode: Option<bool>) -> &'static str {
-        match mode {
-            None => "optimistic",
-            Some(false) => "shared",
-            Some(true) => "exclusive",
-        }
+    pub fn wait(_word: *const u32, _expected: u32, _timeout: Duration) {
+        std::thread::yield_now();
     }
+
+    pub fn wake_all(_word: *const u32) {}
 }
+
+#[cfg(not(feature = "track-thread-locks"))]
+fn lock_track_check(_lock: &SeqLock, _mode: Option<bool>) {}
+#[cfg(not(feature = "track-thread-locks"))]
+fn lock_track_order(_lock: &SeqLock, _mode: Option<bool>) {}
+#[cfg(not(feature = "track-thread-locks"))]
+fn lock_track_set(_lock: &SeqLock, _mode: Option<bool>) {}
+#[cfg(not(feature = "track-thread-locks"))]
+pub(crate) fn label_lock(_lock: &SeqLock, _pid: crate::PageId, _tag: Option<u8>) {}
+#[cfg(not(feature = "track-thread-locks"))]
+pub(crate) fn forget_locks(_first: *const SeqLock, _count: usize) {}
+
+#[cfg(feature = "track-thread-locks")]
+pub(crate) use crate::lock_tracking::{forget_locks, label_lock};
+#[cfg(feature = "track-thread-locks")]
+use crate::lock_tracking::{lock_track_check, lock_track_order, lock_track_set};
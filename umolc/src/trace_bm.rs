@@ -0,0 +1,121 @@
+use crate::{OlcVersion, PageId, WrappingBm};
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Mutex;
+use std::thread::ThreadId;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LockMode {
+    Optimistic,
+    Shared,
+    Exclusive,
+}
+
+/// Page operations recorded by a [TraceBm].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TraceOp {
+    Alloc,
+    Dealloc,
+    Acquire(LockMode),
+    /// Converts a guard from the first mode to the second, possibly failing validation.
+    Upgrade(LockMode, LockMode),
+    /// Converts an exclusive guard to the given mode.
+    Downgrade(LockMode),
+    Release(LockMode),
+    /// Validates an optimistic guard, including when it is dropped.
+    Validate,
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceEvent {
+    pub thread: ThreadId,
+    pub pid: PageId,
+    pub op: TraceOp,
+    /// The version the operation observed, or the version it expected if it was given one.
+    pub version: Option<u64>,
+    /// False if validation failed or the lock was not acquired.
+    pub ok: bool,
+}
+
+impl Display for TraceEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} {:?} page={}", self.thread, self.op, self.pid.x)?;
+        if let Some(v) = self.version {
+            write!(f, " version={v}")?;
+        }
+        f.write_str(if self.ok { " ok" } else { " failed" })
+    }
+}
+
+/// Wraps another buffer manager and records every page operation of its guards, along with the thread and the page
+/// version.
+///
+/// Only the most recent `capacity` events are kept, so a trace dumped after a failure shows the interleaving of
+/// threads leading up to it.
+/// All events go through a single mutex, which serializes threads far more than the wrapped buffer manager does.
+pub struct TraceBm<B> {
+    inner: B,
+    capacity: usize,
+    events: Mutex<VecDeque<TraceEvent>>,
+    dropped: AtomicU64,
+}
+
+impl<B> TraceBm<B> {
+    pub fn new(inner: B, capacity: usize) -> Self {
+        assert!(capacity > 0);
+        TraceBm { inner, capacity, events: Mutex::new(VecDeque::new()), dropped: AtomicU64::new(0) }
+    }
+
+    /// The recorded events, oldest first.
+    pub fn events(&self) -> Vec<TraceEvent> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Number of events discarded to stay within the capacity.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Relaxed)
+    }
+
+    pub fn clear(&self) {
+        self.events.lock().unwrap().clear();
+        self.dropped.store(0, Relaxed);
+    }
+
+    /// Writes the recorded events to a file, one per line, oldest first.
+    pub fn dump(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let events = self.events();
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "# {} events, {} earlier events dropped", events.len(), self.dropped())?;
+        for e in &events {
+            writeln!(file, "{e}")?;
+        }
+        file.flush()
+    }
+
+    fn push(&self, event: TraceEvent) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() == self.capacity {
+            events.pop_front();
+            self.dropped.fetch_add(1, Relaxed);
+        }
+        events.push_back(event);
+    }
+}
+
+impl<B: Sync> WrappingBm for TraceBm<B> {
+    type Inner = B;
+
+    fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    fn record_trace(&self, pid: PageId, op: TraceOp, version: Option<OlcVersion>, ok: bool) {
+        self.push(TraceEvent { thread: std::thread::current().id(), pid, op, version: version.map(|v| v.x), ok });
+    }
+}
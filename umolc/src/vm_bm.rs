@@ -0,0 +1,158 @@
+use crate::anon_mmap::AnonMmap;
+use crate::free_pages::FreePages;
+use crate::seqlock::{forget_locks, SeqLock};
+use crate::{CommonSeqLockBM, OutOfPages, PageId, PageStats, UnwindOlcEh};
+use bytemuck::Zeroable;
+use std::cell::UnsafeCell;
+use std::io;
+use std::marker::PhantomData;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+
+/// number of never used pages added to the free list at once
+const FRESH_BATCH: usize = 64;
+
+#[derive(Clone, Debug)]
+pub struct VmBmConfig {
+    /// Ask the kernel to back pages with transparent huge pages.
+    pub huge_pages: bool,
+    /// Hand the memory of deallocated pages back to the operating system.
+    /// Reallocating such a page costs a page fault.
+    pub release_freed: bool,
+}
+
+impl Default for VmBmConfig {
+    fn default() -> Self {
+        VmBmConfig { huge_pages: false, release_freed: true }
+    }
+}
+
+/// A buffer manager that reserves address space for all pages up front, but only uses memory for pages that are
+/// actually touched.
+///
+/// Unlike [crate::SimpleBm], creating a `VmBm` with a huge capacity is cheap.
+/// Freed pages are reused before new ones are touched, so the used part of the address space stays compact.
+/// Deallocated pages read as zeros once their memory has been released.
+pub struct VmBm<P> {
+    pages: AnonMmap,
+    locks: AnonMmap,
+    capacity: usize,
+    config: VmBmConfig,
+    /// pages at or above this have never been allocated
+    fresh: AtomicUsize,
+    free_pages: FreePages,
+    page_stats: Option<PageStats>,
+    _p: PhantomData<P>,
+}
+
+unsafe impl<P> Sync for VmBm<P> {}
+unsafe impl<P> Send for VmBm<P> {}
+
+impl<P: Zeroable> VmBm<P> {
+    pub fn new(capacity: usize) -> io::Result<Self> {
+        Self::with_config(capacity, VmBmConfig::default())
+    }
+
+    pub fn with_config(capacity: usize, config: VmBmConfig) -> io::Result<Self> {
+        let pages = AnonMmap::reserve(capacity * size_of::<P>())?;
+        assert_eq!(pages.as_ptr().addr() % align_of::<P>(), 0);
+        if config.huge_pages {
+            pages.advise(0, capacity * size_of::<P>(), libc::MADV_HUGEPAGE);
+        }
+        Ok(VmBm {
+            pages,
+            locks: AnonMmap::reserve(capacity * size_of::<SeqLock>())?,
+            capacity,
+            config,
+            fresh: AtomicUsize::new(0),
+            free_pages: FreePages::new([]),
+            page_stats: None,
+            _p: PhantomData,
+        })
+    }
+}
+
+impl<P> VmBm<P> {
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn config(&self) -> &VmBmConfig {
+        &self.config
+    }
+
+    /// Starts counting lock acquisitions, optimistic failures and exclusive wait time for every page,
+    /// see [CommonSeqLockBM::page_stats].
+    /// The counters are zeroed lazily like the pages, so this is cheap even for a huge capacity.
+    pub fn enable_page_stats(&mut self) {
+        self.page_stats = Some(PageStats::new(self.capacity));
+    }
+
+    /// Number of pages that have been allocated at least once.
+    /// Memory is only committed for these.
+    pub fn touched_pages(&self) -> usize {
+        self.fresh.load(Relaxed)
+    }
+
+    /// Adds never used pages to the free list, caller must hold the lock on the shared pool of free pages.
+    fn add_fresh(&self, pool: &mut impl Extend<usize>) -> Result<(), OutOfPages> {
+        let start = self.fresh.load(Relaxed);
+        let end = (start + FRESH_BATCH).min(self.capacity);
+        if start == end {
+            return Err(OutOfPages);
+        }
+        pool.extend(start..end);
+        self.fresh.store(end, Relaxed);
+        Ok(())
+    }
+}
+
+impl<P> Drop for VmBm<P> {
+    fn drop(&mut self) {
+        forget_locks(self.locks.as_ptr() as *const SeqLock, self.capacity);
+    }
+}
+
+impl<'bm, P: Zeroable> CommonSeqLockBM<'bm> for &'bm VmBm<P> {
+    type Page = P;
+    type OlcEH = UnwindOlcEh;
+
+    fn pid_from_address(self, address: usize) -> PageId {
+        let offset = address - self.pages.as_ptr().addr();
+        assert_eq!(offset % size_of::<P>(), 0);
+        let pid = offset / size_of::<P>();
+        assert!(pid < self.capacity);
+        PageId { x: pid as u64 }
+    }
+
+    fn try_alloc(self) -> Result<PageId, OutOfPages> {
+        let pid = PageId { x: self.free_pages.pop(|pool| self.add_fresh(pool))? as u64 };
+        self.lock(pid).force_lock_exclusive();
+        Ok(pid)
+    }
+
+    fn dealloc(self, pid: PageId) {
+        if self.config.release_freed {
+            // optimistic readers may still access the page, they will read zeros and fail validation
+            self.pages.release(pid.x as usize * size_of::<P>(), size_of::<P>());
+        }
+        self.lock(pid).unlock_exclusive();
+        self.free_pages.push(pid.x as usize)
+    }
+
+    fn page(self, pid: PageId) -> &'bm UnsafeCell<Self::Page> {
+        let pid = pid.x as usize;
+        assert!(pid < self.capacity);
+        unsafe { &*(self.pages.as_ptr() as *const UnsafeCell<P>).add(pid) }
+    }
+
+    fn lock(self, pid: PageId) -> &'bm SeqLock {
+        let pid = pid.x as usize;
+        assert!(pid < self.capacity);
+        unsafe { &*(self.locks.as_ptr() as *const SeqLock).add(pid) }
+    }
+
+    fn page_stats(self) -> Option<&'bm PageStats> {
+        self.page_stats.as_ref()
+    }
+}
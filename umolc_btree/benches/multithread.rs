@@ -18,7 +18,7 @@ use std::sync::atomic::Ordering::Relaxed;
 use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::Barrier;
 use std::time::{Duration, Instant};
-use umolc::SimpleBm;
+use umolc::{set_wait_strategy, SimpleBm, WaitStrategy};
 use umolc_btree::Tree;
 
 struct Args {
@@ -31,6 +31,7 @@ struct Args {
     scan_duration: f64,
     zipf: f64,
     threads: usize,
+    wait_strategy: WaitStrategy,
     counters: Option<Vec<String>>,
 }
 
@@ -72,6 +73,7 @@ fn get_args() -> Args {
         scan_duration: get_arg("SCAN_DURATION", 0.5),
         zipf: get_arg("ZIPF", 1.0),
         threads: get_arg("THREADS", 1),
+        wait_strategy: get_arg("WAIT", WaitStrategy::SpinThenPark),
         counters: if let Ok(c) = std::env::var("PERF") {
             Some(c.split(',').map(|x| x.to_string()).collect())
         } else {
@@ -132,6 +134,7 @@ fn main() {
         eprintln!("warning: debug assertions or validation enabled");
     }
     let args = get_args();
+    set_wait_strategy(args.wait_strategy);
     let mut perf = if let Some(c) = &args.counters {
         PerfCounters::with_counters(c.iter().map(|x| x.as_str()))
     } else {
@@ -184,6 +187,7 @@ fn main() {
             "insert":insert,
             "lookup":lookup,
         },
+        "wait_strategy":args.wait_strategy.to_string(),
         "build":build_info
     });
     if stdout().is_terminal() {
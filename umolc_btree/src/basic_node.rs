@@ -1,7 +1,7 @@
 use crate::define_node;
 use crate::heap_node::{HeapLength, HeapLengthError, HeapNode, HeapNodeInfo};
-use crate::key_source::{key_head, HeadSourceSlice, SourceSlice, SourceSlicePair};
-use crate::node::{find_separator, insert_upper_sibling, node_tag, page_cast_mut, page_id_from_bytes, page_id_from_olc_bytes, CommonNodeHead, KindInner, KindLeaf, NodeDynamic, NodeKind, NodeStatic, Page, PromoteError, ToFromPageExt, PAGE_ID_LEN, PAGE_SIZE};
+use crate::key_source::{common_prefix, key_head, HeadSourceSlice, SourceSlice, SourceSlicePair};
+use crate::node::{find_separator, insert_upper_sibling, node_tag, page_cast, page_cast_mut, page_id_from_bytes, page_id_from_olc_bytes, CommonNodeHead, KindInner, KindLeaf, NodeDynamic, NodeKind, NodeStatic, Page, PromoteError, ScanRange, SplitError, ToFromPageExt, PAGE_ID_LEN, PAGE_SIZE, UNDERFULL_SIZE};
 use crate::util::Supreme;
 use bstr::{BStr, BString};
 use bytemuck::{Pod, Zeroable};
@@ -11,7 +11,7 @@ use std::fmt::{Debug, Formatter};
 use std::mem::{offset_of, size_of, MaybeUninit};
 use std::ops::Range;
 use std::sync::atomic::{AtomicU8, Ordering};
-use umolc::{o_project, BufferManager, OPtr, OlcErrorHandler, PageId};
+use umolc::{o_project, BufferManager, OPtr, OPtrMode, OlcErrorHandler, OptimisticError, PageId};
 use crate::fully_dense_leaf::FullyDenseLeaf;
 use crate::hash_leaf::HashLeaf;
 use crate::node::PromoteError::{Capacity, Keys, Node, ValueLen};
@@ -72,20 +72,27 @@ impl<V: NodeKind> BasicNode<V> {
         head.join(tail)
     }
 
-    fn find<O: OlcErrorHandler>(this: OPtr<Self, O>, key: &[u8]) -> Result<usize, usize> {
+    fn find<O: OlcErrorHandler, M: OPtrMode>(this: OPtr<Self, O, M>, key: &[u8]) -> Result<usize, usize> {
+        O::optmistic_fail_check(Self::try_find(this, key))
+    }
+
+    fn try_find<O: OlcErrorHandler, M: OPtrMode>(
+        this: OPtr<Self, O, M>,
+        key: &[u8],
+    ) -> Result<Result<usize, usize>, OptimisticError> {
         let prefix_len = o_project!(this.common.prefix_len).r() as usize;
         if prefix_len > key.len() {
-            O::optimistic_fail()
+            return Err(OptimisticError::new());
         }
         let truncated = &key[prefix_len..];
         let needle_head = key_head(truncated);
         let count = o_project!(this.common.count).r() as usize;
         let slot_start_index = Self::slot_offset(count) / 2;
-        let slots = this.as_slice::<u16>().i(slot_start_index..slot_start_index + count);
-        let heads = this.as_slice::<u32>().i(Self::HEAD_OFFSET / 4..Self::HEAD_OFFSET / 4 + count);
+        let slots = this.as_slice::<u16>().try_i(slot_start_index..slot_start_index + count)?;
+        let heads = this.as_slice::<u32>().try_i(Self::HEAD_OFFSET / 4..Self::HEAD_OFFSET / 4 + count)?;
         let hints = o_project!(this.hints).unsize();
         if heads.len() == 0 {
-            return Err(0);
+            return Ok(Err(0));
         }
         let mut head_range_start = 0;
         let mut head_range_end = heads.len();
@@ -114,22 +121,34 @@ impl<V: NodeKind> BasicNode<V> {
             assert!(MIN_HINT_SPACING >= 2);
         };
 
+        // head_range_end <= count, so this cannot go out of bounds
         let matching_head_range =
             (head_range_start..=head_range_end - 1).binary_all(|i| heads.i(i).r().cmp(&needle_head));
         if matching_head_range.is_empty() {
-            return Err(matching_head_range.start);
+            return Ok(Err(matching_head_range.start));
         }
-        let key_position = (matching_head_range.start..=matching_head_range.end - 1).binary_by(move |i| {
-            let offset = slots.i(i).r() as usize;
-            let len = this.read_unaligned_nonatomic_u16(offset);
-            let tail = this.as_slice::<u8>().sub(offset + Self::RECORD_TO_KEY_OFFSET, len.saturating_sub(4));
-            if len <= 4 || truncated.len() <= 4 {
-                len.cmp(&truncated.len())
-            } else {
-                tail.mem_cmp(&truncated[4..])
-            }
+        // the comparator cannot return an error, so it is stored and ends the search
+        let mut error = None;
+        let key_position = (matching_head_range.start..=matching_head_range.end - 1).binary_by(|i| {
+            let compare = || -> Result<std::cmp::Ordering, OptimisticError> {
+                let offset = slots.try_i(i)?.r() as usize;
+                let len = this.try_read_unaligned_nonatomic_u16(offset)?;
+                let tail = this.as_slice::<u8>().try_sub(offset + Self::RECORD_TO_KEY_OFFSET, len.saturating_sub(4))?;
+                if len <= 4 || truncated.len() <= 4 {
+                    Ok(len.cmp(&truncated.len()))
+                } else {
+                    Ok(tail.mem_cmp(&truncated[4..]))
+                }
+            };
+            compare().unwrap_or_else(|e| {
+                error = Some(e);
+                std::cmp::Ordering::Equal
+            })
         });
-        key_position
+        match error {
+            Some(e) => Err(e),
+            None => Ok(key_position),
+        }
     }
 
     const LOWER_OFFSET: usize = offset_of!(Self, _data);
@@ -206,7 +225,7 @@ impl<V: NodeKind> BasicNode<V> {
     }
 
     fn remove<O: OlcErrorHandler>(&mut self, key: &[u8]) -> Option<()> {
-        let Ok(index) = Self::find::<O>(OPtr::from_mut(self), key) else {
+        let Ok(index) = Self::find::<O, _>(OPtr::locked(self), key) else {
             return None;
         };
         self.heap_free(index);
@@ -236,6 +255,13 @@ impl<V: NodeKind> BasicNode<V> {
     const RECORD_TO_KEY_OFFSET: usize = if V::IS_LEAF { 4 } else { 2 };
 }
 
+impl BasicInner {
+    /// Removes the separator `key` along with the child to its right.
+    pub fn remove_separator<O: OlcErrorHandler>(&mut self, key: &[u8]) -> Option<()> {
+        self.remove::<O>(key)
+    }
+}
+
 impl<V: NodeKind> Debug for BasicNode<V> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let mut s = f.debug_struct(std::any::type_name::<Self>());
@@ -273,7 +299,7 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>, V: NodeKind> NodeStatic<'bm, BM>
 
     fn insert(&mut self, key: &[u8], val: &[u8]) -> Result<Option<()>, ()> {
 
-        let index = Self::find::<BM::OlcEH>(OPtr::from_mut(self), key);
+        let index = Self::find::<BM::OlcEH, _>(OPtr::locked(self), key);
         let count = self.common.count as usize;
         let new_heap_start = Self::heap_start_min(count + index.is_err() as usize);
         let key = &key[self.common.prefix_len as usize..];
@@ -319,18 +345,33 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>, V: NodeKind> NodeStatic<'bm, BM>
         lower.chain(rest).map(|(k, o)| (k, page_id_from_bytes(self.page_id_bytes(o))))
     }
 
-    fn lookup_leaf<'a>(this: OPtr<'a, Self, BM::OlcEH>, key: &[u8]) -> Option<OPtr<'a, [u8], BM::OlcEH>> {
-        assert!(V::IS_LEAF);
-        let index = Self::find(this, key).ok()?;
+    fn lookup_leaf<'a, M: OPtrMode>(
+        this: OPtr<'a, Self, BM::OlcEH, M>,
+        key: &[u8],
+    ) -> Result<Option<OPtr<'a, [u8], BM::OlcEH, M>>, OptimisticError> {
+        if !V::IS_LEAF {
+            // the tag was read from a page that is being modified
+            return Err(OptimisticError::new());
+        }
+        let Ok(index) = Self::try_find(this, key)? else {
+            return Ok(None);
+        };
         let slot_offset = Self::slot_offset(o_project!(this.common.count).r() as usize);
-        let offset = this.as_slice::<u16>().i(slot_offset / 2 + index).r() as usize;
-        let v_len = this.read_unaligned_nonatomic_u16(offset + 2);
-        Some(this.as_slice().sub(offset - v_len, v_len))
+        let offset = this.as_slice::<u16>().try_i(slot_offset / 2 + index)?.r() as usize;
+        let v_len = this.try_read_unaligned_nonatomic_u16(offset + 2)?;
+        let v_start = offset.checked_sub(v_len).ok_or_else(OptimisticError::new)?;
+        Ok(Some(this.as_slice().try_sub(v_start, v_len)?))
     }
 
-    fn lookup_inner(this: OPtr<'_, Self, BM::OlcEH>, key: &[u8], high_on_equal: bool) -> PageId {
-        assert!(!V::IS_LEAF);
-        let index = match Self::find(this, key) {
+    fn lookup_inner<M: OPtrMode>(
+        this: OPtr<'_, Self, BM::OlcEH, M>,
+        key: &[u8],
+        high_on_equal: bool,
+    ) -> Result<PageId, OptimisticError> {
+        if V::IS_LEAF {
+            return Err(OptimisticError::new());
+        }
+        let index = match Self::try_find(this, key)? {
             Err(i) => i,
             Ok(i) => i + high_on_equal as usize,
         };
@@ -338,10 +379,10 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>, V: NodeKind> NodeStatic<'bm, BM>
             Self::LOWER_OFFSET
         } else {
             let slot_offset = Self::slot_offset(o_project!(this.common.count).r() as usize);
-            let offset = this.as_slice::<u16>().i(slot_offset / 2 + index - 1).r() as usize;
-            offset - PAGE_ID_LEN
+            let offset = this.as_slice::<u16>().try_i(slot_offset / 2 + index - 1)?.r() as usize;
+            offset.checked_sub(PAGE_ID_LEN).ok_or_else(OptimisticError::new)?
         };
-        page_id_from_olc_bytes(this.array_slice(lower_offset))
+        Ok(page_id_from_olc_bytes(this.try_array_slice(lower_offset)?))
     }
 
 
@@ -419,18 +460,43 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>, V: NodeKind> NodeDynamic<'bm, BM>
             tmp.common.count = (left_count + right_count + 1) as u16;
             self.copy_records(&mut tmp, 0..left_count, 0);
             right.copy_records(&mut tmp, 0..right_count, left_count + 1);
-            tmp.heap_write_new(
+            tmp.insert_pre_allocated_slot(
+                left_count,
                 self.as_page().upper_fence_combined().slice_start(tmp.common.prefix_len as usize),
                 right.lower().as_slice(),
-                left_count,
             );
         }
-        tmp.update_hints(0, tmp.common.count as usize, 0);
+        if tmp.common.count != 0 {
+            tmp.update_hints(0, tmp.common.count as usize, 0);
+        }
+        NodeStatic::<BM>::set_scan_counter(&mut tmp, &self.common.scan_counter);
         tmp.validate();
         *self = tmp;
     }
 
-    fn split(&mut self, bm: BM, parent: &mut dyn NodeDynamic<'bm, BM>, _key: &[u8]) -> Result<(), ()> {
+    fn can_merge(&self, right: &Page) -> bool {
+        if right.common.tag != Self::TAG {
+            return false;
+        }
+        let right = page_cast::<Page, Self>(right);
+        let upper = right.upper_fence_combined();
+        let prefix_len = common_prefix(self.lower_fence(), upper);
+        let mut count = self.common.count as usize + right.common.count as usize;
+        let mut records = self.records_size_with_prefix(prefix_len) + right.records_size_with_prefix(prefix_len);
+        if !V::IS_LEAF {
+            // the separator becomes a record pointing to the lower child of right
+            count += 1;
+            records += <Self as HeapNode>::KEY_OFFSET + self.upper_fence_combined().len() - prefix_len + PAGE_ID_LEN;
+        }
+        let fences = self.lower_fence().len() + upper.len() - prefix_len;
+        Self::heap_start_min(count) + records + fences <= size_of::<Self>()
+    }
+
+    fn is_underfull(&self) -> bool {
+        Self::heap_start_min(self.common.count as usize) + self.heap_used() < UNDERFULL_SIZE
+    }
+
+    fn split(&mut self, bm: BM, parent: &mut dyn NodeDynamic<'bm, BM>, _key: &[u8]) -> Result<(), SplitError> {
 
         let (lft, rght) = NodeStatic::<BM>::has_good_heads(self);
 
@@ -539,8 +605,14 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>, V: NodeKind> NodeDynamic<'bm, BM>
 
 
 
-                let mut min_suffix = u32::MAX;
-                let mut max_suffix = 0;
+                // keys are stored at their offset from the reference the dense leaf derives from its lower fence
+                let full_len = self.common.prefix_len as usize + key_len;
+                let Some(reference) = FullyDenseLeaf::reference_for(self.lower_fence(), full_len) else {
+                    return Err(Capacity);
+                };
+                let capacity = FullyDenseLeaf::get_capacity_fdl(self.lower_fence().len(),
+                                                                self.upper_fence_tail().len(),
+                                                                first_val.len());
 
                 for i in 0..count {
                     let mut suffix = self.key_combined(i).to_vec();
@@ -549,26 +621,10 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>, V: NodeKind> NodeDynamic<'bm, BM>
                     full_key.extend_from_slice(self.prefix());
                     full_key.append(&mut suffix);
 
-                    let full_len = full_key.len();
-                    let numeric_slice = &full_key[full_len.saturating_sub(4)..];
-
-                    let mut padded = [0u8; 4];
-                    padded[..full_len.min(4)].copy_from_slice(&numeric_slice);
-
-                    let index = u32::from_be_bytes(padded.try_into().unwrap());
-
-
-                    min_suffix = min_suffix.min(index);
-                    max_suffix = max_suffix.max(index);
-                }
-                let area = max_suffix - min_suffix + 1;
-
-                if area as usize >
-                    FullyDenseLeaf::get_capacity_fdl(self.lower_fence().len(),
-                                                     self.upper_fence_tail().len(),
-                                                     first_val.len()) {
-                    return Err(Capacity);
-
+                    let index = FullyDenseLeaf::extract_numeric_part(&full_key);
+                    if index < reference || (index - reference) as usize >= capacity {
+                        return Err(Capacity);
+                    }
                 }
 
                 Ok(())
@@ -672,40 +728,19 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>, V: NodeKind> NodeDynamic<'bm, BM>
     fn scan_with_callback(
         &self,
         buffer: &mut [MaybeUninit<u8>; 512],
-        start: &[u8],
+        range: &ScanRange,
         callback: &mut dyn FnMut(&[u8], &[u8]) -> bool
     ) -> bool {
-
-        let mut lf : usize = 0;
-
-        if self.lower_fence() != start {
-
-            let index = Self::find::<BM::OlcEH>(unsafe { OPtr::from_ref(self) }, start);
-            lf = index.unwrap_or(0);
-
-        }
-
         let prefix = self.prefix();
         let prefix_len = prefix.len();
         prefix.write_to_uninit(&mut buffer[..prefix_len]);
-        for i in lf..self.common.count as usize {
-            let val = self.heap_val(i as usize);
-
-            let suffix = self.key_combined(i as usize);
-
+        let seek = |key: &[u8]| Self::find::<BM::OlcEH, _>(OPtr::locked(self), key).unwrap_or_else(|i| i);
+        range.scan_node(self, buffer, self.common.count as usize, seek, |i, buffer| {
+            let suffix = self.key_combined(i);
             let total_len = prefix_len + suffix.len();
             suffix.write_to_uninit(&mut buffer[prefix_len..total_len]);
-            let full_key : &mut [u8] = unsafe {
-                std::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, total_len)
-            };
-
-
-            if callback(&full_key, val) {
-                return true;
-            }
-        }
-
-        false
+            Some((total_len, self.heap_val(i)))
+        }, callback)
     }
 
 
@@ -783,8 +818,10 @@ mod tests {
                 // lookup
                 for (_i, k) in keys.iter().enumerate() {
                     let expected = Some(k).filter(|_| inserted.contains(k.as_slice()));
-                    let actual = N::lookup_leaf(OPtr::from_mut(leaf), &k[..]).map(|v| v.load_slice_to_vec());
+                    let actual = N::lookup_leaf(OPtr::from_mut(leaf), &k[..]).unwrap().map(|v| v.load_slice_to_vec());
                     assert_eq!(expected, actual.as_ref());
+                    let locked = N::lookup_leaf(OPtr::locked(&*leaf), &k[..]).unwrap().map(|v| v.load_slice_to_vec());
+                    assert_eq!(actual, locked);
                 }
             }
         }
@@ -0,0 +1,97 @@
+use crate::node::{Page, ScanRange};
+use crate::tree::{ScanBatch, Tree};
+use std::ops::Bound;
+use umolc::BufferManager;
+
+/// A position in a [Tree] that can be moved in both directions, created by [Tree::cursor].
+///
+/// The entries around the current one are copied out of its leaf, so no page locks are held between calls and
+/// concurrent writes are not blocked.
+/// Moves within the copied entries only check that the leaf is unchanged, other moves descend from the root and
+/// continue from the key of the current entry, either way they see entries inserted or removed since the previous
+/// move.
+///
+/// Besides the entries of the tree, there is an unpositioned state between the last and the first key.
+/// A new cursor starts there, and moving past either end returns to it.
+pub struct Cursor<'t, 'bm, BM: BufferManager<'bm, Page = Page>> {
+    tree: &'t Tree<'bm, BM>,
+    /// consecutive entries of one leaf, in descending order if `reverse`
+    batch: ScanBatch,
+    reverse: bool,
+    /// the current entry in `batch`, `None` if unpositioned
+    index: Option<usize>,
+    /// the key a move continues from, kept to reuse its allocation
+    key: Vec<u8>,
+}
+
+impl<'t, 'bm, BM: BufferManager<'bm, Page = Page>> Cursor<'t, 'bm, BM> {
+    pub(crate) fn new(tree: &'t Tree<'bm, BM>) -> Self {
+        Cursor { tree, batch: ScanBatch::default(), reverse: false, index: None, key: Vec::new() }
+    }
+
+    /// Moves to the first key greater than or equal to `key`, returns false if there is none.
+    pub fn seek(&mut self, key: &[u8]) -> bool {
+        self.move_to(Bound::Included(key), Bound::Unbounded, false)
+    }
+
+    /// Moves to the next key, or to the first key if the cursor is unpositioned.
+    /// Returns false if there is none.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> bool {
+        self.step(false)
+    }
+
+    /// Moves to the previous key, or to the last key if the cursor is unpositioned.
+    /// Returns false if there is none.
+    pub fn prev(&mut self) -> bool {
+        self.step(true)
+    }
+
+    pub fn is_positioned(&self) -> bool {
+        self.index.is_some()
+    }
+
+    pub fn key(&self) -> Option<&[u8]> {
+        self.index.map(|i| self.batch.get(i).0)
+    }
+
+    pub fn value(&self) -> Option<&[u8]> {
+        self.index.map(|i| self.batch.get(i).1)
+    }
+
+    /// Leaves the cursor unpositioned.
+    pub fn reset(&mut self) {
+        self.index = None;
+    }
+
+    fn step(&mut self, reverse: bool) -> bool {
+        let Some(index) = self.index else {
+            return self.move_to(Bound::Unbounded, Bound::Unbounded, reverse);
+        };
+        // the batch holds consecutive entries of its leaf, so while that is unchanged they are still neighbours
+        let next = if reverse == self.reverse { index.checked_add(1) } else { index.checked_sub(1) };
+        if let Some(next) = next.filter(|&i| i < self.batch.len()) {
+            if self.batch.leaf().is_some_and(|(leaf, version)| self.tree.leaf_unchanged(leaf, version)) {
+                self.index = Some(next);
+                return true;
+            }
+        }
+        let mut key = std::mem::take(&mut self.key);
+        key.clear();
+        key.extend_from_slice(self.batch.get(index).0);
+        let found = if reverse {
+            self.move_to(Bound::Unbounded, Bound::Excluded(&key), true)
+        } else {
+            self.move_to(Bound::Excluded(&key), Bound::Unbounded, false)
+        };
+        self.key = key;
+        found
+    }
+
+    fn move_to(&mut self, lower: Bound<&[u8]>, upper: Bound<&[u8]>, reverse: bool) -> bool {
+        self.tree.scan_batches(ScanRange { lower, upper, reverse }, &mut self.batch, |batch| batch.len() > 0);
+        self.reverse = reverse;
+        self.index = (self.batch.len() > 0).then_some(0);
+        self.index.is_some()
+    }
+}
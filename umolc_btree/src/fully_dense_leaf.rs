@@ -3,7 +3,7 @@ use crate::fully_dense_leaf::insert_resolver::{resolve, Resolution};
 use crate::hash_leaf::HashLeaf;
 use crate::key_source::{HeadSourceSlice, SourceSlice, SourceSlicePair, ZeroKey};
 use crate::node::PromoteError::Node;
-use crate::node::{insert_upper_sibling, node_tag, page_cast_mut, CommonNodeHead, KindLeaf, NodeDynamic, NodeStatic, PromoteError, ToFromPageExt, PAGE_ID_LEN, PAGE_SIZE};
+use crate::node::{partition_point, insert_upper_sibling, node_tag, page_cast_mut, CommonNodeHead, KindLeaf, NodeDynamic, NodeStatic, PromoteError, ScanRange, SplitError, ToFromPageExt, PAGE_ID_LEN, PAGE_SIZE, UNDERFULL_SIZE};
 use crate::{define_node, Page, MAX_KEY_SIZE};
 use bstr::{BStr, BString};
 use bytemuck::Zeroable;
@@ -14,7 +14,7 @@ use std::fmt::{Debug, Display, Formatter};
 use std::mem::{offset_of, MaybeUninit};
 use std::sync::atomic::{AtomicU8, Ordering};
 use std::usize;
-use umolc::{o_project, BufferManager, OPtr, OlcErrorHandler, PageId};
+use umolc::{o_project, BufferManager, OPtr, OPtrMode, OlcErrorHandler, OptimisticError, PageId};
 
 define_node! {
     pub struct FullyDenseLeaf {
@@ -37,9 +37,9 @@ impl FullyDenseLeaf {
         unsafe { std::mem::transmute(self) }
     }
 
+    /// The capacity [Self::init] chooses for fences of the given lengths, `uf_len` excluding the prefix.
     pub fn get_capacity_fdl(lf_len: usize, uf_len: usize, val_len: usize) -> usize {
-        let header_size = size_of::<CommonNodeHead>() + 2 + 2 + 2 + 4 + 1;
-        let space = PAGE_SIZE - header_size - lf_len - uf_len;
+        let space = Self::space(lf_len, uf_len);
         let mut capacity = space * 8 / (val_len * 8 + 1);
         let is_ok = |capacity: usize| capacity.next_multiple_of(64) / 8 + capacity * val_len <= space;
         while !is_ok(capacity) {
@@ -48,6 +48,10 @@ impl FullyDenseLeaf {
         capacity
     }
 
+    fn space(lf_len: usize, uf_len: usize) -> usize {
+        PAGE_SIZE - lf_len - uf_len.max(4) - PAGE_ID_LEN - offset_of!(Self, _data)
+    }
+
     pub fn get_reference(&self) -> u32 {
         self.reference
     }
@@ -55,31 +59,38 @@ impl FullyDenseLeaf {
     // may optimistic fail if key outside fence range
     // otherwise returns Err(()) if length mismatch or nnp mismatch
     // otherwise returns offset from reference, which may be out of bounds
-    fn key_to_index<O: OlcErrorHandler>(this: OPtr<Self, O>, k: &[u8]) -> Result<usize, ()> {
+    fn key_to_index<O: OlcErrorHandler, M: OPtrMode>(this: OPtr<Self, O, M>, k: &[u8]) -> Result<usize, ()> {
+        O::optmistic_fail_check(Self::try_key_to_index(this, k))
+    }
+
+    fn try_key_to_index<O: OlcErrorHandler, M: OPtrMode>(
+        this: OPtr<Self, O, M>,
+        k: &[u8],
+    ) -> Result<Result<usize, ()>, OptimisticError> {
         let prefix_len = o_project!(this.common.prefix_len).r() as usize;
         let lower_fence_start = PAGE_SIZE - o_project!(this.common.lower_fence_len).r() as usize;
         let key_len = o_project!(this.key_len).r() as usize;
         if key_len != k.len() {
-            return Err(());
+            return Ok(Err(()));
         }
 
         let numeric_start = key_len.saturating_sub(4);
         if numeric_start > prefix_len {
-            let nnp = this.as_slice().i(lower_fence_start + prefix_len..lower_fence_start + numeric_start);
+            let nnp = this.as_slice().try_i(lower_fence_start + prefix_len..lower_fence_start + numeric_start)?;
             if !nnp.mem_cmp(&k[prefix_len..numeric_start]).is_eq() {
-                return Err(());
+                return Ok(Err(()));
             }
         }
         let numeric_part = Self::extract_numeric_part(&k);
         let reference = o_project!(this.reference).r();
         if numeric_part < reference {
-            O::optimistic_fail();
+            Err(OptimisticError::new())
         } else {
-            Ok((numeric_part - reference) as usize)
+            Ok(Ok((numeric_part - reference) as usize))
         }
     }
 
-    fn extract_numeric_part(k: &[u8]) -> u32 {
+    pub fn extract_numeric_part(k: &[u8]) -> u32 {
         let numeric_start = k.len().saturating_sub(4);
         let numeric_part = if k.len() < 4 {
             let mut acc = 0;
@@ -104,8 +115,7 @@ impl FullyDenseLeaf {
     }
 
     pub fn force_insert<O: OlcErrorHandler>(&mut self, key: &[u8], val: &[u8]) {
-        let index = Self::key_to_index::<O>(
-            unsafe { OPtr::from_ref(self) }, key).expect("Index computation failed");
+        let index = Self::key_to_index::<O, _>(OPtr::locked(self), key).expect("Index computation failed");
 
         let was_present = self.set_bit::<true>(index);
         self.common.count += (!was_present) as u16;
@@ -113,8 +123,8 @@ impl FullyDenseLeaf {
     }
 
     // specific static call for usage in val_opt
-    fn first_val_start_static(capacity: usize) -> usize {
-        offset_of!(Self, _data) + Self::bitmap_u64_count(capacity) * 8
+    fn first_val_start_static(bitmap_len: usize) -> usize {
+        offset_of!(Self, _data) + bitmap_len
     }
 
     fn key_from_numeric_part(&self, np: u32) -> SourceSlicePair<u8, &[u8], HeadSourceSlice> {
@@ -125,9 +135,9 @@ impl FullyDenseLeaf {
         last_key
     }
 
-    fn get_bit<O: OlcErrorHandler>(this: OPtr<Self, O>, i: usize) -> bool {
+    fn get_bit<O: OlcErrorHandler, M: OPtrMode>(this: OPtr<Self, O, M>, i: usize) -> Result<bool, OptimisticError> {
         let mask = 1 << (i % 8);
-        o_project!(this._data).unsize().i(i / 8).r() & mask != 0
+        Ok(o_project!(this._data).unsize().try_i(i / 8)?.r() & mask != 0)
     }
 
     fn get_bit_direct(&self, i: usize) -> bool {
@@ -166,17 +176,11 @@ impl FullyDenseLeaf {
     /// returns Err(()) if there are no keys that could be inserted with given lower fence and key_len
     pub fn init(&mut self, lf: impl SourceSlice, uf: impl SourceSlice, key_len: usize, val_len: usize) -> Result<(), ()> {
         self.as_page_mut().common_init(node_tag::FULLY_DENSE_LEAF, lf, uf);
-        let space = PAGE_SIZE
-            - (self.common.lower_fence_len as usize)
-            - (self.common.upper_fence_len as usize).max(4)
-            - PAGE_ID_LEN
-            - offset_of!(Self, _data);
-        let mut capacity = space * 8 / (val_len * 8 + 1);
-        let is_ok = |capacity: usize| capacity.next_multiple_of(64) / 8 + capacity * val_len <= space;
-        while !is_ok(capacity) {
-            capacity -= 1;
-        }
-        debug_assert!(!is_ok(capacity + 1));
+        let capacity = Self::get_capacity_fdl(
+            self.common.lower_fence_len as usize,
+            self.common.upper_fence_len as usize,
+            val_len,
+        );
         self.capacity = capacity as u16;
         self.bitmap_len = Self::bitmap_u64_count(capacity) as u16 * 8;
         for i in 0..Self::bitmap_u64_count(capacity) {
@@ -185,20 +189,21 @@ impl FullyDenseLeaf {
         self.val_len = val_len as u16;
         self.key_len = key_len as u16;
         self.split_mode = 0;
-        self.reference = if lf.len() < key_len {
-            lf.join(ZeroKey::new(key_len - lf.len()))
-                .to_ref_buffer::<MAX_KEY_SIZE, _>(|k| Self::extract_numeric_part(k))
+        self.reference = Self::reference_for(lf, key_len).ok_or(())?;
+        Ok(())
+    }
+
+    /// The numeric part of the key at index 0 of a leaf with lower fence `lf`, or `None` if no key of length
+    /// `key_len` fits above the fence.
+    pub fn reference_for(lf: impl SourceSlice, key_len: usize) -> Option<u32> {
+        if lf.len() < key_len {
+            Some(lf.join(ZeroKey::new(key_len - lf.len())).to_ref_buffer::<MAX_KEY_SIZE, _>(|k| Self::extract_numeric_part(k)))
         } else if lf.len() == key_len {
-            lf.to_ref_buffer::<MAX_KEY_SIZE, _>(|k| Self::extract_numeric_part(k))
+            Some(lf.to_ref_buffer::<MAX_KEY_SIZE, _>(|k| Self::extract_numeric_part(k)))
         } else {
             let l = lf.slice(..key_len).to_ref_buffer::<MAX_KEY_SIZE, _>(|lf| Self::extract_numeric_part(&lf));
-            if l == u32::MAX {
-                return Err(());
-            } else {
-                l + 1
-            }
-        };
-        Ok(())
+            l.checked_add(1)
+        }
     }
 
     fn iter_key_indices<'a>(
@@ -243,11 +248,15 @@ impl FullyDenseLeaf {
         self.slice(self.first_val_start() + self.val_len as usize * i, self.val_len as usize)
     }
 
-    fn val_opt<O: OlcErrorHandler>(this: OPtr<Self, O>, i: usize) -> OPtr<[u8], O> {
+    fn val_opt<O: OlcErrorHandler, M: OPtrMode>(
+        this: OPtr<Self, O, M>,
+        i: usize,
+    ) -> Result<OPtr<[u8], O, M>, OptimisticError> {
         let val_len = o_project!(this.val_len).r() as usize;
-        let capacity = o_project!(this.capacity).r() as usize;
-        let first_val_start = Self::first_val_start_static(capacity);
-        this.as_slice().i(first_val_start + val_len * i..first_val_start + val_len * (i + 1))
+        // the bitmap keeps its length when a split reduces the capacity
+        let bitmap_len = o_project!(this.bitmap_len).r() as usize;
+        let first_val_start = Self::first_val_start_static(bitmap_len);
+        this.as_slice().try_i(first_val_start + val_len * i..first_val_start + val_len * (i + 1))
     }
 }
 
@@ -273,7 +282,7 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeStatic<'bm, BM> for FullyDens
                 val.len() == self.val_len as usize && key.len() == self.key_len as usize
             },
             || {
-                let res = Self::key_to_index::<BM::OlcEH>(unsafe { OPtr::from_ref(self) }, key);
+                let res = Self::key_to_index::<BM::OlcEH, _>(OPtr::locked(self), key);
                 if let Ok(i) = res {
                     index.set(i);
                 }
@@ -325,22 +334,32 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeStatic<'bm, BM> for FullyDens
         std::iter::once(unimplemented!())
     }
 
-    fn lookup_leaf<'a>(this: OPtr<'a, Self, BM::OlcEH>, key: &[u8]) -> Option<OPtr<'a, [u8], BM::OlcEH>> {
-        let i = Self::key_to_index(this, key).ok()?;
+    fn lookup_leaf<'a, M: OPtrMode>(
+        this: OPtr<'a, Self, BM::OlcEH, M>,
+        key: &[u8],
+    ) -> Result<Option<OPtr<'a, [u8], BM::OlcEH, M>>, OptimisticError> {
+        let Ok(i) = Self::try_key_to_index(this, key)? else {
+            return Ok(None);
+        };
         if i >= o_project!(this.capacity).r() as usize {
-            return None;
+            return Ok(None);
         }
 
         //TODO: fix pointer issues with get_bit
-        if Self::get_bit(this, i) {
-            Some(Self::val_opt(this, i))
+        if Self::get_bit(this, i)? {
+            Ok(Some(Self::val_opt(this, i)?))
         } else {
-            None
+            Ok(None)
         }
     }
 
-    fn lookup_inner(_this: OPtr<'_, Self, BM::OlcEH>, _key: &[u8], _high_on_equal: bool) -> PageId {
-        unimplemented!()
+    fn lookup_inner<M: OPtrMode>(
+        _this: OPtr<'_, Self, BM::OlcEH, M>,
+        _key: &[u8],
+        _high_on_equal: bool,
+    ) -> Result<PageId, OptimisticError> {
+        // the tag was read from a page that is being modified
+        Err(OptimisticError::new())
     }
 
 
@@ -362,7 +381,7 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeStatic<'bm, BM> for FullyDens
 }
 
 impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeDynamic<'bm, BM> for FullyDenseLeaf {
-    fn split(&mut self, bm: BM, parent: &mut dyn NodeDynamic<'bm, BM>, key: &[u8]) -> Result<(), ()> {
+    fn split(&mut self, bm: BM, parent: &mut dyn NodeDynamic<'bm, BM>, key: &[u8]) -> Result<(), SplitError> {
         if self.split_mode == SPLIT_MODE_HIGH {
 
             let mut right = insert_upper_sibling(parent, bm, key)?;
@@ -459,6 +478,17 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeDynamic<'bm, BM> for FullyDen
         todo!()
     }
 
+    fn can_merge(&self, _right: &Page) -> bool {
+        false
+    }
+
+    /// Only counts the values present, not the slots reserved for the rest of the key range.
+    /// Dense leaves are converted before merging, see [NodeDynamic::can_merge].
+    fn is_underfull(&self) -> bool {
+        let fence_bytes = self.lower_fence().len() + self.upper_fence_tail().len();
+        self.first_val_start() + self.common.count as usize * self.val_len as usize + fence_bytes < UNDERFULL_SIZE
+    }
+
     fn validate(&self) {
         assert!(self.key_len >= 4, "Bad key length");
         assert!(self.val_len > 0, "Bad val length");
@@ -483,7 +513,7 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeDynamic<'bm, BM> for FullyDen
     }
 
     fn leaf_remove(&mut self, k: &[u8]) -> Option<()> {
-        let i = Self::key_to_index::<BM::OlcEH>(OPtr::from_mut(self), k).ok()?;
+        let i = Self::key_to_index::<BM::OlcEH, _>(OPtr::locked(self), k).ok()?;
         if i >= self.capacity as usize {
             return None;
         }
@@ -498,49 +528,26 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeDynamic<'bm, BM> for FullyDen
     fn scan_with_callback(
         &self,
         buffer: &mut [MaybeUninit<u8>; 512],
-        start: &[u8],
+        range: &ScanRange,
         callback: &mut dyn FnMut(&[u8], &[u8]) -> bool
     ) -> bool {
-
-
-        let lf = if start == self.lower_fence() { 0 }
-        else {
-            let res = Self::key_to_index::<BM::OlcEH>(unsafe { OPtr::from_ref(self) }, start);
-            if let Ok(i) = res {
-                i
-            }
-            else {
-                0
-            }
-        };
-
-        let numeric_part_begin = self.key_len - 4;
-
-
-        let key_src = self.key_from_numeric_part(self.reference + 0);
+        let numeric_part_begin = self.key_len as usize - 4;
+        let key_src = self.key_from_numeric_part(self.reference);
         key_src.write_to_uninit(&mut buffer[..key_src.len() as usize]);
 
-
-        let mut np = self.reference + lf as u32;
-
-
-
-        for i in lf..self.capacity as usize {
-            if self.get_bit_direct(i) {
-
-                np.to_be_bytes().write_to_uninit(&mut buffer[numeric_part_begin as usize..numeric_part_begin as usize + 4]);
-                let val = self.val(i);
-                let full_key : &mut [u8] = unsafe {
-                    std::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, self.key_len as usize)
-                };
-
-                if callback(&full_key, val) {
-                    return true;
-                }
+        let capacity = self.capacity as usize;
+        // keys grow with their index, whether or not it is used
+        let seek = |key: &[u8]| {
+            partition_point(capacity, |i| self.key_from_numeric_part(self.reference + i as u32).cmp(key).is_lt())
+        };
+        range.scan_node(self, buffer, capacity, seek, |i, buffer| {
+            if !self.get_bit_direct(i) {
+                return None;
             }
-            np += 1;
-        }
-        false
+            let np = self.reference + i as u32;
+            np.to_be_bytes().write_to_uninit(&mut buffer[numeric_part_begin..numeric_part_begin + 4]);
+            Some((self.key_len as usize, self.val(i)))
+        }, callback)
     }
 
     fn get_node_tag(&self) -> u8 {
@@ -593,7 +600,7 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeDynamic<'bm, BM> for FullyDen
                 let key_len = self.key_len as usize;
                 let val_len = self.val_len as usize;
 
-                let head_bytes = 4 * count.next_multiple_of(BasicLeaf::reserved_head_count(count));
+                let head_bytes = 4 * BasicLeaf::reserved_head_count(count);
                 let slot_bytes = 2 * count;
 
                 let hint_bytes = 64;
@@ -864,6 +871,46 @@ mod test {
         }
     }
 
+    /// Keys that span no more than the capacity, but start above the reference, do not fit into a dense leaf.
+    fn test_promotion_above_reference<'bm, BM, Initial>()
+    where
+        BM: BufferManager<'bm, Page = Page>,
+        Initial: node::ToFromPage + node::NodeStatic<'bm, BM>,
+    {
+        let (key_len, val_len) = (8, 40);
+        let lowerfence = generate_key(0, key_len);
+        let upperfence = generate_key(4096, key_len);
+        let mut fdl = FullyDenseLeaf::zeroed();
+        fdl.init(lowerfence.as_slice(), upperfence.as_slice(), key_len, val_len).unwrap();
+        assert_eq!(fdl.reference, 0);
+
+        let mut page = Page::zeroed();
+        let leaf = page.cast_mut::<Initial>();
+        NodeStatic::<BM>::init(leaf, lowerfence.as_slice(), upperfence.as_slice(), None);
+        let val = vec![7u8; val_len];
+        let capacity = fdl.capacity as u32;
+        for i in [1, capacity] {
+            NodeStatic::<BM>::insert(leaf, generate_key(i, key_len).as_slice(), &val).unwrap();
+        }
+        assert!(NodeDynamic::<BM>::can_promote(leaf, node_tag::FULLY_DENSE_LEAF).is_err());
+
+        let highest = generate_key(capacity - 1, key_len);
+        leaf.as_page_mut().as_dyn_node_mut::<BM>().leaf_remove(generate_key(capacity, key_len).as_slice());
+        NodeStatic::<BM>::insert(leaf, highest.as_slice(), &val).unwrap();
+        assert!(NodeDynamic::<BM>::can_promote(leaf, node_tag::FULLY_DENSE_LEAF).is_ok());
+        NodeDynamic::<BM>::promote(leaf, node_tag::FULLY_DENSE_LEAF);
+        let promoted = leaf.as_page_mut().cast_mut::<FullyDenseLeaf>();
+        assert_eq!(promoted.capacity, fdl.capacity);
+        assert!(promoted.as_page_mut().as_dyn_node_mut::<BM>().leaf_remove(highest.as_slice()).is_some());
+    }
+
+    #[test]
+    fn promotion_above_reference() {
+        use crate::hash_leaf::HashLeaf;
+        test_promotion_above_reference::<&'static SimpleBm<Page>, BasicLeaf>();
+        test_promotion_above_reference::<&'static SimpleBm<Page>, HashLeaf>();
+    }
+
     #[test]
     fn basic_leaf_demotion() {
         for val_len in 0..100 {
@@ -1,6 +1,6 @@
 use crate::heap_node::{HeapNode, HeapNodeInfo};
-use crate::key_source::SourceSlice;
-use crate::node::{find_separator, insert_upper_sibling, node_tag, page_cast_mut, NodeDynamic, NodeStatic, ToFromPageExt, PAGE_SIZE, PromoteError, CommonNodeHead};
+use crate::key_source::{common_prefix, SourceSlice};
+use crate::node::{partition_point, find_separator, insert_upper_sibling, node_tag, page_cast, page_cast_mut, NodeDynamic, NodeStatic, ToFromPageExt, PAGE_SIZE, PromoteError, CommonNodeHead, ScanRange, SplitError, UNDERFULL_SIZE};
 use crate::util::Supreme;
 use crate::fully_dense_leaf::FullyDenseLeaf;
 use crate::{define_node, Page};
@@ -13,7 +13,7 @@ use std::vec::Vec;
 use std::mem::{offset_of, MaybeUninit};
 use std::ops::Range;
 use std::sync::atomic::{AtomicU8, Ordering};
-use umolc::{o_project, BufferManager, OPtr, OlcErrorHandler, PageId};
+use umolc::{o_project, BufferManager, OPtr, OPtrMode, OlcErrorHandler, OptimisticError, PageId};
 use crate::basic_node::BasicLeaf;
 use crate::hash_leaf::PromoteError::{Capacity, Keys, ValueLen};
 
@@ -72,10 +72,17 @@ impl HashLeaf {
         self.sorted = self.common.count
     }
 
-    fn find<O: OlcErrorHandler>(this: OPtr<Self, O>, key: &[u8]) -> (Option<usize>, u8) {
+    fn find<O: OlcErrorHandler, M: OPtrMode>(this: OPtr<Self, O, M>, key: &[u8]) -> (Option<usize>, u8) {
+        O::optmistic_fail_check(Self::try_find(this, key))
+    }
+
+    fn try_find<O: OlcErrorHandler, M: OPtrMode>(
+        this: OPtr<Self, O, M>,
+        key: &[u8],
+    ) -> Result<(Option<usize>, u8), OptimisticError> {
         let prefix_len = o_project!(this.common.prefix_len).r() as usize;
         if prefix_len > key.len() {
-            O::optimistic_fail();
+            return Err(OptimisticError::new());
         }
         let hash = Self::hash(key);
         let key = &key[prefix_len..];
@@ -83,16 +90,16 @@ impl HashLeaf {
         let hash_offset = Self::hash_offset(count);
 
         for i in 0..count {
-            if this.as_slice::<u8>().i(hash_offset + i).r() == hash {
-                let offset = o_project!(this._data).unsize().i(i).r() as usize;
-                let key_len = this.read_unaligned_nonatomic_u16(offset);
-                let stored_key = this.as_slice::<u8>().sub(offset + Self::RECORD_TO_KEY_OFFSET, key_len);
+            if this.as_slice::<u8>().try_i(hash_offset + i)?.r() == hash {
+                let offset = o_project!(this._data).unsize().try_i(i)?.r() as usize;
+                let key_len = this.try_read_unaligned_nonatomic_u16(offset)?;
+                let stored_key = this.as_slice::<u8>().try_sub(offset + Self::RECORD_TO_KEY_OFFSET, key_len)?;
                 if stored_key.mem_cmp(key).is_eq() {
-                    return (Some(i), hash);
+                    return Ok((Some(i), hash));
                 }
             }
         }
-        (None, hash)
+        Ok((None, hash))
     }
 
     fn validate(&self) {
@@ -178,19 +185,30 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeStatic<'bm, BM> for HashLeaf
         std::iter::once(unimplemented!())
     }
 
-    fn lookup_leaf<'a>(this: OPtr<'a, Self, BM::OlcEH>, key: &[u8]) -> Option<OPtr<'a, [u8], BM::OlcEH>> {
-        let (index, _hash) = Self::find(this, key);
-        let offset = o_project!(this._data).unsize().i(index?).r() as usize;
-        let v_len = this.read_unaligned_nonatomic_u16(offset + 2);
-        Some(this.as_slice().sub(offset - v_len, v_len))
+    fn lookup_leaf<'a, M: OPtrMode>(
+        this: OPtr<'a, Self, BM::OlcEH, M>,
+        key: &[u8],
+    ) -> Result<Option<OPtr<'a, [u8], BM::OlcEH, M>>, OptimisticError> {
+        let (Some(index), _hash) = Self::try_find(this, key)? else {
+            return Ok(None);
+        };
+        let offset = o_project!(this._data).unsize().try_i(index)?.r() as usize;
+        let v_len = this.try_read_unaligned_nonatomic_u16(offset + 2)?;
+        let v_start = offset.checked_sub(v_len).ok_or_else(OptimisticError::new)?;
+        Ok(Some(this.as_slice().try_sub(v_start, v_len)?))
     }
 
-    fn lookup_inner(_this: OPtr<'_, Self, BM::OlcEH>, _key: &[u8], _high_on_equal: bool) -> PageId {
-        unimplemented!()
+    fn lookup_inner<M: OPtrMode>(
+        _this: OPtr<'_, Self, BM::OlcEH, M>,
+        _key: &[u8],
+        _high_on_equal: bool,
+    ) -> Result<PageId, OptimisticError> {
+        // the tag was read from a page that is being modified
+        Err(OptimisticError::new())
     }
 
     fn insert(&mut self, key: &[u8], val: &[u8]) -> Result<Option<()>, ()> {
-        let (index, hash) = Self::find::<BM::OlcEH>(OPtr::from_mut(self), key);
+        let (index, hash) = Self::find::<BM::OlcEH, _>(OPtr::locked(self), key);
         let count = self.common.count as usize;
         let is_new = index.is_none();
         let pos = index.unwrap_or(count);
@@ -283,7 +301,7 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeStatic<'bm, BM> for HashLeaf
 }
 
 impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeDynamic<'bm, BM> for HashLeaf {
-    fn split(&mut self, bm: BM, parent: &mut dyn NodeDynamic<'bm, BM>, _key: &[u8]) -> Result<(), ()> {
+    fn split(&mut self, bm: BM, parent: &mut dyn NodeDynamic<'bm, BM>, _key: &[u8]) -> Result<(), SplitError> {
 
         if self.sorted <= self.common.count / 8 as u16 {
             self.sort();
@@ -353,16 +371,34 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeDynamic<'bm, BM> for HashLeaf
         self.copy_records(&mut tmp, 0..left_count, 0);
         right.copy_records(&mut tmp, 0..right_count, left_count);
         tmp.sorted = if self.common.count == self.sorted { self.sorted + right.sorted } else { self.sorted };
+        NodeStatic::<BM>::set_scan_counter(&mut tmp, &self.common.scan_counter);
         tmp.validate();
         *self = tmp;
     }
 
+    fn can_merge(&self, right: &Page) -> bool {
+        if right.common.tag != node_tag::HASH_LEAF {
+            return false;
+        }
+        let right = page_cast::<Page, Self>(right);
+        let upper = right.upper_fence_combined();
+        let prefix_len = common_prefix(self.lower_fence(), upper);
+        let count = self.common.count as usize + right.common.count as usize;
+        let records = self.records_size_with_prefix(prefix_len) + right.records_size_with_prefix(prefix_len);
+        let fences = self.lower_fence().len() + upper.len() - prefix_len;
+        Self::heap_start_min(count) + records + fences <= size_of::<Self>()
+    }
+
+    fn is_underfull(&self) -> bool {
+        Self::heap_start_min(self.common.count as usize) + self.heap_used() < UNDERFULL_SIZE
+    }
+
     fn validate(&self) {
         self.validate()
     }
 
     fn leaf_remove(&mut self, key: &[u8]) -> Option<()> {
-        let (Some(index), _hash) = Self::find::<BM::OlcEH>(OPtr::from_mut(self), key) else {
+        let (Some(index), _hash) = Self::find::<BM::OlcEH, _>(OPtr::locked(self), key) else {
             return None;
         };
         self.heap_free(index);
@@ -383,6 +419,9 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeDynamic<'bm, BM> for HashLeaf
             }
         }
         self.common.count -= 1;
+        if index < self.sorted as usize {
+            self.sorted -= 1;
+        }
         HeapNode::validate(self);
         Some(())
     }
@@ -390,43 +429,24 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeDynamic<'bm, BM> for HashLeaf
     fn scan_with_callback(
         &self,
         buffer: &mut [MaybeUninit<u8>; 512],
-        start: &[u8],
+        range: &ScanRange,
         callback: &mut dyn FnMut(&[u8], &[u8]) -> bool
     ) -> bool {
-
-        let mut lf : usize = 0;
-
         if self.sorted != self.common.count {
             panic!("While not inherent, the sort function should always immediately be called after sorting for hash_leaf")
         }
 
-        if start != self.lower_fence() {
-            let (index, _hash) = Self::find::<BM::OlcEH>(unsafe { OPtr::from_ref(self) }, start);
-            lf = index.unwrap_or(0);
-        }
-
-
         let prefix = self.prefix();
         let prefix_len = prefix.len();
         prefix.write_to_uninit(&mut buffer[..prefix_len]);
-        for i in lf..self.common.count as usize {
-            let val = self.heap_val(i as usize);
-
-
-            let suffix = self.heap_key(i as usize);
-
+        let count = self.common.count as usize;
+        let seek = |key: &[u8]| partition_point(count, |i| self.heap_key(i) < &key[prefix_len..]);
+        range.scan_node(self, buffer, count, seek, |i, buffer| {
+            let suffix = self.heap_key(i);
             let total_len = prefix_len + suffix.len();
             suffix.write_to_uninit(&mut buffer[prefix_len..total_len]);
-            let full_key : &mut [u8] = unsafe {
-                std::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, total_len)
-            };
-
-            if callback(&full_key, val) {
-                return true;
-            }
-        }
-
-        false
+            Some((total_len, self.heap_val(i)))
+        }, callback)
     }
 
     fn get_node_tag(&self) -> u8 {
@@ -493,8 +513,14 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeDynamic<'bm, BM> for HashLeaf
 
 
 
-                let mut min_suffix = u32::MAX;
-                let mut max_suffix = 0;
+                // keys are stored at their offset from the reference the dense leaf derives from its lower fence
+                let full_len = self.common.prefix_len as usize + key_len;
+                let Some(reference) = FullyDenseLeaf::reference_for(self.lower_fence(), full_len) else {
+                    return Err(Capacity);
+                };
+                let capacity = FullyDenseLeaf::get_capacity_fdl(self.lower_fence().len(),
+                                                                self.upper_fence_tail().len(),
+                                                                first_val.len());
 
                 for i in 0..count {
                     let suffix = self.heap_key(i);
@@ -504,25 +530,10 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeDynamic<'bm, BM> for HashLeaf
                     full_key.extend_from_slice(self.prefix());
                     full_key.extend_from_slice(suffix);
 
-                    let full_len = full_key.len();
-                    let numeric_slice = &full_key[full_len.saturating_sub(4)..];
-
-                    let mut padded = [0u8; 4];
-                    padded[..full_len.min(4)].copy_from_slice(&numeric_slice);
-
-                    let index = u32::from_be_bytes(padded.try_into().unwrap());
-
-                    min_suffix = min_suffix.min(index);
-                    max_suffix = max_suffix.max(index);
-                }
-                let area = max_suffix - min_suffix + 1;
-
-                if area as usize >
-                    FullyDenseLeaf::get_capacity_fdl(self.lower_fence().len(),
-                                                     self.upper_fence_tail().len(),
-                                                     first_val.len()) {
-                    return Err(Capacity);
-
+                    let index = FullyDenseLeaf::extract_numeric_part(&full_key);
+                    if index < reference || (index - reference) as usize >= capacity {
+                        return Err(Capacity);
+                    }
                 }
 
                 Ok(())
@@ -221,6 +221,20 @@ pub trait HeapNode: ToFromPageExt + Debug {
         assert_eq!(calculated, tracked);
     }
 
+    /// Bytes taken up by the fences and the records, excluding freed space.
+    fn heap_used(&self) -> usize {
+        let info = self.heap_info();
+        size_of::<Self>() - info.bump as usize - info.freed as usize
+    }
+
+    /// Heap space the records would need in a node with a shorter prefix of `prefix_len`.
+    /// Keys grow by the prefix bytes they lose, this overestimates if part of a key is stored outside the heap.
+    fn records_size_with_prefix(&self, prefix_len: usize) -> usize {
+        let common = &self.as_page().common;
+        let fences = common.lower_fence_len as usize + common.upper_fence_len as usize;
+        self.heap_used() - fences + (common.prefix_len as usize - prefix_len) * common.count as usize
+    }
+
     fn heap_free(&mut self, index: usize) {
         self.heap_info_mut().freed += self.stored_record_size(index) as u16;
     }
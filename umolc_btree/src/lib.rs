@@ -3,6 +3,7 @@
 extern crate core;
 
 mod basic_node;
+mod cursor;
 mod fully_dense_leaf;
 mod hash_leaf;
 mod heap_node;
@@ -10,8 +11,11 @@ mod key_source;
 mod node;
 mod tree;
 mod util;
+mod wal;
 
+pub use cursor::Cursor;
 pub use node::Page;
-pub use tree::Tree;
+pub use tree::{InsertNowaitError, Tree};
+pub use wal::{SyncMode, Wal, WalConfig, WalRecord};
 const MAX_KEY_SIZE: usize = 512;
 const MAX_VAL_SIZE: usize = 512;
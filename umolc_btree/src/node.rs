@@ -10,9 +10,11 @@ use static_assertions::const_assert_eq;
 use std::{assert, fmt};
 use std::fmt::{Debug, Formatter};
 use std::mem::{swap, transmute, MaybeUninit};
+use std::ops::Bound;
 use std::sync::atomic::{AtomicU8, Ordering};
 use umolc::{
-    o_project, BufferManager, BufferManagerExt, BufferManagerGuard, ExclusiveGuard, OPtr, OlcErrorHandler, PageId,
+    o_project, BufferManager, BufferManagerExt, BufferManagerGuard, ExclusiveGuard, Locked, OPtr, OPtrMode,
+    OlcErrorHandler, OptimisticError, OutOfPages, PageId,
 };
 use crate::fully_dense_leaf::FullyDenseLeaf;
 
@@ -30,6 +32,9 @@ pub const PAGE_SIZE: usize = 1024;
 #[cfg(feature = "page_4k")]
 pub const PAGE_SIZE: usize = 4096;
 
+/// Nodes using fewer bytes are merged with a sibling when keys are removed.
+pub const UNDERFULL_SIZE: usize = PAGE_SIZE / 4;
+
 const NODE_TAIL_SIZE: usize = PAGE_SIZE - size_of::<CommonNodeHead>();
 
 #[derive(Debug, Zeroable)]
@@ -121,6 +126,120 @@ impl Debug for PromoteError {
 }
 impl std::error::Error for PromoteError {}
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SplitError {
+    /// the separator could not be inserted into the parent, the parent must be split first
+    ParentFull,
+    OutOfPages,
+}
+
+impl fmt::Display for SplitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            SplitError::ParentFull => "Parent has no space for separator.",
+            SplitError::OutOfPages => "No page available for new sibling.",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+impl std::error::Error for SplitError {}
+
+impl From<OutOfPages> for SplitError {
+    fn from(_: OutOfPages) -> Self {
+        SplitError::OutOfPages
+    }
+}
+
+/// The keys a scan visits and the order in which it visits them.
+#[derive(Debug, Clone, Copy)]
+pub struct ScanRange<'a> {
+    pub lower: Bound<&'a [u8]>,
+    pub upper: Bound<&'a [u8]>,
+    /// descending from the upper bound
+    pub reverse: bool,
+}
+
+impl ScanRange<'_> {
+    pub fn above_lower(&self, key: impl SourceSlice) -> bool {
+        match self.lower {
+            Bound::Included(b) => key.cmp(b).is_ge(),
+            Bound::Excluded(b) => key.cmp(b).is_gt(),
+            Bound::Unbounded => true,
+        }
+    }
+
+    pub fn below_upper(&self, key: impl SourceSlice) -> bool {
+        match self.upper {
+            Bound::Included(b) => key.cmp(b).is_le(),
+            Bound::Excluded(b) => key.cmp(b).is_lt(),
+            Bound::Unbounded => true,
+        }
+    }
+
+    /// Calls `callback` for the entries `0..count` of a sorted node that lie in the range, in scan order.
+    /// `seek` returns the index of the first entry whose key is not less than a key within the fences of `node`,
+    /// it positions the scan at the bound it starts from.
+    /// `entry` writes the full key of an entry to `buffer` and returns its length along with the value,
+    /// or `None` for unused slots.
+    /// Keys are only compared to the bounds of the range that lie within the fences of `node`.
+    /// Returns true if `callback` did or the scan moved past the end of the range.
+    pub fn scan_node<'n>(
+        &self,
+        node: &impl ToFromPageExt,
+        buffer: &mut [MaybeUninit<u8>; 512],
+        count: usize,
+        seek: impl FnOnce(&[u8]) -> usize,
+        mut entry: impl FnMut(usize, &mut [MaybeUninit<u8>; 512]) -> Option<(usize, &'n [u8])>,
+        callback: &mut dyn FnMut(&[u8], &[u8]) -> bool,
+    ) -> bool {
+        let upper_fence = node.upper_fence_combined();
+        // an empty upper fence is unbounded
+        let lower_open = self.above_lower(node.lower_fence());
+        let upper_open =
+            matches!(self.upper, Bound::Unbounded) || upper_fence.len() > 0 && self.below_upper(upper_fence);
+        let (mut started, open_end) = if self.reverse { (upper_open, lower_open) } else { (lower_open, upper_open) };
+        // keys outside the fences may not share the prefix of the node, so the scan starts at the end for them
+        let within = |b: &[u8]| {
+            SourceSlice::cmp(node.lower_fence(), b).is_lt() && (upper_fence.len() == 0 || upper_fence.cmp(b).is_ge())
+        };
+        let start = match if self.reverse { self.upper } else { self.lower } {
+            Bound::Included(b) | Bound::Excluded(b) if !started && within(b) => Some(seek(b)),
+            _ => None,
+        };
+        // the entry at the start index may equal the upper bound of a reverse scan
+        let (first, steps) = match (self.reverse, start) {
+            (false, Some(i)) => (i, count.saturating_sub(i)),
+            (true, Some(i)) => (0, count.min(i + 1)),
+            (_, None) => (0, count),
+        };
+        for n in 0..steps {
+            let i = if self.reverse { steps - 1 - n } else { first + n };
+            let Some((len, val)) = entry(i, buffer) else {
+                continue;
+            };
+            let key = unsafe { std::slice::from_raw_parts(buffer.as_ptr() as *const u8, len) };
+            if !started {
+                let before = if self.reverse { !self.below_upper(key) } else { !self.above_lower(key) };
+                if before {
+                    continue;
+                }
+                started = true;
+            }
+            if !open_end {
+                let after = if self.reverse { !self.above_lower(key) } else { !self.below_upper(key) };
+                if after {
+                    return true;
+                }
+            }
+            if callback(key, val) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
 pub fn page_cast<A: ToFromPage, B: ToFromPage>(a: &A) -> &B {
     unsafe { transmute::<&A, &B>(a) }
 }
@@ -246,8 +365,18 @@ pub trait NodeStatic<'bm, BM: BufferManager<'bm, Page = Page>>: NodeDynamic<'bm,
     /// keys are prefix truncated
     fn iter_children(&self) -> impl Iterator<Item = (Self::TruncatedKey<'_>, PageId)>;
 
-    fn lookup_leaf<'a>(this: OPtr<'a, Self, BM::OlcEH>, key: &[u8]) -> Option<OPtr<'a, [u8], BM::OlcEH>>;
-    fn lookup_inner(this: OPtr<'_, Self, BM::OlcEH>, key: &[u8], high_on_equal: bool) -> PageId;
+    /// Inconsistent data read from a page that is being modified is reported as `Err` without failing through
+    /// [BufferManager::OlcEH], so lookups need no unwinding.
+    #[allow(clippy::type_complexity)]
+    fn lookup_leaf<'a, M: OPtrMode>(
+        this: OPtr<'a, Self, BM::OlcEH, M>,
+        key: &[u8],
+    ) -> Result<Option<OPtr<'a, [u8], BM::OlcEH, M>>, OptimisticError>;
+    fn lookup_inner<M: OPtrMode>(
+        this: OPtr<'_, Self, BM::OlcEH, M>,
+        key: &[u8],
+        high_on_equal: bool,
+    ) -> Result<PageId, OptimisticError>;
 
     fn to_debug_kv(&self) -> (Vec<Vec<u8>>, Vec<Vec<u8>>);
 
@@ -257,14 +386,21 @@ pub trait NodeStatic<'bm, BM: BufferManager<'bm, Page = Page>>: NodeDynamic<'bm,
 }
 
 pub trait NodeDynamic<'bm, BM: BufferManager<'bm, Page = Page>>: ToFromPage + NodeDynamicAuto<'bm, BM> + Debug {
-    /// fails iff parent_insert fails.
+    /// fails iff allocating the new sibling or parent_insert fails, in which case the node is left unchanged.
     /// if node is near empty, no split is performed and parent_insert is not called.
-    fn split(&mut self, bm: BM, parent: &mut dyn NodeDynamic<'bm, BM>, key: &[u8]) -> Result<(), ()>;
+    fn split(&mut self, bm: BM, parent: &mut dyn NodeDynamic<'bm, BM>, key: &[u8]) -> Result<(), SplitError>;
     fn merge(&mut self, right: &mut Page);
+    /// Whether [Self::merge] with `right`, the upper sibling, fits into a single page.
+    /// False if `right` is a different kind of node.
+    fn can_merge(&self, right: &Page) -> bool;
+    /// Whether the node takes up less than [UNDERFULL_SIZE] bytes.
+    fn is_underfull(&self) -> bool;
     fn validate(&self);
     fn leaf_remove(&mut self, k: &[u8]) -> Option<()>;
 
-    fn scan_with_callback(&self, buffer: &mut [MaybeUninit<u8>; 512], start : &[u8], callback: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> bool;
+    /// Calls `callback` for the keys in `range` until it returns true, returns true if it did or the scan moved past
+    /// the end of the range.
+    fn scan_with_callback(&self, buffer: &mut [MaybeUninit<u8>; 512], range: &ScanRange, callback: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> bool;
 
     fn get_node_tag(&self) -> u8;
 
@@ -280,7 +416,7 @@ pub trait NodeDynamic<'bm, BM: BufferManager<'bm, Page = Page>>: ToFromPage + No
 }
 
 pub trait NodeDynamicAuto<'bm, BM: BufferManager<'bm, Page = Page>> {
-    fn free_children(&mut self, bm: BM);
+    fn children(&self) -> Vec<PageId>;
     fn validate_inter_node_fences<'b>(
         &self,
         bm: BM,
@@ -297,17 +433,17 @@ pub trait NodeDynamicAuto<'bm, BM: BufferManager<'bm, Page = Page>> {
 
     #[allow(clippy::result_unit_err)]
     fn insert_leaf(&mut self, key: &[u8], val: &[u8]) -> Result<Option<()>, ()>;
+
+    /// The value of `key` in this leaf, which may be overwritten in place.
+    fn leaf_value_mut(&mut self, key: &[u8]) -> Option<&mut [u8]>;
 }
 
 impl<'bm, BM: BufferManager<'bm, Page = Page>, N: NodeStatic<'bm, BM>> NodeDynamicAuto<'bm, BM> for N {
-    fn free_children(&mut self, bm: BM) {
+    fn children(&self) -> Vec<PageId> {
         if !Self::IS_INNER {
-            return;
-        }
-        for (_key, child) in self.iter_children() {
-            let mut child = bm.lock_exclusive(child);
-            child.as_dyn_node_mut().free_children(bm);
+            return Vec::new();
         }
+        self.iter_children().map(|(_key, child)| child).collect()
     }
 
     fn to_debug(&self) -> DebugNode {
@@ -379,6 +515,13 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>, N: NodeStatic<'bm, BM>> NodeDynam
     fn insert_leaf(&mut self, key: &[u8], val: &[u8]) -> Result<Option<()>, ()> {
         self.insert(key, val)
     }
+
+    fn leaf_value_mut(&mut self, key: &[u8]) -> Option<&mut [u8]> {
+        let val = BM::OlcEH::optmistic_fail_check(Self::lookup_leaf(OPtr::<_, _, Locked>::locked(&*self), key))?;
+        let offset = val.to_raw() as *const u8 as usize - self as *const Self as usize;
+        let len = val.len();
+        unsafe { Some(std::slice::from_raw_parts_mut((self as *mut Self as *mut u8).add(offset), len)) }
+    }
 }
 
 pub const PAGE_ID_LEN: usize = 5;
@@ -395,7 +538,7 @@ pub fn page_id_from_bytes(x: &[u8; PAGE_ID_LEN]) -> PageId {
     PageId { x: u64::from_ne_bytes(b) }
 }
 
-pub fn page_id_from_olc_bytes<O: OlcErrorHandler>(x: OPtr<[u8; PAGE_ID_LEN], O>) -> PageId {
+pub fn page_id_from_olc_bytes<O: OlcErrorHandler, M: OPtrMode>(x: OPtr<[u8; PAGE_ID_LEN], O, M>) -> PageId {
     let mut b = [0; 8];
     unsafe {
         std::ptr::copy(x.to_raw() as *const u8, b.as_mut_ptr(), PAGE_ID_LEN);
@@ -444,14 +587,14 @@ pub fn insert_upper_sibling<'bm, BM: BufferManager<'bm, Page = Page>>(
     parent: &mut dyn NodeDynamic<'bm, BM>,
     bm: BM,
     separator: impl SourceSlice,
-) -> Result<BM::GuardX, ()> {
-    let new_guard = bm.alloc();
+) -> Result<BM::GuardX, SplitError> {
+    let new_guard = bm.try_alloc()?;
     separator.to_ref_buffer::<MAX_KEY_SIZE, _>(|sep| {
         if let Ok(()) = parent.insert_inner(sep, new_guard.page_id()) {
             Ok(new_guard)
         } else {
             new_guard.dealloc();
-            Err(())
+            Err(SplitError::ParentFull)
         }
     })
 }
@@ -507,11 +650,11 @@ impl Page {
     }
 }
 
-pub fn o_ptr_lookup_inner<'bm, BM: BufferManager<'bm, Page = Page>>(
-    this: OPtr<'_, BM::Page, BM::OlcEH>,
+pub fn o_ptr_lookup_inner<'bm, BM: BufferManager<'bm, Page = Page>, M: OPtrMode>(
+    this: OPtr<'_, BM::Page, BM::OlcEH, M>,
     key: &[u8],
     high_on_equal: bool,
-) -> PageId {
+) -> Result<PageId, OptimisticError> {
     let tag = o_project!(this.common.tag).r();
     macro_rules! impl_case {
             ($($t:ty),*) => {
@@ -521,13 +664,14 @@ pub fn o_ptr_lookup_inner<'bm, BM: BufferManager<'bm, Page = Page>>(
             };
         }
     invoke_all_nodes!(impl_case);
-    BM::OlcEH::optimistic_fail()
+    Err(OptimisticError::new())
 }
 
-pub fn o_ptr_lookup_leaf<'a, 'bm, BM: BufferManager<'bm, Page = Page>>(
-    this: OPtr<'a, BM::Page, BM::OlcEH>,
+#[allow(clippy::type_complexity)]
+pub fn o_ptr_lookup_leaf<'a, 'bm, BM: BufferManager<'bm, Page = Page>, M: OPtrMode>(
+    this: OPtr<'a, BM::Page, BM::OlcEH, M>,
     key: &[u8],
-) -> Option<OPtr<'a, [u8], BM::OlcEH>> {
+) -> Result<Option<OPtr<'a, [u8], BM::OlcEH, M>>, OptimisticError> {
     let tag = o_project!(this.common.tag).r();
     macro_rules! impl_case {
             ($($t:ty),*) => {
@@ -537,20 +681,22 @@ pub fn o_ptr_lookup_leaf<'a, 'bm, BM: BufferManager<'bm, Page = Page>>(
             };
         }
     invoke_all_nodes!(impl_case);
-    BM::OlcEH::optimistic_fail()
+    Err(OptimisticError::new())
 }
 
-pub fn o_ptr_is_inner<'bm, BM: BufferManager<'bm, Page = Page>>(this: OPtr<'_, BM::Page, BM::OlcEH>) -> bool {
+pub fn o_ptr_is_inner<'bm, BM: BufferManager<'bm, Page = Page>, M: OPtrMode>(
+    this: OPtr<'_, BM::Page, BM::OlcEH, M>,
+) -> Result<bool, OptimisticError> {
     let tag = o_project!(this.common.tag).r();
     macro_rules! impl_case {
             ($($t:ty),*) => {
                 $(if tag==<$t as NodeStatic<'bm,BM>>::TAG {
-                    return <$t as NodeStatic<'bm,BM>>::IS_INNER
+                    return Ok(<$t as NodeStatic<'bm,BM>>::IS_INNER)
                 })*
             };
         }
     invoke_all_nodes!(impl_case);
-    BM::OlcEH::optimistic_fail()
+    Err(OptimisticError::new())
 }
 
 /// returns the number of keys in the low node and the separator (including prefix)
@@ -579,6 +725,20 @@ pub fn find_separator<'a, 'bm, BM: BufferManager<'bm, Page = Page>, N: NodeStati
     }
 }
 
+/// returns the number of indices in `0..count` for which `pred` holds, it must hold for a prefix of them
+pub fn partition_point(count: usize, mut pred: impl FnMut(usize) -> bool) -> usize {
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if pred(mid) {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
 
 pub trait OPtrScanCounterExt<O: OlcErrorHandler> {
     fn increase_scan_counter(self);
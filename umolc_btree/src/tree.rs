@@ -1,25 +1,156 @@
 use crate::basic_node::{BasicInner, BasicLeaf};
+use crate::cursor::Cursor;
 use crate::hash_leaf::HashLeaf;
 use crate::key_source::SourceSlice;
-use crate::node::{node_tag, o_ptr_is_inner, o_ptr_lookup_inner, o_ptr_lookup_leaf, page_cast, page_cast_mut, page_id_to_bytes, CommonNodeHead, NodeDynamic, NodeDynamicAuto, NodeStatic, OPtrScanCounterExt, Page, PromoteError, ToFromPageExt, PAGE_SIZE};
+use crate::node::{node_tag, o_ptr_is_inner, o_ptr_lookup_inner, o_ptr_lookup_leaf, page_cast, page_cast_mut, page_id_to_bytes, CommonNodeHead, NodeDynamic, NodeStatic, OPtrScanCounterExt, Page, PromoteError, ScanRange, SplitError, ToFromPageExt, PAGE_SIZE};
+use crate::wal::{restore_page, Wal, WalRecord};
 use crate::{define_node, MAX_KEY_SIZE, MAX_VAL_SIZE};
 use std::fmt::{Debug, Formatter};
 use std::marker::PhantomData;
 use std::mem::{size_of, MaybeUninit};
+use std::ops::Bound;
 use std::sync::atomic::{AtomicU8, Ordering};
 use bstr::BStr;
 use umolc::{
-    o_project, BufferManageGuardUpgrade, BufferManager, BufferManagerExt, BufferManagerGuard, ExclusiveGuard, OPtr,
-    OlcErrorHandler, OptimisticGuard, PageId,
+    o_project, BufferManageGuardDowngrade, BufferManageGuardUpgrade, BufferManager, BufferManagerExt, BufferManagerGuard,
+    ExclusiveGuard, OPtr, OPtrMode, OlcErrorHandler, OlcVersion, OptimisticError, OptimisticGuard, OutOfPages,
+    PageId, RestartStats, WouldBlock,
 };
 
 pub struct Tree<'bm, BM: BufferManager<'bm, Page = Page>> {
     meta: PageId,
     bm: BM,
+    wal: Option<Wal>,
+    restart_limit: Option<u32>,
+    restart_stats: RestartStats,
     _p: PhantomData<&'bm BM>,
 }
 
+/// Why an attempt of a non-blocking operation failed.
+enum NowaitError {
+    Restart,
+    WouldBlock,
+    OutOfPages,
+}
+
+/// Why [Tree::try_insert_nowait] failed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum InsertNowaitError {
+    /// a page on the path was locked by another thread, or the restart limit was exceeded
+    WouldBlock,
+    /// no page was available to split a full node
+    OutOfPages,
+}
+
+impl std::fmt::Display for InsertNowaitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InsertNowaitError::WouldBlock => std::fmt::Display::fmt(&WouldBlock, f),
+            InsertNowaitError::OutOfPages => std::fmt::Display::fmt(&OutOfPages, f),
+        }
+    }
+}
+
+impl std::error::Error for InsertNowaitError {}
+
+impl From<OptimisticError> for NowaitError {
+    fn from(_: OptimisticError) -> Self {
+        NowaitError::Restart
+    }
+}
+
+impl From<WouldBlock> for NowaitError {
+    fn from(_: WouldBlock) -> Self {
+        NowaitError::WouldBlock
+    }
+}
+
+impl From<OutOfPages> for NowaitError {
+    fn from(_: OutOfPages) -> Self {
+        NowaitError::OutOfPages
+    }
+}
+
+/// Computes the new value of a key from its current value, see [Tree::update].
+type UpdateFn<'a> = dyn FnMut(Option<&[u8]>) -> Option<Vec<u8>> + 'a;
+
+/// No key is greater, descending with it finds the last leaf.
+static MAX_KEY: [u8; MAX_KEY_SIZE] = [u8::MAX; MAX_KEY_SIZE];
+
+/// The smallest key greater than all keys starting with `prefix`, `None` if there is no such key.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let last = prefix.iter().rposition(|&b| b != u8::MAX)?;
+    let mut end = prefix[..=last].to_vec();
+    end[last] += 1;
+    Some(end)
+}
+
+/// The key the scan of `range` starts from, and whether a key equal to a separator belongs to the subtree right of it.
+fn scan_start<'a>(range: &ScanRange<'a>) -> (&'a [u8], bool) {
+    // leaves hold keys from their lower fence up to, but excluding their upper fence
+    match (range.reverse, range.lower, range.upper) {
+        (false, Bound::Included(k) | Bound::Excluded(k), _) => (k, true),
+        (false, Bound::Unbounded, _) => (&[][..], true),
+        (true, _, Bound::Included(k)) => (k, true),
+        (true, _, Bound::Excluded(k)) => (k, false),
+        (true, _, Bound::Unbounded) => (&MAX_KEY[..], true),
+    }
+}
 
+/// The most entries a scan copies out of a leaf at once, so callbacks that stop early do not wait for the whole leaf.
+const SCAN_BATCH_LEN: usize = 64;
+
+/// Where a scan continues after a batch.
+enum Resume {
+    /// at the fence of the next leaf
+    Fence(Vec<u8>),
+    /// after the last key of a full batch, within the same leaf
+    After(Vec<u8>),
+}
+
+/// Entries copied out of a leaf, so scan callbacks run without holding a lock.
+#[derive(Default)]
+pub(crate) struct ScanBatch {
+    bytes: Vec<u8>,
+    /// end offsets of each key and value in `bytes`
+    ends: Vec<(usize, usize)>,
+    /// the leaf the entries were copied from and its version at the time
+    leaf: Option<(PageId, OlcVersion)>,
+}
+
+impl ScanBatch {
+    fn clear(&mut self) {
+        self.bytes.clear();
+        self.ends.clear();
+        self.leaf = None;
+    }
+
+    fn push(&mut self, key: &[u8], val: &[u8]) {
+        self.bytes.extend_from_slice(key);
+        let key_end = self.bytes.len();
+        self.bytes.extend_from_slice(val);
+        self.ends.push((key_end, self.bytes.len()));
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    /// Returns the key and value of the entry at `index`.
+    pub(crate) fn get(&self, index: usize) -> (&[u8], &[u8]) {
+        let start = index.checked_sub(1).map_or(0, |i| self.ends[i].1);
+        let (key_end, val_end) = self.ends[index];
+        (&self.bytes[start..key_end], &self.bytes[key_end..val_end])
+    }
+
+    pub(crate) fn leaf(&self) -> Option<(PageId, OlcVersion)> {
+        self.leaf
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        (0..self.len()).map(|i| self.get(i))
+    }
+}
 
 impl<'bm, BM: BufferManager<'bm, Page = Page>> Tree<'bm, BM> {
     pub fn new(bm: BM) -> Self {
@@ -33,7 +164,155 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> Tree<'bm, BM> {
         }
         NodeStatic::<BM>::init(root_guard.cast_mut::<BasicLeaf>(), &[][..], &[][..], None);
 
-        Tree { meta: meta_guard.page_id(), bm, _p: PhantomData }
+        Tree::with_meta(bm, meta_guard.page_id())
+    }
+
+    /// Attaches to a tree previously created with [Tree::new] on the same buffer manager.
+    /// Only one handle to a tree may be dropped, all others must be detached using [Tree::close].
+    pub fn open(bm: BM, meta: PageId) -> Self {
+        let meta_guard = bm.lock_shared(meta);
+        assert_eq!(meta_guard.common.tag, node_tag::METADATA_MARKER, "page {meta:?} is not a tree metadata page");
+        drop(meta_guard);
+        Tree::with_meta(bm, meta)
+    }
+
+    fn with_meta(bm: BM, meta: PageId) -> Self {
+        Tree { meta, bm, wal: None, restart_limit: None, restart_stats: RestartStats::new(), _p: PhantomData }
+    }
+
+    /// Identifies the tree within its buffer manager, see [Tree::open].
+    pub fn meta_page_id(&self) -> PageId {
+        self.meta
+    }
+
+    /// Detaches from the tree without freeing its pages and returns its metadata page id.
+    pub fn close(mut self) -> PageId {
+        drop(self.wal.take());
+        let meta = self.meta;
+        std::mem::forget(self);
+        meta
+    }
+
+    /// Logs all subsequent modifications to `wal`, which must have been created or truncated while the tree was durable,
+    /// see [Wal].
+    /// Inserts and removes return once their log record is committed according to the [WalConfig] of the log.
+    pub fn attach_wal(&mut self, wal: Wal) {
+        self.wal = Some(wal);
+    }
+
+    pub fn detach_wal(&mut self) -> Option<Wal> {
+        self.wal.take()
+    }
+
+    pub fn wal(&self) -> Option<&Wal> {
+        self.wal.as_ref()
+    }
+
+    /// Point operations and batches of scans that fail optimistic validation more than `limit` times in a row switch
+    /// to pessimistic locking, bounding the work they waste on hot pages.
+    /// By default there is no limit.
+    pub fn set_restart_limit(&mut self, limit: Option<u32>) {
+        self.restart_limit = limit;
+    }
+
+    pub fn restart_limit(&self) -> Option<u32> {
+        self.restart_limit
+    }
+
+    /// Restarts of point operations and batches of scans on this tree.
+    pub fn restart_stats(&self) -> &RestartStats {
+        &self.restart_stats
+    }
+
+    /// Runs an optimistic operation, returns `None` if it exceeded the restart limit.
+    fn optimistic<R>(&self, f: impl FnMut() -> R) -> Option<R> {
+        BM::repeat_limited(&self.restart_stats, self.restart_limit, f)
+    }
+
+    /// Like [Self::optimistic], for operations that return optimistic errors instead of unwinding.
+    fn optimistic_result<R>(&self, f: impl FnMut() -> Result<R, OptimisticError>) -> Option<R> {
+        BM::repeat_limited_result(&self.restart_stats, self.restart_limit, f)
+    }
+
+    fn log(&self, record: WalRecord) {
+        if let Some(wal) = &self.wal {
+            wal.append(record);
+        }
+    }
+
+    /// Logs the contents of `nodes` before they are modified or freed, see [Wal::log_before_write].
+    fn log_before_write(&self, nodes: &[&BM::GuardX]) {
+        if let Some(wal) = &self.wal {
+            for node in nodes {
+                wal.log_before_write(node.page_id(), node);
+            }
+        }
+    }
+
+    /// Logs the contents of pages changed by a structural modification, which must still be locked.
+    fn log_pages(&self, pages: &[(PageId, &Page)]) {
+        if let Some(wal) = &self.wal {
+            wal.log_pages(pages);
+        }
+    }
+
+    /// Logs the nodes changed by splitting `node`, whose new upper sibling follows it in `parent`.
+    fn log_split(&self, node: &BM::GuardX, parent: &BM::GuardX) {
+        if self.wal.is_none() {
+            return;
+        }
+        let children = parent.as_dyn_node::<BM>().children();
+        let i = children.iter().position(|&c| c == node.page_id()).unwrap();
+        // the sibling is only reachable through the locked parent, so waiting for it cannot deadlock
+        let sibling = self.bm.lock_shared(children[i + 1]);
+        self.log_pages(&[(node.page_id(), node), (sibling.page_id(), &sibling), (parent.page_id(), parent)]);
+    }
+
+    /// Repeats a logged modification on the page it names, see [Wal::replay].
+    /// Returns false if the modification does not apply to the page.
+    pub(crate) fn redo(&self, record: WalRecord) -> bool {
+        let redo_leaf = |pid: PageId, f: &mut dyn FnMut(&mut dyn NodeDynamic<'bm, BM>) -> bool| {
+            let mut node = self.bm.lock_exclusive(pid);
+            let is_leaf =
+                matches!(node.common.tag, node_tag::BASIC_LEAF | node_tag::HASH_LEAF | node_tag::FULLY_DENSE_LEAF);
+            is_leaf && f(node.as_dyn_node_mut::<BM>())
+        };
+        match record {
+            WalRecord::Insert { page, key, val } => redo_leaf(page, &mut |leaf| leaf.insert_leaf(key, val).is_ok()),
+            WalRecord::Overwrite { page, key, val } => redo_leaf(page, &mut |leaf| match leaf.leaf_value_mut(key) {
+                Some(old) if old.len() == val.len() => {
+                    old.copy_from_slice(val);
+                    true
+                }
+                _ => false,
+            }),
+            WalRecord::Remove { page, key } => redo_leaf(page, &mut |leaf| leaf.leaf_remove(key).is_some()),
+            WalRecord::Pages { images } => {
+                for (pid, image) in images {
+                    restore_page(&mut self.bm.lock_exclusive(pid), image);
+                }
+                true
+            }
+        }
+    }
+
+    /// Ids of all pages of the tree, including its metadata page.
+    /// The tree must not be modified concurrently.
+    pub fn page_ids(&self) -> Vec<PageId> {
+        let mut ids = vec![self.meta];
+        let mut i = 0;
+        while let Some(&pid) = ids.get(i) {
+            let children = self.bm.lock_shared(pid).as_dyn_node::<BM>().children();
+            ids.extend(children);
+            i += 1;
+        }
+        ids
+    }
+
+    fn commit_log(&self) {
+        if let Some(wal) = &self.wal {
+            wal.commit(wal.appended_lsn());
+        }
     }
 
     fn validate_fences(&self) {
@@ -50,102 +329,208 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> Tree<'bm, BM> {
 
     pub fn remove(&self, k: &[u8]) -> Option<()> {
         let mut removed = false;
-        BM::repeat(|| {
-            self.try_remove(k, &mut removed);
-        });
+        let underfull = match self.optimistic(|| self.try_remove(k, &mut removed)) {
+            Some(underfull) => underfull,
+            None => {
+                let underfull = self.remove_pessimistic(k);
+                removed = underfull.is_some();
+                underfull.flatten()
+            }
+        };
+        if let Some(node) = underfull {
+            self.merge_underfull(node, k);
+        }
+        self.commit_log();
+        self.validate_fences();
         if removed {
             Some(())
         } else {
             None
         }
     }
-    pub fn scan<F>(&self, lower_bound: &[u8], mut callback: F)
+    pub fn scan<F>(&self, lower_bound: &[u8], callback: F)
     where
             for<'a> F: FnMut(&[u8], &'a [u8]) -> bool {
-        let mut buffer: [MaybeUninit<u8>; 512] = unsafe { MaybeUninit::uninit().assume_init() };
-;
-        let mut key = lower_bound;
-        loop {
-
-            let (len, breakof) = BM::repeat(|| self.try_scan(key, &mut buffer, &mut callback));
-            if breakof || len == 0 {
-                return;
-            }
-            key = unsafe {
-                std::slice::from_raw_parts_mut(
-                    buffer.as_mut_ptr() as *mut u8,
-                    len
-                )
-            };
-        }
-
+        self.scan_range(Bound::Included(lower_bound), Bound::Unbounded, callback)
     }
 
-    fn try_scan<F>(&self, key: &[u8], buffer: &mut [MaybeUninit<u8>; 512], mut callback: F) -> (usize, bool)
+    /// Calls `callback` for the keys within the bounds in ascending order, until it returns true.
+    pub fn scan_range<F>(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>, callback: F)
     where
             for<'a> F: FnMut(&[u8], &'a [u8]) -> bool {
+        self.scan_in(ScanRange { lower, upper, reverse: false }, callback)
+    }
 
-        let [parent, node] = self.descend(key, None);
-
-        parent.release_unchecked();
-
-
-        let mut node = self.increase_scan_counter(node);
-        let o = node.o_ptr();
-        let tag = o_project!(o.common.tag).r();
+    /// Like [Tree::scan_range], but in descending order, starting from the upper bound.
+    pub fn scan_range_rev<F>(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>, callback: F)
+    where
+            for<'a> F: FnMut(&[u8], &'a [u8]) -> bool {
+        self.scan_in(ScanRange { lower, upper, reverse: true }, callback)
+    }
 
-        if tag == node_tag::HASH_LEAF {
-            let mut node: BM::GuardX = node.upgrade();
-            node.cast_mut::<HashLeaf>().sort();
+    /// Calls `callback` for the keys starting with `prefix` in ascending order, until it returns true.
+    /// Leaves past the prefix are never visited.
+    pub fn scan_prefix<F>(&self, prefix: &[u8], callback: F)
+    where
+            for<'a> F: FnMut(&[u8], &'a [u8]) -> bool {
+        let end = prefix_end(prefix);
+        let upper = match &end {
+            Some(end) => Bound::Excluded(&end[..]),
+            None => Bound::Unbounded,
+        };
+        self.scan_range(Bound::Included(prefix), upper, callback)
+    }
 
-            // in theory we could demote the lock here, but for hash_leafs, being sorted is relevant that we just keep the lock on
-            let ret = node.as_dyn_node::<BM>().scan_with_callback(buffer, key, &mut callback);
+    fn scan_in<F>(&self, range: ScanRange, mut callback: F)
+    where
+            for<'a> F: FnMut(&[u8], &'a [u8]) -> bool {
+        // no lock is held while a batch is consumed, so the callback may access the tree
+        self.scan_batches(range, &mut ScanBatch::default(), |batch| batch.iter().any(|(key, val)| callback(key, val)));
+    }
 
-            if ret {
-                return (0, true);
+    /// Copies the entries of `range` into `batch` one batch at a time, until `consume` returns true for a batch.
+    pub(crate) fn scan_batches(&self, range: ScanRange, batch: &mut ScanBatch, mut consume: impl FnMut(&ScanBatch) -> bool) {
+        let mut buffer: [MaybeUninit<u8>; 512] = unsafe { MaybeUninit::uninit().assume_init() };
+        let mut resume: Option<Resume> = None;
+        loop {
+            let mut remaining = range;
+            match (&resume, range.reverse) {
+                (Some(Resume::Fence(fence)), false) => remaining.lower = Bound::Included(fence),
+                (Some(Resume::Fence(fence)), true) => remaining.upper = Bound::Excluded(fence),
+                (Some(Resume::After(key)), false) => remaining.lower = Bound::Excluded(key),
+                (Some(Resume::After(key)), true) => remaining.upper = Bound::Excluded(key),
+                (None, _) => {}
             }
-
-            let upper = node.upper_fence_combined();
-
-            let upper_len = upper.len();
-
-            upper.to_vec()
-                .write_to_uninit(&mut buffer[..upper_len]);
-
-
-            if upper_len == 0 {
-                return (0, true);
+            let leaf = batch.leaf.filter(|_| matches!(resume, Some(Resume::After(_))));
+            let next = match self.optimistic(|| self.try_scan(&remaining, leaf, &mut buffer, batch)) {
+                Some(next) => next,
+                None => self.scan_pessimistic(&remaining, &mut buffer, batch),
+            };
+            if consume(batch) {
+                return;
+            }
+            match next {
+                Some(next) => resume = Some(next),
+                None => return,
             }
-
-            (upper_len, false)
         }
+    }
 
-        else {
-
-            let mut node: BM::GuardX = node.upgrade();
+    /// Returns true if `leaf` still has `version`, so entries copied out of it at that version are current.
+    pub(crate) fn leaf_unchanged(&self, leaf: PageId, version: OlcVersion) -> bool {
+        match BM::GuardO::try_acquire_version(self.bm, leaf, version) {
+            Ok(Some(guard)) => {
+                guard.release_unchecked();
+                true
+            }
+            _ => false,
+        }
+    }
 
-            let ret = node.as_dyn_node::<BM>().scan_with_callback(buffer, key, &mut callback);
+    /// Copies up to [SCAN_BATCH_LEN] entries of `range` in the leaf holding its start into `batch`.
+    /// `leaf` is the leaf of the previous batch and its version, it is scanned without descending if it is unchanged.
+    /// Returns where the scan continues unless it is done.
+    fn try_scan(
+        &self,
+        range: &ScanRange,
+        leaf: Option<(PageId, OlcVersion)>,
+        buffer: &mut [MaybeUninit<u8>; 512],
+        batch: &mut ScanBatch,
+    ) -> Option<Resume> {
+        batch.clear();
+        let unchanged = leaf.and_then(|(pid, version)| BM::GuardS::try_acquire_version(self.bm, pid, version).ok()?);
+        let node = match unchanged {
+            Some(node) => node,
+            None => self.lock_scan_leaf(range),
+        };
+        self.copy_batch(node, range, buffer, batch)
+    }
 
-            if ret {
-                return (0, true);
-            }
+    /// Like [Self::try_scan], but locks nodes pessimistically.
+    fn scan_pessimistic(
+        &self,
+        range: &ScanRange,
+        buffer: &mut [MaybeUninit<u8>; 512],
+        batch: &mut ScanBatch,
+    ) -> Option<Resume> {
+        batch.clear();
+        let (key, high_on_equal) = scan_start(range);
+        // locked exclusively in case a hash leaf must be sorted, as a shared lock cannot be upgraded
+        let (_, _, mut node) = self.descend_pessimistic_with::<BM::GuardX>(key, high_on_equal, None);
+        if node.common.tag == node_tag::HASH_LEAF && node.cast::<HashLeaf>().sorted != node.common.count {
+            self.sort_hash_leaf(&mut node);
+        }
+        self.copy_batch(node.downgrade(), range, buffer, batch)
+    }
 
+    /// Copies up to [SCAN_BATCH_LEN] entries of `range` in the locked leaf `node` into `batch` and releases it.
+    fn copy_batch(
+        &self,
+        node: BM::GuardS,
+        range: &ScanRange,
+        buffer: &mut [MaybeUninit<u8>; 512],
+        batch: &mut ScanBatch,
+    ) -> Option<Resume> {
+        let done = node.as_dyn_node::<BM>().scan_with_callback(buffer, range, &mut |key, val| {
+            batch.push(key, val);
+            batch.len() == SCAN_BATCH_LEN
+        });
+        let next = if batch.len() == SCAN_BATCH_LEN {
+            Some(Resume::After(batch.get(SCAN_BATCH_LEN - 1).0.to_vec()))
+        } else if done {
+            None
+        } else if range.reverse {
+            // the previous leaf holds keys below the lower fence
+            let lower = node.lower_fence();
+            let past_end = match range.lower {
+                Bound::Included(b) | Bound::Excluded(b) => lower <= b,
+                Bound::Unbounded => false,
+            };
+            (!lower.is_empty() && !past_end).then(|| Resume::Fence(lower.to_vec()))
+        } else {
             let upper = node.upper_fence_combined();
+            (upper.len() != 0 && range.below_upper(upper)).then(|| Resume::Fence(upper.to_vec()))
+        };
+        batch.leaf = Some((node.page_id(), node.release()));
+        next
+    }
 
-            let upper_len = upper.len();
+    /// Locks the leaf holding the start of `range` for scanning it.
+    fn lock_scan_leaf(&self, range: &ScanRange) -> BM::GuardS {
+        let (key, high_on_equal) = scan_start(range);
+        let [parent, node] = BM::OlcEH::optmistic_fail_check(self.try_descend_with(key, high_on_equal, None, |pid| {
+            Ok::<_, OptimisticError>(self.bm.lock_optimistic(pid))
+        }));
 
-            upper.to_vec()
-                .write_to_uninit(&mut buffer[..upper_len]);
+        parent.release_unchecked();
 
 
-            if upper_len == 0 {
-                return (0, true);
-            }
+        let mut node = self.increase_scan_counter(node);
 
-            (upper_len, false)
+        let o = node.o_ptr();
+        let unsorted = o_project!(o.common.tag).r() == node_tag::HASH_LEAF && {
+            let leaf = o.cast::<HashLeaf>();
+            o_project!(leaf.sorted).r() != o_project!(o.common.count).r()
+        };
+        if unsorted {
+            // sorting persists, so later scans of the leaf only need a shared lock
+            let mut node: BM::GuardX = node.upgrade();
+            self.sort_hash_leaf(&mut node);
+            node.downgrade()
+        } else {
+            node.upgrade()
         }
+    }
 
+    fn sort_hash_leaf(&self, node: &mut BM::GuardX) {
+        self.log_before_write(&[node]);
+        node.cast_mut::<HashLeaf>().sort();
+        self.log_pages(&[(node.page_id(), node)]);
+    }
 
+    /// Returns an unpositioned [Cursor] over the tree.
+    pub fn cursor(&self) -> Cursor<'_, 'bm, BM> {
+        Cursor::new(self)
     }
 
     pub fn scan_node_types<F>(&self, lower_bound: &[u8], mut callback: F)
@@ -185,7 +570,8 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> Tree<'bm, BM> {
 
     }
 
-    fn try_remove(&self, k: &[u8], removed: &mut bool) {
+    /// Returns the leaf if it is underfull after removing `k`.
+    fn try_remove(&self, k: &[u8], removed: &mut bool) -> Option<PageId> {
         let [parent, node] = self.descend(k, None);
 
         let node = self.decrease_scan_counter(node);
@@ -193,63 +579,428 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> Tree<'bm, BM> {
         let mut node: BM::GuardX = node.upgrade();
 
 
-        if node.as_dyn_node_mut::<BM>().leaf_remove(k).is_some() {
-            *removed = true;
-        }
         parent.release_unchecked();
-        //TODO merge nodes
+        self.log_before_write(&[&node]);
+        node.as_dyn_node_mut::<BM>().leaf_remove(k)?;
+        self.log(WalRecord::Remove { page: node.page_id(), key: k });
+        *removed = true;
+        node.as_dyn_node::<BM>().is_underfull().then(|| node.page_id())
+    }
+
+    /// Merges `target`, an underfull node on the path to `k`, with a sibling and continues with its parent while that
+    /// is underfull afterwards.
+    /// Merging is best effort, it is skipped if a sibling is locked or the nodes do not fit into one page.
+    /// Like other writes, it falls back to pessimistic locking if optimistic locking fails too often or cannot be
+    /// restarted.
+    fn merge_underfull(&self, mut target: PageId, k: &[u8]) {
+        loop {
+            let next = match self.optimistic(|| self.try_merge(target, k)) {
+                Some(next) => next,
+                None => self.merge_pessimistic(target, k),
+            };
+            let Some(parent) = next else {
+                return;
+            };
+            target = parent;
+        }
+    }
+
+    /// Merges `target` with a sibling, or replaces the root by its only child if `target` is the root.
+    /// Returns the node to merge next.
+    fn try_merge(&self, target: PageId, k: &[u8]) -> Option<PageId> {
+        let [parent, node] = self.descend(k, Some(target));
+        let node_pid = node.page_id();
+        node.release_unchecked();
+        if node_pid != target {
+            // merged concurrently
+            parent.release_unchecked();
+            return None;
+        }
+        let mut parent: BM::GuardX = parent.upgrade();
+        self.merge_child(&mut parent, target)
+    }
+
+    /// Like [Self::try_merge], but descends with shared locks and locks the parent exclusively once it has not
+    /// changed since the descent.
+    fn merge_pessimistic(&self, target: PageId, k: &[u8]) -> Option<PageId> {
+        let (parent_pid, parent_version, node) = self.descend_pessimistic::<BM::GuardS>(k, Some(target));
+        let node_pid = node.page_id();
+        drop(node);
+        if node_pid != target {
+            // merged concurrently
+            return None;
+        }
+        let mut parent = BM::GuardX::acquire_wait_version(self.bm, parent_pid, parent_version)?;
+        self.merge_child(&mut parent, target)
+    }
+
+    /// Merges the child `target` of the exclusively locked `parent` with a sibling.
+    /// Returns the node to merge next.
+    fn merge_child(&self, parent: &mut BM::GuardX, target: PageId) -> Option<PageId> {
+        if parent.common.tag == node_tag::METADATA_MARKER {
+            return self.collapse_root(parent);
+        }
+        let children = parent.as_dyn_node::<BM>().children();
+        let i = children.iter().position(|&c| c == target).unwrap();
+        let (left, right) = match (i.checked_sub(1), children.get(i + 1)) {
+            (_, Some(&right)) => (target, right),
+            (Some(left), None) => (children[left], target),
+            (None, None) => return None,
+        };
+        // the parent is locked first, so waiting for a child could deadlock with writers that lock the child first
+        let Ok(mut left) = BM::GuardX::try_acquire(self.bm, left) else {
+            return None;
+        };
+        let Ok(right) = BM::GuardX::try_acquire(self.bm, right) else {
+            return None;
+        };
+        if !self.merge_locked_nodes(&mut left, right, parent) {
+            return None;
+        }
+        parent.as_dyn_node::<BM>().is_underfull().then(|| parent.page_id())
+    }
+
+    /// Merges `right` into its lower sibling `left` and frees its page, unless they do not fit into one page.
+    /// Both nodes are left unchanged if they are not merged.
+    fn merge_locked_nodes(&self, left: &mut BM::GuardX, mut right: BM::GuardX, parent: &mut BM::GuardX) -> bool {
+        // dense leaves cannot be merged, so they are converted to the kind of their sibling, or to basic leaves
+        let to = match (left.common.tag, right.common.tag) {
+            (node_tag::FULLY_DENSE_LEAF, node_tag::FULLY_DENSE_LEAF) => node_tag::BASIC_LEAF,
+            (node_tag::FULLY_DENSE_LEAF, tag) => tag,
+            (tag, _) => tag,
+        };
+        if left.common.tag == to && right.common.tag == to {
+            if !left.as_dyn_node::<BM>().can_merge(&right) {
+                return false;
+            }
+            self.log_before_write(&[left, &right, parent]);
+            left.as_dyn_node_mut::<BM>().merge(&mut right);
+        } else {
+            // copies are converted, so a dense leaf stays dense if the converted nodes do not fit into one page
+            let mut merged = left.copy_page();
+            let mut right_copy = right.copy_page();
+            left.reset_written();
+            right.reset_written();
+            let fits = [&mut merged, &mut right_copy]
+                .into_iter()
+                .all(|node| node.common.tag == to || self.convert_for_merge(node, to))
+                && merged.as_dyn_node::<BM>().can_merge(&right_copy);
+            if !fits {
+                return false;
+            }
+            self.log_before_write(&[left, &right, parent]);
+            merged.as_dyn_node_mut::<BM>().merge(&mut right_copy);
+            **left = merged;
+        }
+        let separator = parent.cast_mut::<BasicInner>().remove_separator::<BM::OlcEH>(right.lower_fence());
+        debug_assert!(separator.is_some());
+        self.log_pages(&[(left.page_id(), left), (parent.page_id(), parent)]);
+        right.dealloc();
+        true
+    }
+
+    fn convert_for_merge(&self, node: &mut Page, to: u8) -> bool {
+        if cfg!(feature = "disallow_promotions") || node.as_dyn_node::<BM>().can_promote(to).is_err() {
+            return false;
+        }
+        node.as_dyn_node_mut::<BM>().promote(to);
+        true
+    }
+
+    /// Replaces the root by its only child if it is an inner node without separators.
+    /// Returns the new root, which may be collapsed as well.
+    fn collapse_root(&self, meta: &mut BM::GuardX) -> Option<PageId> {
+        let root = BM::GuardX::try_acquire(self.bm, meta.cast::<MetadataPage>().root).ok()?;
+        if !root.as_dyn_node::<BM>().is_inner() || root.common.count != 0 {
+            return None;
+        }
+        self.log_before_write(&[meta, &root]);
+        let new_root = root.as_dyn_node::<BM>().children()[0];
+        meta.cast_mut::<MetadataPage>().root = new_root;
+        self.log_pages(&[(meta.page_id(), meta)]);
+        root.dealloc();
+        Some(new_root)
     }
 
     pub fn insert(&self, k: &[u8], val: &[u8]) -> Option<()> {
-        let x = BM::repeat(|| self.try_insert(k, val));
+        self.insert_fallible(k, val).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Like [Tree::insert], but returns an error instead of panicking if a split needs a page and the buffer manager
+    /// has none left.
+    /// The tree remains consistent and the key is not inserted in that case.
+    pub fn insert_fallible(&self, k: &[u8], val: &[u8]) -> Result<Option<()>, OutOfPages> {
+        let x = match self.optimistic(|| self.try_insert(k, val)) {
+            Some(x) => x,
+            None => self.insert_pessimistic(k, val),
+        };
+        self.commit_log();
         self.validate_fences();
         x
     }
 
+    /// Replaces the value of `k` by the result of `f`, which is called with the current value while the leaf is locked
+    /// exclusively.
+    /// `k` is removed if `f` returns `None`, and a value of unchanged length is overwritten in place.
+    /// `f` may be called again if the operation is retried, e.g. after splitting a leaf the new value does not fit
+    /// into, only the result of the last call is stored.
+    /// Returns the previous value, or an error if a split needs a page and the buffer manager has none left.
+    /// The tree remains consistent and the value of `k` is unchanged in that case.
+    pub fn update(
+        &self,
+        k: &[u8],
+        mut f: impl FnMut(Option<&[u8]>) -> Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>, OutOfPages> {
+        let x = match self.optimistic(|| self.try_update(k, &mut f)) {
+            Some(x) => x,
+            None => self.update_pessimistic(k, &mut f),
+        };
+        if let Ok((_, Some(node))) = x {
+            self.merge_underfull(node, k);
+        }
+        self.commit_log();
+        self.validate_fences();
+        x.map(|(old, _)| old)
+    }
+
+    /// Sets the value of `k` to `new` if it currently is `expected`, where `None` stands for a missing key.
+    /// Otherwise, the current value is returned as the inner `Err`.
+    /// Fails like [Tree::update] if the buffer manager runs out of pages.
+    pub fn compare_and_swap(
+        &self,
+        k: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<Result<(), Option<Vec<u8>>>, OutOfPages> {
+        let mut swapped = false;
+        let old = self.update(k, |old| {
+            swapped = old == expected;
+            if swapped {
+                new.map(|v| v.to_vec())
+            } else {
+                old.map(|v| v.to_vec())
+            }
+        })?;
+        if swapped {
+            Ok(Ok(()))
+        } else {
+            Ok(Err(old))
+        }
+    }
+
+    /// Returns the value of `k`, inserting the result of `f` first if it is missing.
+    /// Fails like [Tree::update] if the buffer manager runs out of pages.
+    pub fn get_or_insert_with(&self, k: &[u8], f: impl FnOnce() -> Vec<u8>) -> Result<Vec<u8>, OutOfPages> {
+        let mut f = Some(f);
+        let mut inserted = None;
+        let old = self.update(k, |old| match old {
+            Some(old) => Some(old.to_vec()),
+            None => Some(inserted.get_or_insert_with(|| f.take().unwrap()()).clone()),
+        })?;
+        Ok(old.or(inserted).unwrap())
+    }
+
+    fn try_update(
+        &self,
+        k: &[u8],
+        f: &mut UpdateFn,
+    ) -> Result<(Option<Vec<u8>>, Option<PageId>), OutOfPages> {
+        loop {
+            let [parent, node] = self.descend(k, None);
+            let node = self.decrease_scan_counter(node);
+            let mut node: BM::GuardX = node.upgrade();
+            if let Ok(x) = self.update_locked(&mut node, k, f) {
+                parent.release_unchecked();
+                return Ok(x);
+            }
+            let mut parent = parent.upgrade();
+            let full_parent = self.make_room(&mut node, &mut parent, k)?;
+            drop(parent);
+            drop(node);
+            if let Some(parent_id) = full_parent {
+                self.split_optimistic(parent_id, k)?;
+            }
+        }
+    }
+
+    fn update_pessimistic(
+        &self,
+        k: &[u8],
+        f: &mut UpdateFn,
+    ) -> Result<(Option<Vec<u8>>, Option<PageId>), OutOfPages> {
+        loop {
+            let (parent_id, parent_version, mut node) = self.descend_pessimistic::<BM::GuardX>(k, None);
+            if let Ok(x) = self.update_locked(&mut node, k, f) {
+                return Ok(x);
+            }
+            let Some(mut parent) = BM::GuardX::acquire_wait_version(self.bm, parent_id, parent_version) else {
+                continue;
+            };
+            let full_parent = self.make_room(&mut node, &mut parent, k)?;
+            drop(parent);
+            drop(node);
+            if let Some(parent_id) = full_parent {
+                self.split_pessimistic(parent_id, k)?;
+            }
+        }
+    }
+
+    /// Applies `f` to the value of `k` in the locked leaf `node`.
+    /// Returns the previous value and the leaf if it is underfull after removing `k`.
+    /// Fails if the new value does not fit, the leaf is left unchanged in that case.
+    fn update_locked(
+        &self,
+        node: &mut BM::GuardX,
+        k: &[u8],
+        f: &mut UpdateFn,
+    ) -> Result<(Option<Vec<u8>>, Option<PageId>), ()> {
+        let node_pid = node.page_id();
+        self.log_before_write(&[node]);
+        let leaf = node.as_dyn_node_mut::<BM>();
+        let old = leaf.leaf_value_mut(k);
+        let new = f(old.as_deref());
+        let old = match (old, &new) {
+            (Some(old), Some(new)) if old == new => {
+                let old = old.to_vec();
+                node.reset_written();
+                return Ok((Some(old), None));
+            }
+            (Some(old), Some(new)) if old.len() == new.len() => {
+                let prev = old.to_vec();
+                old.copy_from_slice(new);
+                self.log(WalRecord::Overwrite { page: node_pid, key: k, val: new });
+                return Ok((Some(prev), None));
+            }
+            (old, _) => old.map(|v| v.to_vec()),
+        };
+        match new {
+            Some(new) => {
+                if leaf.insert_leaf(k, &new).is_err() {
+                    node.reset_written();
+                    return Err(());
+                }
+                self.log(WalRecord::Insert { page: node_pid, key: k, val: &new });
+                Ok((old, None))
+            }
+            None if old.is_some() => {
+                leaf.leaf_remove(k);
+                self.log(WalRecord::Remove { page: node_pid, key: k });
+                Ok((old, leaf.is_underfull().then_some(node_pid)))
+            }
+            None => {
+                node.reset_written();
+                Ok((None, None))
+            }
+        }
+    }
+
     fn descend(&self, k: &[u8], stop_at: Option<PageId>) -> [BM::GuardO; 2] {
-        let mut parent = self.bm.lock_optimistic(self.meta);
+        BM::OlcEH::optmistic_fail_check(self.try_descend(k, stop_at))
+    }
+
+    /// Like [Self::descend], but returns optimistic errors instead of failing through [BufferManager::OlcEH].
+    fn try_descend(&self, k: &[u8], stop_at: Option<PageId>) -> Result<[BM::GuardO; 2], OptimisticError> {
+        self.try_descend_with(k, true, stop_at, |pid| Ok(self.bm.lock_optimistic(pid)))
+    }
+
+    /// Like [Self::try_descend], but locks nodes optimistically using `lock`, which may fail.
+    /// Unless `high_on_equal`, a key equal to a separator descends into the subtree left of it.
+    fn try_descend_with<E: From<OptimisticError>>(
+        &self,
+        k: &[u8],
+        high_on_equal: bool,
+        stop_at: Option<PageId>,
+        lock: impl Fn(PageId) -> Result<BM::GuardO, E>,
+    ) -> Result<[BM::GuardO; 2], E> {
+        let mut parent = lock(self.meta)?;
         let mut node_pid = self.meta;
-        let mut node = self.bm.lock_optimistic(self.meta);
-        while o_ptr_is_inner::<BM>(node.o_ptr()) && Some(node_pid) != stop_at {
-            node_pid = o_ptr_lookup_inner::<BM>(node.o_ptr(), k, true);
-            node.check(); // check here so we do not attempt to lock wrong page id
-            parent.release_unchecked();
-            parent = node;
-            node = self.bm.lock_optimistic(node_pid);
+        let mut node = match lock(self.meta) {
+            Ok(node) => node,
+            Err(e) => {
+                parent.release_unchecked();
+                return Err(e);
+            }
+        };
+        let next = |node: &BM::GuardO, node_pid: PageId| -> Result<Option<PageId>, OptimisticError> {
+            if Some(node_pid) == stop_at || !o_ptr_is_inner::<BM, _>(node.o_ptr_bm())? {
+                return Ok(None);
+            }
+            let child = o_ptr_lookup_inner::<BM, _>(node.o_ptr_bm(), k, high_on_equal)?;
+            node.try_check()?; // check here so we do not attempt to lock wrong page id
+            Ok(Some(child))
+        };
+        loop {
+            match next(&node, node_pid) {
+                Ok(Some(child)) => {
+                    // node was locked before validating its parent, so it is not a page that was freed by a merge
+                    // and reused since the parent was read
+                    if let Err(e) = parent.try_release() {
+                        node.release_unchecked();
+                        return Err(e.into());
+                    }
+                    parent = node;
+                    node_pid = child;
+                    node = match lock(node_pid) {
+                        Ok(node) => node,
+                        Err(e) => {
+                            parent.release_unchecked();
+                            return Err(e);
+                        }
+                    };
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    // dropping the guards would validate them again
+                    parent.release_unchecked();
+                    node.release_unchecked();
+                    return Err(e.into());
+                }
+            }
         }
         // ensure we return the correct node
         // we could push this responsibility on the caller, but this has proven error-prone
-        parent.check();
-        [parent, node]
+        if let Err(e) = parent.try_check() {
+            parent.release_unchecked();
+            node.release_unchecked();
+            return Err(e.into());
+        }
+        Ok([parent, node])
+    }
+
+    fn split_and_insert(&self, split_target: PageId, k: &[u8], val: &[u8]) -> Result<Option<()>, OutOfPages> {
+        self.split_optimistic(split_target, k)?;
+        self.try_insert(k, val)
     }
 
-    fn split_and_insert(&self, split_target: PageId, k: &[u8], val: &[u8]) -> Option<()> {
+    /// Splits the inner node `split_target` on the path to `k`, and its ancestors as long as they are full.
+    fn split_optimistic(&self, split_target: PageId, k: &[u8]) -> Result<(), OutOfPages> {
         let parent_id = {
             let [parent, node] = self.descend(k, Some(split_target));
             if node.page_id() == split_target {
                 let mut node: BM::GuardX = node.upgrade();
                 let mut parent: BM::GuardX = parent.upgrade();
-                self.ensure_parent_not_meta(&mut parent);
-                if self.split_locked_node(&mut node, &mut parent, k).is_ok() {
-                    None
-                } else {
-                    Some(parent.page_id())
+                self.ensure_parent_not_meta(&mut parent)?;
+                match self.split_locked_node(&mut node, &mut parent, k) {
+                    Ok(()) => None,
+                    Err(SplitError::ParentFull) => Some(parent.page_id()),
+                    Err(SplitError::OutOfPages) => return Err(OutOfPages),
                 }
             } else {
                 None
             }
         };
         if let Some(p) = parent_id {
-            self.split_and_insert(p, k, val)
+            self.split_optimistic(p, k)
         } else {
-            self.try_insert(k, val)
+            Ok(())
         }
     }
 
-    fn ensure_parent_not_meta(&self, parent: &mut BM::GuardX) {
+    fn ensure_parent_not_meta(&self, parent: &mut BM::GuardX) -> Result<(), OutOfPages> {
         if parent.common.tag == node_tag::METADATA_MARKER {
+            let mut new_root = self.bm.try_alloc()?;
+            self.log_before_write(&[parent]);
             let meta = parent.cast_mut::<MetadataPage>();
-            let mut new_root = self.bm.alloc();
             NodeStatic::<BM>::init(
                 new_root.cast_mut::<BasicInner>(),
                 &[][..],
@@ -257,51 +1008,307 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> Tree<'bm, BM> {
                 Some(&page_id_to_bytes(meta.root)),
             );
             meta.root = new_root.page_id();
+            self.log_pages(&[(new_root.page_id(), &new_root), (parent.page_id(), parent)]);
             *parent = new_root
         }
+        Ok(())
     }
 
-    fn try_insert(&self, k: &[u8], val: &[u8]) -> Option<()> {
+    fn try_insert(&self, k: &[u8], val: &[u8]) -> Result<Option<()>, OutOfPages> {
         let [parent, node] = self.descend(k, None);
         let node = self.decrease_scan_counter(node);
         let mut node: BM::GuardX = node.upgrade();
 
 
+        self.log_before_write(&[&node]);
         match node.as_dyn_node_mut::<BM>().insert_leaf(k, val) {
             Ok(x) => {
+                self.log(WalRecord::Insert { page: node.page_id(), key: k, val });
                 parent.release_unchecked();
-                x
+                Ok(x)
             }
             Err(_) => {
                 node.reset_written();
                 let mut parent = parent.upgrade();
-                self.ensure_parent_not_meta(&mut parent);
+                let full_parent = self.make_room(&mut node, &mut parent, k)?;
+                drop(parent);
+                drop(node);
+                if let Some(parent_id) = full_parent {
+                    return self.split_and_insert(parent_id, k, val);
+                }
+                // TODO could descend from parent
+                self.try_insert(k, val)
+            }
+        }
+    }
+
+    /// Makes room in a full leaf by promoting or splitting it.
+    /// Returns the parent page id if the parent must be split first.
+    fn make_room(
+        &self,
+        node: &mut BM::GuardX,
+        parent: &mut BM::GuardX,
+        k: &[u8],
+    ) -> Result<Option<PageId>, OutOfPages> {
+        self.ensure_parent_not_meta(parent)?;
 
-                #[cfg(not(feature = "disallow_promotions"))]
-                let can_promote = node.as_dyn_node::<BM>().can_promote(node_tag::FULLY_DENSE_LEAF).is_ok();
+        #[cfg(not(feature = "disallow_promotions"))]
+        let can_promote = node.as_dyn_node::<BM>().can_promote(node_tag::FULLY_DENSE_LEAF).is_ok();
+
+        #[cfg(feature = "disallow_promotions")]
+        let can_promote = false;
 
-                #[cfg(feature = "disallow_promotions")]
-                let can_promote = false;
+        if can_promote {
+            self.log_before_write(&[node]);
+            node.as_dyn_node_mut::<BM>().promote(node_tag::FULLY_DENSE_LEAF);
+            self.log_pages(&[(node.page_id(), node)]);
+            return Ok(None);
+        }
+        match self.split_locked_node(node, parent, k) {
+            Ok(()) => Ok(None),
+            Err(SplitError::ParentFull) => Ok(Some(parent.page_id())),
+            Err(SplitError::OutOfPages) => Err(OutOfPages),
+        }
+    }
 
+    /// Pessimistic counterpart of [Tree::descend], used once an operation exceeded the restart limit.
+    ///
+    /// Locks are coupled: inner nodes are locked shared, and each node is locked before the lock of its parent is
+    /// released, so it cannot be merged away and freed in between.
+    /// The node `k` belongs to, or `stop_at`, is locked as `G`.
+    /// Writers lock a node before its parent, so waiting for a child while holding the lock of its parent could
+    /// deadlock with them.
+    /// Instead, if the child is locked in a conflicting mode, the parent is released and locked again at the same
+    /// version once the child is available, or the descent restarts if the parent was modified in between.
+    /// Returns the id and version of the parent along with the locked node.
+    fn descend_pessimistic<G: BufferManagerGuard<'bm, BM>>(
+        &self,
+        k: &[u8],
+        stop_at: Option<PageId>,
+    ) -> (PageId, OlcVersion, G) {
+        self.descend_pessimistic_with(k, true, stop_at)
+    }
 
-                if can_promote {
-                    node.as_dyn_node_mut::<BM>().promote(node_tag::FULLY_DENSE_LEAF);
+    /// Like [Self::descend_pessimistic], but unless `high_on_equal`, a key equal to a separator descends into the
+    /// subtree left of it.
+    fn descend_pessimistic_with<G: BufferManagerGuard<'bm, BM>>(
+        &self,
+        k: &[u8],
+        high_on_equal: bool,
+        stop_at: Option<PageId>,
+    ) -> (PageId, OlcVersion, G) {
+        'restart: loop {
+            let mut parent = self.bm.lock_shared(self.meta);
+            let mut node_pid = page_cast::<_, MetadataPage>(&*parent).root;
+            loop {
+                let mut node = match BM::GuardS::try_acquire(self.bm, node_pid) {
+                    Ok(node) => node,
+                    Err(WouldBlock) => match self.await_child(parent, node_pid) {
+                        Some(p) => {
+                            parent = p;
+                            continue;
+                        }
+                        None => continue 'restart,
+                    },
+                };
+                if node.as_dyn_node::<BM>().is_inner() && Some(node_pid) != stop_at {
+                    let child = BM::OlcEH::optmistic_fail_check(o_ptr_lookup_inner::<BM, _>(node.o_ptr(), k, high_on_equal));
+                    parent.release();
+                    parent = node;
+                    node_pid = child;
+                    continue;
                 }
+                drop(node);
+                match G::try_acquire(self.bm, node_pid) {
+                    Ok(node) => return (parent.page_id(), parent.release(), node),
+                    Err(WouldBlock) => match self.await_child(parent, node_pid) {
+                        Some(p) => parent = p,
+                        None => continue 'restart,
+                    },
+                }
+            }
+        }
+    }
+
+    /// Waits until `child` is no longer locked exclusively, after releasing its locked `parent`.
+    /// The child may be freed once the parent is released, so it is only locked optimistically.
+    /// Returns the parent locked again, or `None` if it was modified in between.
+    fn await_child(&self, parent: BM::GuardS, child: PageId) -> Option<BM::GuardS> {
+        let parent_pid = parent.page_id();
+        let version = parent.release();
+        self.bm.lock_optimistic(child).release_unchecked();
+        BM::GuardS::acquire_wait_version(self.bm, parent_pid, version)
+    }
 
-                else if self.split_locked_node(&mut node, &mut parent, k).is_err() {
+    fn insert_pessimistic(&self, k: &[u8], val: &[u8]) -> Result<Option<()>, OutOfPages> {
+        loop {
+            let (parent_id, parent_version, mut node) = self.descend_pessimistic::<BM::GuardX>(k, None);
+            self.log_before_write(&[&node]);
+            if let Ok(x) = node.as_dyn_node_mut::<BM>().insert_leaf(k, val) {
+                self.log(WalRecord::Insert { page: node.page_id(), key: k, val });
+                return Ok(x);
+            }
+            node.reset_written();
+            let Some(mut parent) = BM::GuardX::acquire_wait_version(self.bm, parent_id, parent_version) else {
+                continue;
+            };
+            let full_parent = self.make_room(&mut node, &mut parent, k)?;
+            drop(parent);
+            drop(node);
+            if let Some(parent_id) = full_parent {
+                self.split_pessimistic(parent_id, k)?;
+            }
+        }
+    }
+
+    /// Splits the inner node `target` on the path to `k`, unless it was split concurrently.
+    fn split_pessimistic(&self, target: PageId, k: &[u8]) -> Result<(), OutOfPages> {
+        loop {
+            let (parent_id, parent_version, mut node) = self.descend_pessimistic::<BM::GuardX>(k, Some(target));
+            if node.page_id() != target {
+                return Ok(());
+            }
+            let Some(mut parent) = BM::GuardX::acquire_wait_version(self.bm, parent_id, parent_version) else {
+                continue;
+            };
+            self.ensure_parent_not_meta(&mut parent)?;
+            match self.split_locked_node(&mut node, &mut parent, k) {
+                Ok(()) => return Ok(()),
+                Err(SplitError::ParentFull) => {
                     let parent_id = parent.page_id();
                     drop(parent);
                     drop(node);
-                    return self.split_and_insert(parent_id, k, val);
+                    self.split_pessimistic(parent_id, k)?;
                 }
-                drop(parent);
-                drop(node);
-                // TODO could descend from parent
-                self.try_insert(k, val)
+                Err(SplitError::OutOfPages) => return Err(OutOfPages),
+            }
+        }
+    }
+
+    /// Like [Tree::insert], but fails with [InsertNowaitError::WouldBlock] instead of waiting for a page that is locked
+    /// by another thread.
+    /// Operations exceeding the restart limit fail the same way, as they would fall back to waiting.
+    /// The key is not inserted if an error is returned, though nodes on its path may have been split.
+    pub fn try_insert_nowait(&self, k: &[u8], val: &[u8]) -> Result<Option<()>, InsertNowaitError> {
+        let x = self.nowait(|| self.insert_nowait_attempt(k, val));
+        self.commit_log();
+        self.validate_fences();
+        x
+    }
+
+    /// Like [Tree::remove], but returns [WouldBlock] instead of waiting, see [Tree::try_insert_nowait].
+    /// Underfull leaves are not merged, as that waits for the parent.
+    pub fn try_remove_nowait(&self, k: &[u8]) -> Result<Option<()>, WouldBlock> {
+        let removed = self.nowait(|| {
+            let [parent, node] = self.try_descend_with(k, true, None, |p| self.lock_nowait(p))?;
+            parent.release_unchecked();
+            let mut node: BM::GuardX = self.upgrade_nowait(node)?;
+            self.log_before_write(&[&node]);
+            let removed = node.as_dyn_node_mut::<BM>().leaf_remove(k);
+            if removed.is_some() {
+                self.log(WalRecord::Remove { page: node.page_id(), key: k });
+            }
+            Ok(removed)
+        });
+        self.commit_log();
+        removed.map_err(|e| match e {
+            InsertNowaitError::WouldBlock => WouldBlock,
+            InsertNowaitError::OutOfPages => unreachable!("removing does not allocate pages"),
+        })
+    }
+
+    /// Runs a non-blocking operation, restarting it on optimistic errors up to the restart limit.
+    fn nowait<R>(&self, mut f: impl FnMut() -> Result<R, NowaitError>) -> Result<R, InsertNowaitError> {
+        let r = self.optimistic_result(|| match f() {
+            Ok(x) => Ok(Ok(x)),
+            Err(NowaitError::WouldBlock) => Ok(Err(InsertNowaitError::WouldBlock)),
+            Err(NowaitError::OutOfPages) => Ok(Err(InsertNowaitError::OutOfPages)),
+            Err(NowaitError::Restart) => Err(OptimisticError::new()),
+        });
+        r.unwrap_or(Err(InsertNowaitError::WouldBlock))
+    }
+
+    fn lock_nowait(&self, pid: PageId) -> Result<BM::GuardO, NowaitError> {
+        Ok(BM::GuardO::try_acquire(self.bm, pid)?)
+    }
+
+    /// Locks the page of `node` in another mode without waiting, unless it was modified since `node` was acquired.
+    /// `node` is released in any case.
+    fn upgrade_nowait<G: BufferManagerGuard<'bm, BM>>(&self, node: BM::GuardO) -> Result<G, NowaitError> {
+        let pid = node.page_id();
+        let version = node.try_release()?;
+        G::try_acquire_version(self.bm, pid, version)?.ok_or(NowaitError::Restart)
+    }
+
+    /// Non-blocking counterpart of [Self::try_insert].
+    /// Scan counters are left alone, as adaptive promotion may wait for locks.
+    fn insert_nowait_attempt(&self, k: &[u8], val: &[u8]) -> Result<Option<()>, NowaitError> {
+        loop {
+            let [parent, node] = self.try_descend_with(k, true, None, |p| self.lock_nowait(p))?;
+            let mut node: BM::GuardX = match self.upgrade_nowait(node) {
+                Ok(node) => node,
+                Err(e) => {
+                    parent.release_unchecked();
+                    return Err(e);
+                }
+            };
+            self.log_before_write(&[&node]);
+            if let Ok(x) = node.as_dyn_node_mut::<BM>().insert_leaf(k, val) {
+                self.log(WalRecord::Insert { page: node.page_id(), key: k, val });
+                parent.release_unchecked();
+                return Ok(x);
+            }
+            node.reset_written();
+            let mut parent: BM::GuardX = self.upgrade_nowait(parent)?;
+            let full_parent = self.make_room(&mut node, &mut parent, k)?;
+            drop(parent);
+            drop(node);
+            if let Some(parent_id) = full_parent {
+                self.split_nowait(parent_id, k)?;
+            }
+        }
+    }
+
+    /// Non-blocking counterpart of [Self::split_pessimistic].
+    fn split_nowait(&self, target: PageId, k: &[u8]) -> Result<(), NowaitError> {
+        loop {
+            let [parent, node] = self.try_descend_with(k, true, Some(target), |p| self.lock_nowait(p))?;
+            if node.page_id() != target {
+                parent.release_unchecked();
+                node.release_unchecked();
+                return Ok(());
+            }
+            let mut node: BM::GuardX = match self.upgrade_nowait(node) {
+                Ok(node) => node,
+                Err(e) => {
+                    parent.release_unchecked();
+                    return Err(e);
+                }
+            };
+            let mut parent: BM::GuardX = self.upgrade_nowait(parent)?;
+            self.ensure_parent_not_meta(&mut parent)?;
+            match self.split_locked_node(&mut node, &mut parent, k) {
+                Ok(()) => return Ok(()),
+                Err(SplitError::ParentFull) => {
+                    let parent_id = parent.page_id();
+                    drop(parent);
+                    drop(node);
+                    self.split_nowait(parent_id, k)?;
+                }
+                Err(SplitError::OutOfPages) => return Err(NowaitError::OutOfPages),
             }
         }
     }
 
+    /// Returns `None` if `k` was not found, otherwise the leaf if it is underfull, see [Self::try_remove].
+    fn remove_pessimistic(&self, k: &[u8]) -> Option<Option<PageId>> {
+        let (_, _, mut node) = self.descend_pessimistic::<BM::GuardX>(k, None);
+        self.log_before_write(&[&node]);
+        node.as_dyn_node_mut::<BM>().leaf_remove(k)?;
+        self.log(WalRecord::Remove { page: node.page_id(), key: k });
+        Some(node.as_dyn_node::<BM>().is_underfull().then(|| node.page_id()))
+    }
+
     pub fn lookup_to_vec(&self, k: &[u8]) -> Option<Vec<u8>> {
         self.lookup_inspect(k, |v| v.map(|v| v.load_slice_to_vec()))
     }
@@ -313,25 +1320,55 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> Tree<'bm, BM> {
         })
     }
 
+    /// Calls `f` with the value of `k`, possibly several times with values read from a page that is being modified.
+    /// Only the result of the last call, which saw consistent data, is returned.
+    ///
+    /// Restarts do not unwind, so this also works if `BM::OlcEH` cannot catch optimistic errors, as long as `f`
+    /// does not fail through it.
     pub fn lookup_inspect<R>(&self, k: &[u8], mut f: impl FnMut(Option<OPtr<[u8], BM::OlcEH>>) -> R) -> R {
-        BM::repeat(move || if let Some((_guard, val)) = self.try_lookup(k) { f(Some(val)) } else { f(None) })
+        let optimistic = self.optimistic_result(|| match self.try_lookup(k)? {
+            Some((guard, val)) => {
+                let r = f(Some(val));
+                guard.try_release()?;
+                Ok(r)
+            }
+            None => Ok(f(None)),
+        });
+        optimistic.unwrap_or_else(|| {
+            let (_, _, mut node) = self.descend_pessimistic::<BM::GuardS>(k, None);
+            let val = BM::OlcEH::optmistic_fail_check(o_ptr_lookup_leaf::<BM, _>(node.o_ptr(), k));
+            f(val.map(OPtr::optimistic))
+        })
     }
 
-    pub fn try_lookup(&self, k: &[u8]) -> Option<(BM::GuardO, OPtr<[u8], BM::OlcEH>)> {
-        let [parent, node] = self.descend(k, None);
-        drop(parent);
-        let node = self.decrease_scan_counter(node);
-
-        let val = o_ptr_lookup_leaf::<BM>(node.o_ptr_bm(), k)?;
-
-
-        Some((node, val))
+    /// Finds the value of `k` without failing through [BufferManager::OlcEH].
+    ///
+    /// If the key is found, its value must be read before validating the returned guard with
+    /// [OptimisticGuard::try_release], dropping the guard would fail through `BM::OlcEH` if validation fails.
+    pub fn try_lookup(&self, k: &[u8]) -> Result<Option<(BM::GuardO, OPtr<[u8], BM::OlcEH>)>, OptimisticError> {
+        let [parent, node] = self.try_descend(k, None)?;
+        parent.release_unchecked();
+        let node = self.try_decrease_scan_counter(node)?;
+        match o_ptr_lookup_leaf::<BM, _>(node.o_ptr_bm(), k) {
+            Ok(Some(val)) => Ok(Some((node, val))),
+            Ok(None) => {
+                node.try_release()?;
+                Ok(None)
+            }
+            Err(e) => {
+                node.release_unchecked();
+                Err(e)
+            }
+        }
     }
 
-    fn split_locked_node(&self, node: &mut Page, parent: &mut Page, key: &[u8]) -> Result<(), ()> {
+    fn split_locked_node(&self, node: &mut BM::GuardX, parent: &mut BM::GuardX, key: &[u8]) -> Result<(), SplitError> {
         //TODO inline
         if node.common.count as usize > 1 {
-            node.as_dyn_node_mut().split(self.bm, parent.as_dyn_node_mut(), key)
+            self.log_before_write(&[node, parent]);
+            node.as_dyn_node_mut().split(self.bm, parent.as_dyn_node_mut(), key)?;
+            self.log_split(node, parent);
+            Ok(())
         } else {
             Ok(())
         }
@@ -346,7 +1383,7 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> Tree<'bm, BM> {
             self.bm.lock_shared(node_pid)
         };
         while node.as_dyn_node::<BM>().is_inner() {
-            let node_pid = o_ptr_lookup_inner::<BM>(node.o_ptr(), key, true);
+            let node_pid = BM::OlcEH::optmistic_fail_check(o_ptr_lookup_inner::<BM, _>(node.o_ptr(), key, true));
             path.push(node);
             node = self.bm.lock_shared(node_pid);
         }
@@ -354,14 +1391,17 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> Tree<'bm, BM> {
         path
     }
 
-    fn decrease_scan_counter(&self, mut node: BM::GuardO) -> BM::GuardO{
+    fn decrease_scan_counter(&self, node: BM::GuardO) -> BM::GuardO {
+        BM::OlcEH::optmistic_fail_check(self.try_decrease_scan_counter(node))
+    }
+
+    fn try_decrease_scan_counter(&self, mut node: BM::GuardO) -> Result<BM::GuardO, OptimisticError> {
         if fastrand::u8(..100) < 5 {
             node.o_ptr().decrease_scan_counter();
 
-            let node = self.adaptive_promotion(node);
-            return node;
+            return self.adaptive_promotion(node);
         }
-        node
+        Ok(node)
     }
 
 
@@ -369,7 +1409,7 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> Tree<'bm, BM> {
         if fastrand::u8(..100) < 15 {
             node.o_ptr().increase_scan_counter();
 
-            let node = self.adaptive_promotion(node);
+            let node = BM::OlcEH::optmistic_fail_check(self.adaptive_promotion(node));
             return node;
         }
         node
@@ -378,7 +1418,8 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> Tree<'bm, BM> {
 
 
 
-    fn adaptive_promotion (&self, mut node: BM::GuardO) -> BM::GuardO{
+    /// On failure, `node` is released without validating it.
+    fn adaptive_promotion (&self, mut node: BM::GuardO) -> Result<BM::GuardO, OptimisticError> {
         let o: OPtr<'_, Page, BM::OlcEH> = node.o_ptr();
 
         let tag: u8 = o_project!(o.common.tag).r();
@@ -390,38 +1431,38 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> Tree<'bm, BM> {
 
         #[cfg(not(feature = "disallow_promotions"))]
         if (tag == 251 && scan == 0) || (tag==252 && scan >= 3) {
-            let mut node: BM::GuardX = node.upgrade();
+            let mut node: BM::GuardX = node.try_upgrade()?;
 
             let to = if tag == 251 {252} else {251};
 
+            self.log_before_write(&[&node]);
             if node.as_dyn_node::<BM>().can_promote(to).is_ok() {
                 node.as_dyn_node_mut::<BM>().promote(to);
             }
             else {
                 node.as_dyn_node_mut::<BM>().retry_later();
             }
+            self.log_pages(&[(node.page_id(), &node)]);
 
-            return self.downgrade_guard(node);
+            // keeps the version continuous, so a concurrent split cannot hand us a different node
+            return Ok(node.downgrade());
         }
 
-        node
+        Ok(node)
     }
 
 
-    fn downgrade_guard(&self, x: BM::GuardX) -> BM::GuardO {
-        let pid = x.page_id();
-        let v   = x.release();                // unlock X, get new version
-        BM::GuardO::acquire_wait_version(self.bm, pid, v)
-            .unwrap_or_else(|| BM::GuardO::acquire_wait(self.bm, pid))
-    }
-
 }
 
 impl<'bm, BM: BufferManager<'bm, Page = Page>> Drop for Tree<'bm, BM> {
     fn drop(&mut self) {
-        let mut meta_lock = self.bm.lock_exclusive(self.meta);
-        meta_lock.cast_mut::<MetadataPage>().free_children(self.bm);
-        meta_lock.dealloc();
+        // nodes are freed before their children, so no parent is held while locking a child
+        let mut pending = vec![self.meta];
+        while let Some(pid) = pending.pop() {
+            let node = self.bm.lock_exclusive(pid);
+            pending.extend(node.as_dyn_node::<BM>().children());
+            node.dealloc();
+        }
     }
 }
 
@@ -462,12 +1503,19 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeStatic<'bm, BM> for MetadataP
         std::iter::once((&[][..], self.root))
     }
 
-    fn lookup_leaf<'a>(_this: OPtr<'a, Self, BM::OlcEH>, _key: &[u8]) -> Option<OPtr<'a, [u8], BM::OlcEH>> {
-        BM::OlcEH::optimistic_fail()
+    fn lookup_leaf<'a, M: OPtrMode>(
+        _this: OPtr<'a, Self, BM::OlcEH, M>,
+        _key: &[u8],
+    ) -> Result<Option<OPtr<'a, [u8], BM::OlcEH, M>>, OptimisticError> {
+        Err(OptimisticError::new())
     }
 
-    fn lookup_inner(this: OPtr<'_, Self, BM::OlcEH>, _key: &[u8], _high_on_equal: bool) -> PageId {
-        PageId { x: o_project!(this.root.x).r() }
+    fn lookup_inner<M: OPtrMode>(
+        this: OPtr<'_, Self, BM::OlcEH, M>,
+        _key: &[u8],
+        _high_on_equal: bool,
+    ) -> Result<PageId, OptimisticError> {
+        Ok(PageId { x: o_project!(this.root.x).r() })
     }
 
 
@@ -485,7 +1533,7 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeStatic<'bm, BM> for MetadataP
 }
 
 impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeDynamic<'bm, BM> for MetadataPage {
-    fn split(&mut self, _bm: BM, _parent: &mut dyn NodeDynamic<'bm, BM>, _key: &[u8]) -> Result<(), ()> {
+    fn split(&mut self, _bm: BM, _parent: &mut dyn NodeDynamic<'bm, BM>, _key: &[u8]) -> Result<(), SplitError> {
         unimplemented!()
     }
 
@@ -493,13 +1541,21 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeDynamic<'bm, BM> for Metadata
         unimplemented!()
     }
 
+    fn can_merge(&self, _right: &Page) -> bool {
+        false
+    }
+
+    fn is_underfull(&self) -> bool {
+        false
+    }
+
     fn validate(&self) {}
 
     fn leaf_remove(&mut self, _k: &[u8]) -> Option<()> {
         unimplemented!()
     }
 
-    fn scan_with_callback(&self, _buffer: &mut [MaybeUninit<u8>; 512], _start : &[u8], _callback: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> bool {
+    fn scan_with_callback(&self, _buffer: &mut [MaybeUninit<u8>; 512], _range: &ScanRange, _callback: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> bool {
         unimplemented!()
     }
 
@@ -528,3 +1584,64 @@ impl<'bm, BM: BufferManager<'bm, Page = Page>> NodeDynamic<'bm, BM> for Metadata
         todo!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::node::{o_ptr_lookup_leaf, Page};
+    use crate::tree::Tree;
+    use dev_utils::keyset_generator::{GoodHeadsKeyset, KeyGenerator};
+    use dev_utils::mixed_test_keys;
+    use umolc::{BufferManager, BufferManagerExt, BufferManagerGuard, SimpleBm};
+
+    type BM<'a> = &'a SimpleBm<Page>;
+
+    fn lookup_pessimistic<'bm>(tree: &Tree<'bm, BM<'bm>>, k: &[u8]) -> Option<Vec<u8>> {
+        let (_, _, mut node) = tree.descend_pessimistic::<<BM<'bm> as BufferManager<'bm>>::GuardS>(k, None);
+        o_ptr_lookup_leaf::<BM, _>(node.o_ptr(), k).unwrap().map(|v| v.load_slice_to_vec())
+    }
+
+    #[test]
+    fn pessimistic_operations() {
+        let bm = SimpleBm::<Page>::new(1 << 12);
+        let tree = Tree::new(&bm);
+        let keys = mixed_test_keys(10_000, false, 31);
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(tree.insert_pessimistic(k, &(i as u32).to_le_bytes()), Ok(None));
+        }
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(lookup_pessimistic(&tree, k).unwrap(), (i as u32).to_le_bytes());
+            assert_eq!(tree.lookup_to_vec(k).unwrap(), (i as u32).to_le_bytes());
+        }
+        for k in keys.iter().step_by(2) {
+            assert!(tree.remove_pessimistic(k).is_some());
+            assert!(tree.remove_pessimistic(k).is_none());
+        }
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(lookup_pessimistic(&tree, k).is_some(), i % 2 == 1);
+        }
+    }
+
+    #[cfg_attr(not(miri), test)]
+    fn pessimistic_and_optimistic_writers() {
+        const THREADS: usize = 4;
+        let bm = SimpleBm::<Page>::new(1 << 14);
+        let tree = &Tree::new(&bm);
+        let keys = &GoodHeadsKeyset::generate_keyset(10_000 * THREADS);
+        std::thread::scope(|s| {
+            for t in 0..THREADS {
+                s.spawn(move || {
+                    for (k, v) in keys.iter().skip(t).step_by(THREADS) {
+                        if t % 2 == 0 {
+                            tree.insert_pessimistic(k, v).unwrap();
+                        } else {
+                            tree.insert(k, v);
+                        }
+                    }
+                });
+            }
+        });
+        for (k, v) in keys {
+            assert_eq!(&lookup_pessimistic(tree, k).unwrap(), v);
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use crate::node::Page;
+use crate::tree::Tree;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+use umolc::{BufferManager, PageId};
+
+const MAGIC: &[u8; 8] = b"umolcwal";
+/// length and checksum
+const RECORD_HEADER_LEN: usize = 8;
+/// in [SyncMode::None], the buffer is written out once it grows beyond this
+const MAX_BUFFERED: usize = 1 << 20;
+
+const TYPE_INSERT: u8 = 1;
+const TYPE_REMOVE: u8 = 2;
+const TYPE_OVERWRITE: u8 = 3;
+const TYPE_PAGES: u8 = 4;
+
+/// Determines what [Wal::commit] waits for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SyncMode {
+    /// Records are buffered in memory and written out in large batches, commit returns immediately.
+    None,
+    /// Commit waits until the record has been handed to the operating system.
+    Flush,
+    /// Commit waits until the record has been written to stable storage.
+    Fsync,
+}
+
+#[derive(Clone, Debug)]
+pub struct WalConfig {
+    pub sync_mode: SyncMode,
+    /// How long a committing thread waits for other threads to append records before flushing them together.
+    pub group_commit_delay: Duration,
+}
+
+impl Default for WalConfig {
+    fn default() -> Self {
+        WalConfig { sync_mode: SyncMode::Fsync, group_commit_delay: Duration::ZERO }
+    }
+}
+
+/// A single log entry.
+/// Leaf modifications name the leaf they were applied to, [Wal::replay] repeats them on the same page.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WalRecord<'a> {
+    /// `key` was inserted into the leaf `page`, or its value replaced.
+    Insert { page: PageId, key: &'a [u8], val: &'a [u8] },
+    /// The value of `key` in the leaf `page` was overwritten in place by `val`, which has the same length.
+    Overwrite { page: PageId, key: &'a [u8], val: &'a [u8] },
+    /// `key` was removed from the leaf `page`.
+    Remove { page: PageId, key: &'a [u8] },
+    /// The contents of pages, which replaying restores together.
+    /// Logged for a page before it is modified or freed for the first time since the log was created or truncated,
+    /// and for all pages changed by a split, promotion, merge or new root.
+    Pages { images: Vec<(PageId, &'a [u8])> },
+}
+
+impl WalRecord<'_> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(&[0; RECORD_HEADER_LEN]);
+        match *self {
+            WalRecord::Insert { page, key, val } | WalRecord::Overwrite { page, key, val } => {
+                out.push(if matches!(self, WalRecord::Insert { .. }) { TYPE_INSERT } else { TYPE_OVERWRITE });
+                out.extend_from_slice(&page.x.to_le_bytes());
+                out.extend_from_slice(&(key.len() as u16).to_le_bytes());
+                out.extend_from_slice(key);
+                out.extend_from_slice(val);
+            }
+            WalRecord::Remove { page, key } => {
+                out.push(TYPE_REMOVE);
+                out.extend_from_slice(&page.x.to_le_bytes());
+                out.extend_from_slice(key);
+            }
+            WalRecord::Pages { ref images } => {
+                out.push(TYPE_PAGES);
+                for &(page, image) in images {
+                    debug_assert_eq!(image.len(), size_of::<Page>());
+                    out.extend_from_slice(&page.x.to_le_bytes());
+                    out.extend_from_slice(image);
+                }
+            }
+        }
+        let body_len = (out.len() - start - RECORD_HEADER_LEN) as u32;
+        let crc = crc32fast::hash(&out[start + RECORD_HEADER_LEN..]);
+        out[start..][..4].copy_from_slice(&body_len.to_le_bytes());
+        out[start + 4..][..4].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    fn decode(body: &[u8]) -> Option<WalRecord<'_>> {
+        let (&ty, payload) = body.split_first()?;
+        let page = |bytes: &[u8]| Some(PageId { x: u64::from_le_bytes(bytes.get(..8)?.try_into().unwrap()) });
+        Some(match ty {
+            TYPE_INSERT | TYPE_OVERWRITE => {
+                let page = page(payload)?;
+                let key_len = u16::from_le_bytes(payload.get(8..10)?.try_into().unwrap()) as usize;
+                let key = payload.get(10..10 + key_len)?;
+                let val = &payload[10 + key_len..];
+                if ty == TYPE_INSERT {
+                    WalRecord::Insert { page, key, val }
+                } else {
+                    WalRecord::Overwrite { page, key, val }
+                }
+            }
+            TYPE_REMOVE => WalRecord::Remove { page: page(payload)?, key: payload.get(8..)? },
+            TYPE_PAGES if payload.len() % (8 + size_of::<Page>()) == 0 => {
+                let images = payload.chunks(8 + size_of::<Page>()).map(|x| Some((page(x)?, &x[8..])));
+                WalRecord::Pages { images: images.collect::<Option<_>>()? }
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// The contents of `page` as logged in [WalRecord::Pages].
+pub(crate) fn page_bytes(page: &Page) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(page as *const Page as *const u8, size_of::<Page>()) }
+}
+
+/// Overwrites `page` with contents logged in [WalRecord::Pages].
+pub(crate) fn restore_page(page: &mut Page, image: &[u8]) {
+    unsafe { std::slice::from_raw_parts_mut(page as *mut Page as *mut u8, size_of::<Page>()) }.copy_from_slice(image)
+}
+
+/// Calls `f` on every record in the log contents, stopping at the first incomplete or corrupt record.
+/// Returns the length of the valid prefix.
+fn for_each_record(log: &[u8], mut f: impl FnMut(WalRecord)) -> usize {
+    let mut offset = MAGIC.len();
+    while let Some(header) = log.get(offset..offset + RECORD_HEADER_LEN) {
+        let body_len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let Some(body) = log.get(offset + RECORD_HEADER_LEN..offset + RECORD_HEADER_LEN + body_len) else {
+            break;
+        };
+        if crc32fast::hash(body) != crc {
+            break;
+        }
+        let Some(record) = WalRecord::decode(body) else {
+            break;
+        };
+        f(record);
+        offset += RECORD_HEADER_LEN + body_len;
+    }
+    offset
+}
+
+struct WalState {
+    buffer: Vec<u8>,
+    /// log offset one past the last appended record
+    appended: u64,
+    /// log offset up to which records have been committed according to the sync mode
+    committed: u64,
+    flush_in_progress: bool,
+    /// pages whose contents were logged since the log was created or truncated, see [Wal::log_before_write]
+    logged_pages: HashSet<PageId>,
+}
+
+/// A redo log for tree mutations.
+///
+/// The log starts from a durable state of the tree, e.g. a flushed [umolc::MmapBm], and [Wal::replay] restores the
+/// tree on the pages it was written from after a crash.
+/// Pages may have been written back in any state before the crash, even partially.
+/// So before a page is modified or freed for the first time since the log was created or truncated, its contents
+/// are logged and committed, and replaying starts each page from there.
+/// Structural changes like splits, promotions, merges and new roots log the contents of all pages they changed in one
+/// record, inserts and removes log the leaf they modified, so replaying never changes the structure on its own.
+/// Records are appended while the modified pages are still locked, so for every page the log order matches the order
+/// of its modifications.
+/// Log sequence numbers are byte offsets into the log file.
+pub struct Wal {
+    file: File,
+    config: WalConfig,
+    state: Mutex<WalState>,
+    committed: Condvar,
+}
+
+impl Wal {
+    /// Creates an empty log, truncating any existing file at `path`.
+    /// The tree it is attached to must be durable at this point.
+    pub fn create(path: impl AsRef<Path>, config: WalConfig) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.write_all(MAGIC)?;
+        file.sync_all()?;
+        Ok(Self::with_file(file, config, MAGIC.len() as u64))
+    }
+
+    /// Opens an existing log for appending.
+    /// A torn record at the end, left by a crash during a write, is truncated.
+    pub fn open(path: impl AsRef<Path>, config: WalConfig) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let log = read_log(&mut file)?;
+        let valid = for_each_record(&log, |_| ()) as u64;
+        file.set_len(valid)?;
+        file.seek(SeekFrom::Start(valid))?;
+        Ok(Self::with_file(file, config, valid))
+    }
+
+    fn with_file(file: File, config: WalConfig, len: u64) -> Self {
+        Wal {
+            file,
+            config,
+            state: Mutex::new(WalState {
+                buffer: Vec::new(),
+                appended: len,
+                committed: len,
+                flush_in_progress: false,
+                logged_pages: HashSet::new(),
+            }),
+            committed: Condvar::new(),
+        }
+    }
+
+    pub fn config(&self) -> &WalConfig {
+        &self.config
+    }
+
+    /// Adds a record to the log buffer and returns its log sequence number.
+    /// The record is not durable until it is committed.
+    pub fn append(&self, record: WalRecord) -> u64 {
+        self.append_locked(&mut self.state.lock().unwrap(), record)
+    }
+
+    fn append_locked(&self, state: &mut WalState, record: WalRecord) -> u64 {
+        let len_before = state.buffer.len();
+        record.encode(&mut state.buffer);
+        state.appended += (state.buffer.len() - len_before) as u64;
+        let lsn = state.appended;
+        if self.config.sync_mode == SyncMode::None && state.buffer.len() > MAX_BUFFERED {
+            (&self.file).write_all(&state.buffer).expect("failed to write log");
+            state.buffer.clear();
+            state.committed = state.appended;
+        }
+        lsn
+    }
+
+    /// Logs and commits the contents of `page` before it is modified or freed, unless they were logged since the log
+    /// was created or truncated.
+    pub(crate) fn log_before_write(&self, pid: PageId, page: &Page) {
+        let lsn = {
+            let mut state = self.state.lock().unwrap();
+            if !state.logged_pages.insert(pid) {
+                return;
+            }
+            self.append_locked(&mut state, WalRecord::Pages { images: vec![(pid, page_bytes(page))] })
+        };
+        self.commit(lsn);
+    }
+
+    /// Appends the contents of pages changed together and returns the record's log sequence number.
+    pub(crate) fn log_pages(&self, pages: &[(PageId, &Page)]) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.logged_pages.extend(pages.iter().map(|&(pid, _)| pid));
+        let images = pages.iter().map(|&(pid, page)| (pid, page_bytes(page))).collect();
+        self.append_locked(&mut state, WalRecord::Pages { images })
+    }
+
+    /// Log sequence number of the most recently appended record.
+    pub fn appended_lsn(&self) -> u64 {
+        self.state.lock().unwrap().appended
+    }
+
+    /// Waits until all records up to `lsn` are committed according to the configured [SyncMode].
+    ///
+    /// Concurrent committers are grouped: one thread writes out the records of all waiting threads,
+    /// the others wait for it to finish.
+    pub fn commit(&self, lsn: u64) {
+        if self.config.sync_mode == SyncMode::None {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.committed >= lsn {
+                return;
+            }
+            if state.flush_in_progress {
+                state = self.committed.wait(state).unwrap();
+                continue;
+            }
+            state.flush_in_progress = true;
+            if !self.config.group_commit_delay.is_zero() {
+                drop(state);
+                std::thread::sleep(self.config.group_commit_delay);
+                state = self.state.lock().unwrap();
+            }
+            let buffer = std::mem::take(&mut state.buffer);
+            let end = state.appended;
+            drop(state);
+            self.write_out(&buffer).expect("failed to write log");
+            state = self.state.lock().unwrap();
+            if state.buffer.is_empty() {
+                // reuse allocation
+                state.buffer = buffer;
+                state.buffer.clear();
+            }
+            state.committed = end;
+            state.flush_in_progress = false;
+            self.committed.notify_all();
+        }
+    }
+
+    fn write_out(&self, buffer: &[u8]) -> io::Result<()> {
+        (&self.file).write_all(buffer)?;
+        if self.config.sync_mode == SyncMode::Fsync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Discards all records.
+    /// Call this after making the tree itself durable, e.g. by flushing a persistent buffer manager, while it is not
+    /// modified.
+    pub fn truncate(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while state.flush_in_progress {
+            state = self.committed.wait(state).unwrap();
+        }
+        state.buffer.clear();
+        state.logged_pages.clear();
+        self.file.set_len(MAGIC.len() as u64)?;
+        (&self.file).seek(SeekFrom::Start(MAGIC.len() as u64))?;
+        self.file.sync_all()?;
+        state.appended = MAGIC.len() as u64;
+        state.committed = state.appended;
+        Ok(())
+    }
+
+    /// Applies all records in the log at `path` to `tree` and returns the number of applied records.
+    ///
+    /// `tree` must be the tree that wrote the log, opened on its pages after a crash, e.g. with
+    /// [umolc::MmapBm::recover].
+    /// Every page the log modifies is restored from its logged contents first, so replaying does not depend on the
+    /// state the pages were left in, and may be repeated.
+    /// Pages allocated or freed since the log was created are not tracked, the free list of the buffer manager should
+    /// be rebuilt from [Tree::page_ids] afterwards.
+    /// The tree should not have a log attached while replaying.
+    pub fn replay<'bm, BM: BufferManager<'bm, Page = Page>>(
+        path: impl AsRef<Path>,
+        tree: &Tree<'bm, BM>,
+    ) -> io::Result<usize> {
+        let log = read_log(&mut File::open(path)?)?;
+        let mut applied = 0;
+        let mut matches = true;
+        for_each_record(&log, |record| {
+            if matches {
+                matches = tree.redo(record);
+                applied += matches as usize;
+            }
+        });
+        if !matches {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "log does not match the tree"));
+        }
+        Ok(applied)
+    }
+
+    /// Calls `f` on every valid record in the log at `path`.
+    pub fn inspect(path: impl AsRef<Path>, f: impl FnMut(WalRecord)) -> io::Result<()> {
+        let log = read_log(&mut File::open(path)?)?;
+        for_each_record(&log, f);
+        Ok(())
+    }
+}
+
+fn read_log(file: &mut File) -> io::Result<Vec<u8>> {
+    let mut log = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut log)?;
+    if !log.starts_with(MAGIC) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a write-ahead log"));
+    }
+    Ok(log)
+}
+
+impl Drop for Wal {
+    fn drop(&mut self) {
+        if let Ok(state) = self.state.get_mut() {
+            let buffer = std::mem::take(&mut state.buffer);
+            if !buffer.is_empty() {
+                let _ = self.write_out(&buffer);
+            }
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use dev_utils::mixed_test_keys;
+use umolc::{
+    BufferManager, BufferManagerExt, BufferManagerGuard, ExclusiveGuard, LocalBm, OutOfPages, SimpleBm, VmBm,
+    VmBmConfig,
+};
+use umolc_btree::{InsertNowaitError, Page, Tree};
+
+#[test]
+fn insert_fails_when_out_of_pages() {
+    let bm = SimpleBm::<Page>::new(32);
+    let tree = Tree::new(&bm);
+    let keys = mixed_test_keys(20_000, true, 21);
+    let mut inserted = 0;
+    for (i, k) in keys.iter().enumerate() {
+        match tree.insert_fallible(k, &(i as u32).to_le_bytes()) {
+            Ok(_) => inserted += 1,
+            Err(OutOfPages) => break,
+        }
+    }
+    assert!(inserted > 0 && inserted < keys.len());
+    for (i, k) in keys[..inserted].iter().enumerate() {
+        assert_eq!(tree.lookup_to_vec(k).unwrap(), (i as u32).to_le_bytes());
+    }
+    assert!(tree.lookup_to_vec(&keys[inserted]).is_none());
+    // space freed by removals is still usable
+    assert!(tree.remove(&keys[0]).is_some());
+    assert_eq!(tree.insert_fallible(&keys[0], b"again"), Ok(None));
+}
+
+#[test]
+fn insert_nowait_fails_when_out_of_pages() {
+    let bm = SimpleBm::<Page>::new(32);
+    let tree = Tree::new(&bm);
+    let keys = mixed_test_keys(20_000, true, 21);
+    let mut inserted = 0;
+    for (i, k) in keys.iter().enumerate() {
+        match tree.try_insert_nowait(k, &(i as u32).to_le_bytes()) {
+            Ok(_) => inserted += 1,
+            Err(e) => {
+                assert_eq!(e, InsertNowaitError::OutOfPages);
+                break;
+            }
+        }
+    }
+    assert!(inserted > 0 && inserted < keys.len());
+    for (i, k) in keys[..inserted].iter().enumerate() {
+        assert_eq!(tree.lookup_to_vec(k).unwrap(), (i as u32).to_le_bytes());
+    }
+    assert!(tree.lookup_to_vec(&keys[inserted]).is_none());
+}
+
+#[test]
+fn growable_simple_bm() {
+    let bm = SimpleBm::<Page>::new_growable(16, 64);
+    assert_eq!(bm.capacity(), 16);
+    assert_eq!(bm.max_capacity(), 16 * 64);
+    let tree = Tree::new(&bm);
+    let keys = mixed_test_keys(5_000, true, 22);
+    for (i, k) in keys.iter().enumerate() {
+        assert_eq!(tree.insert_fallible(k, &(i as u32).to_le_bytes()), Ok(None));
+    }
+    assert!(bm.capacity() > 16);
+    for (i, k) in keys.iter().enumerate() {
+        assert_eq!(tree.lookup_to_vec(k).unwrap(), (i as u32).to_le_bytes());
+    }
+}
+
+#[test]
+fn tree_drop_frees_all_pages() {
+    let mut bm = SimpleBm::<Page>::new(1 << 12);
+    bm.enable_leak_check(|p| p.common.tag);
+    let keys = mixed_test_keys(10_000, true, 23);
+    {
+        let tree = Tree::new(&bm);
+        for (i, k) in keys.iter().enumerate() {
+            tree.insert(k, &(i as u32).to_le_bytes());
+        }
+        for k in keys.iter().step_by(2) {
+            tree.remove(k);
+        }
+        assert!(bm.live_pages() > 1);
+        assert_eq!(bm.allocated_pages().len(), bm.live_pages());
+        assert!(bm.high_water_mark() >= bm.live_pages());
+    }
+    assert_eq!(bm.live_pages(), 0);
+    assert!(bm.high_water_mark() > 1);
+}
+
+#[test]
+fn remove_merges_nodes_and_frees_pages() {
+    let mut bm = SimpleBm::<Page>::new(1 << 12);
+    bm.enable_leak_check(|p| p.common.tag);
+    let keys = mixed_test_keys(10_000, true, 24);
+    {
+        let tree = Tree::new(&bm);
+        for (i, k) in keys.iter().enumerate() {
+            tree.insert(k, &(i as u32).to_le_bytes());
+        }
+        let full = bm.live_pages();
+        for (i, k) in keys.iter().enumerate() {
+            if i % 20 != 0 {
+                assert!(tree.remove(k).is_some());
+            }
+        }
+        assert!(bm.live_pages() < full / 5, "{} of {full} pages left", bm.live_pages());
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(tree.lookup_to_vec(k).is_some(), i % 20 == 0);
+        }
+        let mut scanned = 0;
+        tree.scan(&[], |_, _| {
+            scanned += 1;
+            false
+        });
+        assert_eq!(scanned, keys.len().div_ceil(20));
+
+        for k in keys.iter().step_by(20) {
+            assert!(tree.remove(k).is_some());
+        }
+        // the metadata page and a single leaf as root
+        assert_eq!(bm.live_pages(), 2);
+        for (i, k) in keys.iter().enumerate() {
+            tree.insert(k, &(i as u32).to_le_bytes());
+        }
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(tree.lookup_to_vec(k).unwrap(), (i as u32).to_le_bytes());
+        }
+    }
+    assert_eq!(bm.live_pages(), 0);
+}
+
+#[test]
+#[should_panic(expected = "leaked 1 pages")]
+fn leak_check_reports_leaked_page() {
+    let mut bm = SimpleBm::<Page>::new(16);
+    bm.enable_leak_check(|p| p.common.tag);
+    let leaked = (&bm).alloc();
+    assert_eq!(bm.live_pages(), 1);
+    // releasing the lock without deallocating leaks the page
+    drop(leaked);
+}
+
+#[test]
+fn alloc_takes_pages_cached_by_other_threads() {
+    const CAPACITY: usize = 256;
+    let bm = SimpleBm::<[u64; 8]>::new(CAPACITY);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            let guards: Vec<_> = (0..CAPACITY).map(|_| (&bm).alloc()).collect();
+            assert!((&bm).try_alloc().is_err());
+            for g in guards {
+                g.dealloc();
+            }
+        });
+    });
+    let mut pids: Vec<u64> = (0..CAPACITY).map(|_| (&bm).alloc().page_id().x).collect();
+    assert!((&bm).try_alloc().is_err());
+    pids.sort();
+    pids.dedup();
+    assert_eq!(pids.len(), CAPACITY);
+}
+
+#[cfg_attr(not(miri), test)]
+fn concurrent_alloc_dealloc() {
+    const THREADS: u64 = 8;
+    let bm = SimpleBm::<[u64; 8]>::new(1 << 10);
+    std::thread::scope(|s| {
+        for t in 0..THREADS {
+            let bm = &bm;
+            s.spawn(move || {
+                for i in 0..5_000 {
+                    let mut guards: Vec<_> = (0..(i % 7 + 1)).map(|_| bm.alloc()).collect();
+                    for g in &mut guards {
+                        g[0] = t;
+                        g[1] = i;
+                    }
+                    std::thread::yield_now();
+                    for g in guards {
+                        assert_eq!(g[..2], [t, i]);
+                        g.dealloc();
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(bm.live_pages(), 0);
+    assert!(bm.allocated_pages().is_empty());
+    assert!(bm.high_water_mark() <= THREADS as usize * 7);
+}
+
+#[test]
+fn prefer_recent_pages() {
+    let mut bm = SimpleBm::<[u64; 8]>::new(64);
+    let pid = (&bm).alloc().page_id();
+    (&bm).lock_exclusive(pid).dealloc();
+    assert_eq!((&bm).alloc().page_id(), pid);
+    bm.set_prefer_recent_pages(false);
+    let pid = (&bm).alloc().page_id();
+    (&bm).lock_exclusive(pid).dealloc();
+    assert_ne!((&bm).alloc().page_id(), pid);
+}
+
+#[cfg_attr(not(miri), test)]
+fn vm_bm_huge_capacity() {
+    for huge_pages in [false, true] {
+        // 256 MiB of address space, far more than the tree touches, but small enough for limited overcommit
+        let bm = VmBm::<Page>::with_config(1 << 16, VmBmConfig { huge_pages, ..Default::default() }).unwrap();
+        let keys = mixed_test_keys(10_000, true, 24);
+        {
+            let tree = Tree::new(&bm);
+            for (i, k) in keys.iter().enumerate() {
+                tree.insert(k, &(i as u32).to_le_bytes());
+            }
+            for (i, k) in keys.iter().enumerate() {
+                assert_eq!(tree.lookup_to_vec(k).unwrap(), (i as u32).to_le_bytes());
+            }
+        }
+        let touched = bm.touched_pages();
+        assert!(touched > 1 && touched < 10_000);
+        // freed pages are reused before touching new ones
+        drop(Tree::new(&bm));
+        assert_eq!(bm.touched_pages(), touched);
+    }
+}
+
+static_assertions::assert_not_impl_any!(LocalBm<Page>: Sync);
+
+#[test]
+fn local_bm_tree() {
+    let bm = LocalBm::<Page>::new(1 << 12);
+    let keys = mixed_test_keys(20_000, false, 25);
+    {
+        let tree = Tree::new(&bm);
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(tree.insert(k, &(i as u32).to_le_bytes()), None);
+        }
+        for k in keys.iter().step_by(3) {
+            assert_eq!(tree.remove(k), Some(()));
+        }
+        for (i, k) in keys.iter().enumerate() {
+            let expected = (i % 3 != 0).then(|| (i as u32).to_le_bytes().to_vec());
+            assert_eq!(tree.lookup_to_vec(k), expected);
+        }
+        let mut scanned = 0;
+        tree.scan(&[], |_, _| {
+            scanned += 1;
+            false
+        });
+        assert_eq!(scanned, keys.len() - keys.len().div_ceil(3));
+        assert_eq!(tree.restart_stats().restarts(), 0);
+    }
+    assert_eq!(bm.live_pages(), 0);
+}
@@ -0,0 +1,108 @@
+use dev_utils::keyset_generator::{GoodHeadsKeyset, KeyGenerator};
+use dev_utils::mixed_test_keys;
+use dev_utils::test_files::temp_path;
+use std::time::Duration;
+use umolc::{BufferManager, BufferManagerExt, BufferManagerGuard, EvictingBm, ExclusiveGuard, PageId};
+use umolc_btree::{Page, Tree};
+
+#[test]
+fn evicting_single_thread() {
+    let path = temp_path("evict-single", "db");
+    let bm = EvictingBm::<Page>::new(&path, 1 << 14, 64).unwrap();
+    let keys = mixed_test_keys(20_000, true, 7);
+    {
+        let tree = Tree::new(&bm);
+        for (i, k) in keys.iter().enumerate() {
+            tree.insert(k, &(i as u32).to_le_bytes());
+        }
+        assert!(bm.eviction_count() > 0);
+        assert!(bm.resident_count() <= bm.frames());
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(tree.lookup_to_vec(k).unwrap(), (i as u32).to_le_bytes());
+        }
+        for k in keys.iter().step_by(2) {
+            assert!(tree.remove(k).is_some());
+        }
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(tree.lookup_to_vec(k).is_some(), i % 2 == 1);
+        }
+        assert!(bm.fault_count() > 0);
+    }
+    drop(bm);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg_attr(not(miri), test)]
+fn evicting_multi_thread() {
+    const THREADS: usize = 4;
+    let path = temp_path("evict-multi", "db");
+    let bm = EvictingBm::<Page>::new(&path, 1 << 14, 128).unwrap();
+    let keys = &GoodHeadsKeyset::generate_keyset(10_000 * THREADS);
+    {
+        let tree = &Tree::new(&bm);
+        std::thread::scope(|s| {
+            for t in 0..THREADS {
+                s.spawn(move || {
+                    let own = || keys.iter().skip(t).step_by(THREADS);
+                    for (k, v) in own() {
+                        tree.insert(k, v);
+                    }
+                    for (k, v) in own() {
+                        assert_eq!(&tree.lookup_to_vec(k).unwrap(), v);
+                    }
+                });
+            }
+        });
+        for (k, v) in keys {
+            assert_eq!(&tree.lookup_to_vec(k).unwrap(), v);
+        }
+        assert!(bm.eviction_count() > 0);
+        assert!(bm.resident_count() <= bm.frames());
+    }
+    drop(bm);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn evicting_all_frames_locked() {
+    let path = temp_path("evict-locked", "db");
+    let bm = EvictingBm::<Page>::new(&path, 16, 2).unwrap();
+    {
+        let bm = &bm;
+        let a = bm.alloc();
+        let b = bm.alloc();
+        assert!(bm.try_alloc().is_err());
+        drop(a);
+        // the unlocked page can be evicted now
+        let c = bm.try_alloc().unwrap();
+        c.dealloc();
+        b.dealloc();
+    }
+    drop(bm);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn fault_in_with_all_frames_locked() {
+    let path = temp_path("evict-fault-locked", "db");
+    let bm = EvictingBm::<Page>::new(&path, 16, 2).unwrap();
+    {
+        let bm = &bm;
+        let pids: Vec<PageId> = (0..3).map(|_| bm.alloc().page_id()).collect();
+        // holding two pages fills both frames and evicts the third
+        let a = bm.lock_exclusive(pids[0]);
+        let b = bm.lock_exclusive(pids[1]);
+        let evicted = <&EvictingBm<Page> as BufferManager>::GuardX::try_acquire(bm, pids[2]);
+        assert!(evicted.is_err());
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| bm.lock_shared(pids[2]).page_id());
+            std::thread::sleep(Duration::from_millis(20));
+            drop(a);
+            assert_eq!(waiter.join().unwrap(), pids[2]);
+        });
+        drop(b);
+        assert!(bm.resident_count() <= bm.frames());
+    }
+    drop(bm);
+    std::fs::remove_file(&path).unwrap();
+}
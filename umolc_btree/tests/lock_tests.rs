@@ -0,0 +1,286 @@
+use dev_utils::test_files::temp_path;
+use std::sync::Barrier;
+use std::time::{Duration, Instant};
+use umolc::{
+    BufferManageGuardDowngrade, BufferManageGuardUpgrade, BufferManager, BufferManagerExt, BufferManagerGuard,
+    LockMode, OptimisticGuard, PageHeat, SimpleBm, TraceBm, TraceOp, WouldBlock,
+};
+use umolc_btree::{InsertNowaitError, Page, Tree};
+
+type Bm<'a> = &'a SimpleBm<[u64; 8]>;
+
+#[test]
+fn guard_conversions() {
+    let bm = SimpleBm::<[u64; 8]>::new(4);
+    let mut x = (&bm).alloc();
+    let pid = x.page_id();
+    x[0] = 1;
+    let s: <Bm as BufferManager>::GuardS = x.downgrade();
+    assert_eq!(s[0], 1);
+    let mut x: <Bm as BufferManager>::GuardX = s.try_upgrade().unwrap();
+    x[0] = 2;
+    let o: <Bm as BufferManager>::GuardO = x.downgrade();
+    let version = o.try_check().unwrap();
+    assert_eq!(o.o_ptr_bm().unsize().i(0).r(), 2);
+    // the optimistic guard continues at the version the exclusive lock was released with
+    assert!(<Bm as BufferManager>::GuardO::acquire_wait_version(&bm, pid, version).is_some());
+    (&bm).lock_exclusive(pid)[0] = 3;
+    assert!(o.try_release().is_err());
+
+    // upgrading fails while another thread holds a shared lock
+    let barrier = Barrier::new(2);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            let _guard = (&bm).lock_shared(pid);
+            barrier.wait();
+            barrier.wait();
+        });
+        barrier.wait();
+        let shared = (&bm).lock_shared(pid);
+        let upgraded: Result<<Bm as BufferManager>::GuardX, _> = shared.try_upgrade();
+        assert!(upgraded.is_err());
+        barrier.wait();
+    });
+    assert_eq!((&bm).lock_exclusive(pid)[0], 3);
+}
+
+#[cfg(feature = "track-thread-locks")]
+#[test]
+fn conflicting_lock_orders() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    let bm = SimpleBm::<[u64; 8]>::new(4);
+    let [a, b, c, d] = [(); 4].map(|()| (&bm).alloc().page_id());
+    {
+        let _a = (&bm).lock_exclusive(a);
+        let _b = (&bm).lock_exclusive(b);
+    }
+    // shared locks do not exclude each other, so their order does not matter
+    for (first, second) in [(c, d), (d, c)] {
+        let _first = (&bm).lock_shared(first);
+        let _second = (&bm).lock_shared(second);
+    }
+    let error = catch_unwind(AssertUnwindSafe(|| {
+        let _b = (&bm).lock_shared(b);
+        let _a = (&bm).lock_shared(a);
+    }))
+    .unwrap_err();
+    let message = error.downcast_ref::<String>().unwrap();
+    assert!(message.contains("possible deadlock"), "{message}");
+    assert!(message.contains(&format!("acquiring shared page {} while holding shared page {}", a.x, b.x)));
+    umolc::assert_no_locks_held();
+}
+
+#[cfg(feature = "track-thread-locks")]
+#[test]
+fn leaked_guards() {
+    let bm = SimpleBm::<[u64; 8]>::new(4);
+    let pid = (&bm).alloc().page_id();
+    std::thread::scope(|s| {
+        s.spawn(|| std::mem::forget((&bm).lock_shared(pid))).join().unwrap();
+    });
+    let expected = format!("thread exited while holding shared page {}", pid.x);
+    assert!(umolc::take_leaked_locks().contains(&expected));
+}
+
+#[cfg(feature = "track-thread-locks")]
+#[cfg_attr(not(miri), test)]
+fn sibling_locking() {
+    let bm = SimpleBm::<[u64; 8]>::new(4);
+    let [parent, left, right] = [(); 3].map(|()| (&bm).alloc().page_id());
+    {
+        let _right = (&bm).lock_exclusive(right);
+        let _left = (&bm).lock_exclusive(left);
+    }
+    // merges lock both siblings below their parent without waiting, so their order cannot deadlock
+    {
+        let _parent = (&bm).lock_exclusive(parent);
+        let _left = <Bm as BufferManager>::GuardX::try_acquire(&bm, left).unwrap();
+        let _right = <Bm as BufferManager>::GuardX::try_acquire(&bm, right).unwrap();
+    }
+
+    const THREADS: usize = 4;
+    let bm = SimpleBm::<Page>::new(1 << 12);
+    let tree = &Tree::new(&bm);
+    let keys: &Vec<[u8; 8]> = &(0..20_000u64).map(|i| (i * 7919 % 20_000).to_be_bytes()).collect();
+    std::thread::scope(|s| {
+        for t in 0..THREADS {
+            s.spawn(move || {
+                let own = || keys.iter().skip(t).step_by(THREADS);
+                for k in own() {
+                    tree.insert(k, &[0; 40]);
+                }
+                // removing most keys empties leaves, which are merged with their siblings concurrently
+                for k in own().filter(|k| k[7] % 8 != 0) {
+                    assert!(tree.remove(k).is_some());
+                }
+                umolc::assert_no_locks_held();
+            });
+        }
+    });
+    for k in keys {
+        assert_eq!(tree.lookup_to_vec(k).is_some(), k[7] % 8 == 0);
+    }
+    umolc::assert_no_locks_held();
+}
+
+#[test]
+fn non_blocking_acquisition() {
+    let bm = SimpleBm::<[u64; 8]>::new(4);
+    let pid = (&bm).alloc().page_id();
+    let barrier = Barrier::new(2);
+    let version = std::thread::scope(|s| {
+        s.spawn(|| {
+            let guard = (&bm).lock_exclusive(pid);
+            barrier.wait();
+            barrier.wait();
+            drop(guard);
+            let guard = (&bm).lock_shared(pid);
+            barrier.wait();
+            barrier.wait();
+            drop(guard);
+        });
+        barrier.wait();
+        assert_eq!(<Bm as BufferManager>::GuardO::try_acquire(&bm, pid).err(), Some(WouldBlock));
+        assert_eq!(<Bm as BufferManager>::GuardS::try_acquire(&bm, pid).err(), Some(WouldBlock));
+        assert_eq!(<Bm as BufferManager>::GuardX::try_acquire(&bm, pid).err(), Some(WouldBlock));
+        let deadline = Instant::now() + Duration::from_millis(20);
+        assert_eq!(<Bm as BufferManager>::GuardS::acquire_with_deadline(&bm, pid, deadline).err(), Some(WouldBlock));
+        assert!(Instant::now() >= deadline);
+        barrier.wait();
+
+        // shared locks only exclude exclusive ones
+        barrier.wait();
+        let version = <Bm as BufferManager>::GuardO::try_acquire(&bm, pid).unwrap().release();
+        drop(<Bm as BufferManager>::GuardS::try_acquire(&bm, pid).unwrap());
+        assert!(<Bm as BufferManager>::GuardX::try_acquire_version(&bm, pid, version).is_err());
+        let deadline = Instant::now() + Duration::from_millis(20);
+        assert_eq!(<Bm as BufferManager>::GuardX::acquire_with_deadline(&bm, pid, deadline).err(), Some(WouldBlock));
+        barrier.wait();
+        version
+    });
+    // the failed exclusive attempts left the version unchanged
+    assert!(matches!(<Bm as BufferManager>::GuardO::try_acquire_version(&bm, pid, version), Ok(Some(_))));
+    let deadline = Instant::now() + Duration::from_millis(20);
+    let mut x = <Bm as BufferManager>::GuardX::acquire_with_deadline(&bm, pid, deadline).unwrap();
+    x[0] = 1;
+    drop(x);
+    assert!(matches!(<Bm as BufferManager>::GuardX::try_acquire_version(&bm, pid, version), Ok(None)));
+}
+
+#[test]
+fn tree_operations_without_waiting() {
+    let bm = SimpleBm::<Page>::new(1 << 10);
+    let tree = Tree::new(&bm);
+    let keys: Vec<[u8; 4]> = (0u32..5_000).map(|i| i.to_be_bytes()).collect();
+    for k in &keys {
+        assert_eq!(tree.try_insert_nowait(k, k), Ok(None));
+    }
+    let barrier = Barrier::new(2);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            let _path = tree.lock_path(&keys[0]);
+            barrier.wait();
+            barrier.wait();
+        });
+        barrier.wait();
+        assert_eq!(tree.try_insert_nowait(&keys[0], b"new"), Err(InsertNowaitError::WouldBlock));
+        assert_eq!(tree.try_remove_nowait(&keys[0]), Err(WouldBlock));
+        barrier.wait();
+    });
+    assert_eq!(tree.try_insert_nowait(&keys[0], b"new"), Ok(Some(())));
+    for k in keys.iter().step_by(2) {
+        assert_eq!(tree.try_remove_nowait(k), Ok(Some(())));
+    }
+    assert_eq!(tree.try_remove_nowait(&keys[0]), Ok(None));
+    for (i, k) in keys.iter().enumerate() {
+        assert_eq!(tree.lookup_to_vec(k).is_some(), i % 2 == 1);
+    }
+}
+
+#[test]
+fn page_heat() {
+    let mut bm = SimpleBm::<[u64; 8]>::new(4);
+    bm.enable_page_stats();
+    let bm = &bm;
+    let pid = bm.alloc().page_id();
+    let version = bm.lock_optimistic(pid).release();
+    drop(bm.lock_shared(pid));
+    drop(bm.lock_shared(pid));
+    bm.lock_exclusive(pid)[0] = 1;
+    assert!(<Bm as BufferManager>::GuardO::acquire_wait_version(bm, pid, version).is_none());
+    let stats = umolc::CommonSeqLockBM::page_stats(bm).unwrap();
+    let heat = stats.heat_map();
+    assert_eq!(heat.len(), 1);
+    let expected = PageHeat {
+        optimistic_failures: 1,
+        exclusive_wait: heat[&pid].exclusive_wait,
+        shared_acquisitions: 2,
+        exclusive_acquisitions: 1,
+        last_access_epoch: 1,
+    };
+    assert_eq!(heat[&pid], expected);
+    stats.reset();
+    assert!(stats.heat_map().is_empty());
+
+    let mut bm = SimpleBm::<Page>::new(1 << 10);
+    bm.enable_page_stats();
+    let tree = Tree::new(&bm);
+    let keys: Vec<[u8; 4]> = (0u32..5_000).map(|i| i.to_be_bytes()).collect();
+    for k in &keys {
+        tree.insert(k, k);
+    }
+    let stats = umolc::CommonSeqLockBM::page_stats(&bm).unwrap();
+    let heat = stats.heat_map();
+    assert!(heat.len() > 3);
+    assert!(heat.values().map(|h| h.exclusive_acquisitions).sum::<u64>() >= keys.len() as u64);
+    // only the path to the key is accessed in the new epoch
+    let epoch = stats.advance_epoch();
+    assert!(tree.lookup_to_vec(&keys[0]).is_some());
+    let hot = stats.heat_map().values().filter(|h| h.last_access_epoch == epoch).count();
+    assert!((2..heat.len()).contains(&hot));
+}
+
+#[test]
+fn traced_operations() {
+    let bm = TraceBm::new(SimpleBm::<[u64; 8]>::new(4), 8);
+    let bm = &bm;
+    let pid = bm.alloc().page_id();
+    let version = bm.lock_optimistic(pid).release();
+    bm.lock_exclusive(pid)[0] = 1;
+    assert!(bm.lock_optimistic(pid).try_release().is_ok());
+    let ops: Vec<_> = bm.events().iter().map(|e| (e.op, e.ok)).collect();
+    assert_eq!(
+        ops,
+        [
+            (TraceOp::Alloc, true),
+            (TraceOp::Release(LockMode::Exclusive), true),
+            (TraceOp::Acquire(LockMode::Optimistic), true),
+            (TraceOp::Release(LockMode::Optimistic), true),
+            (TraceOp::Acquire(LockMode::Exclusive), true),
+            (TraceOp::Release(LockMode::Exclusive), true),
+            (TraceOp::Acquire(LockMode::Optimistic), true),
+            (TraceOp::Validate, true),
+        ]
+    );
+    let events = bm.events();
+    assert!(events.iter().all(|e| e.pid == pid && e.thread == std::thread::current().id()));
+    assert_eq!(events[2].version, Some(version.x));
+    assert_eq!(bm.dropped(), 0);
+
+    // the buffer keeps the most recent events
+    assert!(<&TraceBm<SimpleBm<[u64; 8]>> as BufferManager>::GuardS::acquire_wait_version(bm, pid, version).is_none());
+    bm.lock_shared(pid);
+    let events = bm.events();
+    assert_eq!(bm.dropped(), 3);
+    assert_eq!(events.len(), 8);
+    assert_eq!(events[5].op, TraceOp::Acquire(LockMode::Shared));
+    assert_eq!(events[5].version, Some(version.x), "failed acquisitions record the expected version");
+    assert!(!events[5].ok);
+
+    let path = temp_path("traced-operations", "trace");
+    bm.dump(&path).unwrap();
+    let dump = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(dump.lines().count(), 9);
+    assert!(dump.lines().nth(6).unwrap().ends_with("failed"));
+}
@@ -1,17 +1,35 @@
 extern crate core;
 
+use std::panic::AssertUnwindSafe;
 use std::sync::Barrier;
 use std::{panic, thread};
 use std::backtrace::Backtrace;
 use std::thread::yield_now;
 use bstr::BStr;
 use dev_utils::keyset_generator::{BadHeadsKeyset, DenseKeyset, GoodHeadsKeyset, KeyGenerator, ScrambledDenseKeyset};
+use dev_utils::test_files::temp_path;
 use dev_utils::tree_utils::check_node_tag_percentage;
-use umolc::{BufferManager, SimpleBm};
+use umolc::{BufferManager, SimpleBm, TraceBm};
 use umolc_btree::{Page, Tree};
 
 
 
+/// number of page operations kept for the trace dumped when a test fails
+const TRACE_CAPACITY: usize = 1 << 16;
+
+/// Runs `f`, writing the most recent page operations on `bm` to a file in the temp directory if it panics.
+fn dump_trace_on_panic<B>(bm: &TraceBm<B>, f: impl FnOnce()) {
+    if let Err(e) = panic::catch_unwind(AssertUnwindSafe(f)) {
+        let name = thread::current().name().unwrap_or("test").replace("::", "_");
+        let path = temp_path(&name, "trace");
+        match bm.dump(&path) {
+            Ok(()) => eprintln!("page operation trace written to {}", path.display()),
+            Err(err) => eprintln!("failed to write page operation trace: {err}"),
+        }
+        panic::resume_unwind(e);
+    }
+}
+
 static SET_HOOK: std::sync::Once = std::sync::Once::new();
 
 fn install_panic_hook() {
@@ -117,22 +135,34 @@ fn combined_multithread_tests_dense_data() {
     adaptive_promotion_multithreaded::<DenseKeyset::<10000>>(1000, 16, 15, 1);
 }
 
+/// Set to trace page operations in [point_operations_multithreaded] and dump them if the test fails.
+/// Tracing serializes all page operations on a mutex, so it is off by default.
+const TRACE_VAR: &str = "TRACE_PAGE_OPS";
+
 fn point_operations_multithreaded<KG: KeyGenerator>(amount: usize, threads: u16, iterations: u16)
 {
     let bm = SimpleBm::<Page>::new((amount * threads as usize)/10);
-    let tree = Tree::new(&bm);
+    let keysets = prepare_keyset::<KG>(amount, threads);
 
+    if std::env::var_os(TRACE_VAR).is_some() {
+        let bm = TraceBm::new(bm, TRACE_CAPACITY);
+        dump_trace_on_panic(&bm, || run_point_operations(&Tree::new(&bm), &keysets, threads, iterations));
+    } else {
+        run_point_operations(&Tree::new(&bm), &keysets, threads, iterations);
+    }
+}
 
+fn run_point_operations<'bm, BM>(tree: &Tree<'bm, BM>, keysets: &[Vec<(Vec<u8>, Vec<u8>)>], threads: u16, iterations: u16)
+where
+    BM: BufferManager<'bm, Page = Page> + Sync,
+{
     let barrier = &Barrier::new(threads as usize);
 
-    let keysets = prepare_keyset::<KG>(amount, threads);
-
-
     thread::scope(|s| {
         for i in 0..threads {
             let thread_id = i;
             let check = keysets[thread_id as usize].clone();
-            let tree_ref = &tree;
+            let tree_ref = tree;
             let barrier_ref = &barrier;
             s.spawn(move || {
 
@@ -0,0 +1,181 @@
+use bytemuck::Zeroable;
+use dev_utils::mixed_test_keys;
+use dev_utils::test_files::temp_path;
+use umolc::{BufferManager, BufferManagerExt, BufferManagerGuard, ExclusiveGuard, MmapBm, PageId, SimpleBm};
+use umolc_btree::{Page, Tree};
+
+#[derive(Zeroable)]
+#[repr(C, align(4096))]
+struct RawPage([u64; 512]);
+
+#[test]
+fn mmap_pages_survive_reopen() {
+    let path = temp_path("mmap-reopen", "db");
+    let mut pids: Vec<PageId> = Vec::new();
+    {
+        let bm = &MmapBm::<RawPage>::create(&path, 64).unwrap();
+        for i in 0..10u64 {
+            let mut guard = bm.alloc();
+            guard.0.fill(i * 1000);
+            pids.push(guard.page_id());
+            guard.release();
+        }
+        bm.lock_exclusive(pids[3]).dealloc();
+        assert_eq!(bm.free_page_count(), 64 - 9);
+    }
+    {
+        let bm = &MmapBm::<RawPage>::open(&path).unwrap();
+        assert_eq!(bm.capacity(), 64);
+        assert_eq!(bm.free_page_count(), 64 - 9);
+        for (i, &pid) in pids.iter().enumerate() {
+            if i != 3 {
+                assert!(bm.lock_shared(pid).0.iter().all(|&x| x == i as u64 * 1000));
+            }
+        }
+        let reused = bm.alloc();
+        assert_eq!(reused.page_id(), pids[3]);
+        reused.dealloc();
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn mmap_open_rejects_page_size_mismatch() {
+    let path = temp_path("mmap-mismatch", "db");
+    drop(MmapBm::<RawPage>::create(&path, 4).unwrap());
+    assert!(MmapBm::<[u64; 8]>::open(&path).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn mmap_open_rejects_corrupt_header() {
+    use std::io::{Seek, SeekFrom, Write};
+    let path = temp_path("mmap-corrupt", "db");
+    let write_u64_at = |offset: u64, x: u64| {
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&x.to_le_bytes()).unwrap();
+    };
+    // the header holds magic, page size, capacity and free count, followed by the free list
+    drop(MmapBm::<RawPage>::create(&path, 4).unwrap());
+    write_u64_at(32 + 8, 4);
+    assert!(MmapBm::<RawPage>::open(&path).is_err());
+    drop(MmapBm::<RawPage>::create(&path, 4).unwrap());
+    write_u64_at(32, 1);
+    assert!(MmapBm::<RawPage>::open(&path).is_err());
+    drop(MmapBm::<RawPage>::create(&path, 4).unwrap());
+    write_u64_at(16, u64::MAX / 2);
+    assert!(MmapBm::<RawPage>::open(&path).is_err());
+    drop(MmapBm::<RawPage>::create(&path, 4).unwrap());
+    assert!(MmapBm::<RawPage>::open(&path).is_ok());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn mmap_tree_insert_lookup() {
+    let path = temp_path("mmap-tree", "db");
+    let bm = MmapBm::<Page>::create(&path, 1 << 12).unwrap();
+    let keys = mixed_test_keys(5_000, true, 42);
+    {
+        let tree = Tree::new(&bm);
+        for (i, k) in keys.iter().enumerate() {
+            tree.insert(k, &(i as u32).to_le_bytes());
+        }
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(tree.lookup_to_vec(k).unwrap(), (i as u32).to_le_bytes());
+        }
+    }
+    assert_eq!(bm.free_page_count(), bm.capacity());
+    drop(bm);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn mmap_tree_reopen() {
+    let path = temp_path("mmap-tree-reopen", "db");
+    let keys = mixed_test_keys(5_000, true, 43);
+    let meta = {
+        let bm = MmapBm::<Page>::create(&path, 1 << 12).unwrap();
+        let tree = Tree::new(&bm);
+        for (i, k) in keys.iter().enumerate() {
+            tree.insert(k, &(i as u32).to_le_bytes());
+        }
+        let meta = tree.close();
+        bm.flush().unwrap();
+        meta
+    };
+    let bm = MmapBm::<Page>::open(&path).unwrap();
+    {
+        let tree = Tree::open(&bm, meta);
+        assert_eq!(tree.meta_page_id(), meta);
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(tree.lookup_to_vec(k).unwrap(), (i as u32).to_le_bytes());
+        }
+        for k in keys.iter().step_by(2) {
+            assert!(tree.remove(k).is_some());
+        }
+        let second = Tree::open(&bm, meta);
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(second.lookup_to_vec(k).is_some(), i % 2 == 1);
+        }
+        second.close();
+    }
+    drop(bm);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn simple_bm_snapshot_rejects_corrupt_free_list() {
+    use std::io::{Seek, SeekFrom, Write};
+    let path = temp_path("snapshot-corrupt", "db");
+    let write_u64_at = |offset: u64, x: u64| {
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&x.to_le_bytes()).unwrap();
+    };
+    let bm = SimpleBm::<RawPage>::new(4);
+    let meta = bm.alloc().page_id();
+    bm.save_snapshot(&path, meta).unwrap();
+    // the header holds magic, page size, capacity, meta page and free count, followed by the free list
+    assert!(SimpleBm::<RawPage>::load_snapshot(&path).is_ok());
+    let first_free = u64::from_le_bytes(std::fs::read(&path).unwrap()[40..48].try_into().unwrap());
+    write_u64_at(40 + 8, first_free);
+    assert!(SimpleBm::<RawPage>::load_snapshot(&path).is_err());
+    write_u64_at(40 + 8, 4);
+    assert!(SimpleBm::<RawPage>::load_snapshot(&path).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn simple_bm_snapshot_roundtrip() {
+    let path = temp_path("snapshot", "db");
+    let keys = mixed_test_keys(5_000, true, 44);
+    {
+        let bm = SimpleBm::<Page>::new(1 << 12);
+        let tree = Tree::new(&bm);
+        for (i, k) in keys.iter().enumerate() {
+            tree.insert(k, &(i as u32).to_le_bytes());
+        }
+        {
+            let _held = bm.lock_shared(tree.meta_page_id());
+            let err = bm.save_snapshot(&path, tree.meta_page_id()).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::ResourceBusy);
+        }
+        bm.save_snapshot(&path, tree.meta_page_id()).unwrap();
+    }
+    let (bm, meta) = SimpleBm::<Page>::load_snapshot(&path).unwrap();
+    {
+        let tree = Tree::open(&bm, meta);
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(tree.lookup_to_vec(k).unwrap(), (i as u32).to_le_bytes());
+        }
+        // the restored free list must not hand out pages that are in use
+        for (i, k) in keys.iter().enumerate() {
+            tree.insert(&[&k[..], b"-new"].concat(), &(i as u32).to_le_bytes());
+        }
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(tree.lookup_to_vec(k).unwrap(), (i as u32).to_le_bytes());
+        }
+    }
+    std::fs::remove_file(&path).unwrap();
+}
@@ -195,7 +195,19 @@ fn adaptive_promotion<KG: KeyGenerator>(point_op_tag: u8, scan_tag: u8, allow_go
             }
         }
 
-        let action = match iteration%3 { 0=> "insert", 1=> "lookup", 2=> "remove", _ => unreachable!() };
+        if iteration % 3 == 2 {
+            // every key was removed, so the leaves were merged into an empty root, which cannot be a dense leaf
+            let mut nodes = 0;
+            tree.scan_node_types(first_key.as_slice(), |_, _, count| {
+                assert_eq!(count, 0);
+                nodes += 1;
+                false
+            });
+            assert_eq!(nodes, 1);
+            continue;
+        }
+
+        let action = match iteration%3 { 0=> "insert", 1=> "lookup", _ => unreachable!() };
         check_node_tag_percentage(point_op_tag, margin, action, allow_good_heads, true, &tree);
 
 
@@ -227,3 +239,85 @@ fn adaptive_promotion_dense_heads () {
     adaptive_promotion::<ScrambledDenseKeyset>(253, 253, true, 100000, 4096, 6, 0.65);
 }
 
+
+
+#[test]
+fn fdl_emptied_by_remove() {
+    const PAGE_COUNT: usize = 1024;
+    let bm = SimpleBm::<Page>::new(PAGE_COUNT);
+    let tree = Tree::new(&bm);
+    let key = |i: u32| [&b"Test"[..], &i.to_be_bytes()].concat();
+
+    // growing from both ends splits the dense leaves at their upper end
+    for i in 0..2000 {
+        tree.insert(&key(i), &i.to_le_bytes());
+        tree.insert(&key(4000 - i), &i.to_le_bytes());
+    }
+    check_node_tag_percentage(253, 0.6f32, "insert", true, true, &tree);
+
+    // underfull dense leaves are converted to be merged, even if they are empty
+    for i in 0..2000 {
+        assert!(tree.remove(&key(i)).is_some());
+        assert!(tree.remove(&key(4000 - i)).is_some());
+    }
+    let mut leaves = 0;
+    tree.scan_node_types(b"\0", |_, _, count| {
+        assert_eq!(count, 0, "Leaf not empty after removing all keys");
+        leaves += 1;
+        false
+    });
+    assert_eq!(leaves, 1, "Empty dense leaves were not merged");
+}
+
+#[test]
+fn fdl_kept_when_merge_refused() {
+    let bm = SimpleBm::<Page>::new(1 << 12);
+    let tree = Tree::new(&bm);
+    let keys: Vec<[u8; 4]> = (0u32..20_000).map(|i| i.to_be_bytes()).collect();
+    for k in &keys {
+        tree.insert(k, k);
+    }
+    let dense_leaves = || {
+        let mut dense = 0;
+        tree.scan_node_types(b"\0", |tag, _, _| {
+            dense += (tag == 253) as usize;
+            false
+        });
+        dense
+    };
+    let before = dense_leaves();
+    check_node_tag_percentage(253, 0.9f32, "insert", true, true, &tree);
+
+    // the underfull leaves only fit into one page with their full siblings as dense leaves, so they are not merged
+    for k in &keys[10_000..10_900] {
+        assert!(tree.remove(k).is_some());
+    }
+    assert_eq!(dense_leaves(), before);
+    for (i, k) in keys.iter().enumerate() {
+        let expected = (!(10_000..10_900).contains(&i)).then(|| k.to_vec());
+        assert_eq!(tree.lookup_to_vec(k), expected, "wrong value for {}", BStr::new(k));
+    }
+}
+
+#[test]
+fn fdl_update_after_split_half() {
+    let bm = SimpleBm::<Page>::new(1 << 12);
+    let tree = Tree::new(&bm);
+    let keys: Vec<[u8; 4]> = (0u32..20_000).map(|i| i.to_be_bytes()).collect();
+    let inverted = |k: &[u8; 4]| k.map(|b| !b);
+    for k in &keys {
+        tree.insert(k, &inverted(k));
+    }
+    check_node_tag_percentage(253, 0.6f32, "insert", true, true, &tree);
+
+    // longer values split dense leaves in half, which reduces their capacity but keeps the bitmap length,
+    // updates read the current value at the offset given by the bitmap length
+    for k in keys.iter().step_by(3) {
+        let old = tree.update(k, |old| Some([old.unwrap(), &[42; 20]].concat())).unwrap();
+        assert_eq!(old, Some(inverted(k).to_vec()), "wrong value for {}", BStr::new(k));
+    }
+    for (i, k) in keys.iter().enumerate() {
+        let expected = if i % 3 == 0 { [&inverted(k)[..], &[42; 20]].concat() } else { inverted(k).to_vec() };
+        assert_eq!(tree.lookup_to_vec(k), Some(expected), "wrong value for {}", BStr::new(k));
+    }
+}
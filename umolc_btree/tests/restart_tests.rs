@@ -0,0 +1,344 @@
+use dev_utils::keyset_generator::{GoodHeadsKeyset, KeyGenerator};
+use std::collections::HashSet;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use umolc::{
+    BufferManagerExt, BufferManagerGuard, FaultBm, FaultSite, LockMode, OptimisticGuard, PanicOlcEh, RestartStats,
+    SimpleBm, TraceBm, TraceOp,
+};
+use umolc_btree::{Page, Tree};
+
+#[test]
+fn restart_histogram() {
+    let stats = RestartStats::new();
+    stats.record(0, false);
+    stats.record(1, false);
+    stats.record(5, true);
+    stats.record(7, false);
+    let histogram = stats.histogram();
+    assert_eq!(histogram[..4], [1, 0, 2, 0]);
+    assert_eq!(stats.restarted_operations(), 3);
+    assert_eq!(stats.restarts(), 13);
+    assert_eq!(stats.fallbacks(), 1);
+    stats.reset();
+    assert_eq!(stats.restarted_operations(), 0);
+}
+
+#[cfg_attr(not(miri), test)]
+fn restart_limit_multithreaded() {
+    const THREADS: usize = 4;
+    let bm = SimpleBm::<Page>::new(1 << 14);
+    let mut tree = Tree::new(&bm);
+    tree.set_restart_limit(Some(0));
+    let tree = &tree;
+    let keys = &GoodHeadsKeyset::generate_keyset(10_000 * THREADS);
+    std::thread::scope(|s| {
+        for t in 0..THREADS {
+            s.spawn(move || {
+                let own = || keys.iter().skip(t).step_by(THREADS);
+                for (k, v) in own() {
+                    tree.insert(k, v);
+                }
+                for (k, v) in own() {
+                    assert_eq!(&tree.lookup_to_vec(k).unwrap(), v);
+                }
+                for (k, _) in own().step_by(2) {
+                    assert!(tree.remove(k).is_some());
+                }
+            });
+        }
+    });
+    for (i, (k, v)) in keys.iter().enumerate() {
+        let expected = if (i / THREADS) % 2 == 0 { None } else { Some(v) };
+        assert_eq!(tree.lookup_to_vec(k).as_ref(), expected);
+    }
+    let stats = tree.restart_stats();
+    // with a limit of zero, every restarted operation falls back
+    assert_eq!(stats.fallbacks(), stats.restarted_operations());
+    assert_eq!(stats.restarts(), stats.fallbacks());
+}
+
+#[cfg_attr(not(miri), test)]
+fn lookups_without_unwinding() {
+    const THREADS: usize = 4;
+    // optimistic errors that reach the handler panic instead of unwinding to a restart
+    let bm = SimpleBm::<Page, PanicOlcEh>::with_error_handler(1 << 14, 1);
+    let tree = &Tree::new(&bm);
+    let keys = &GoodHeadsKeyset::generate_keyset(20_000);
+    for (k, v) in keys {
+        tree.insert(k, v);
+    }
+    // lookups promote nodes, so concurrent lookups invalidate each other
+    std::thread::scope(|s| {
+        for t in 0..THREADS {
+            s.spawn(move || {
+                for _ in 0..5 {
+                    for (k, v) in keys.iter().skip(t) {
+                        assert_eq!(&tree.lookup_to_vec(k).unwrap(), v);
+                    }
+                }
+            });
+        }
+    });
+
+    let (k, v) = &keys[0];
+    let (guard, val) = tree.try_lookup(k).unwrap().unwrap();
+    assert_eq!(&val.load_slice_to_vec(), v);
+    drop((&bm).lock_exclusive(guard.page_id()));
+    assert!(guard.try_release().is_err());
+    assert!(tree.try_lookup(b"missing").unwrap().is_none());
+}
+
+#[cfg_attr(not(miri), test)]
+fn writes_without_unwinding() {
+    const THREADS: usize = 4;
+    // writes cannot restart without unwinding, so they must lock pessimistically instead of panicking
+    let bm = SimpleBm::<Page, PanicOlcEh>::with_error_handler(1 << 14, 1);
+    let tree = &Tree::new(&bm);
+    let keys = &GoodHeadsKeyset::generate_keyset(20_000);
+    std::thread::scope(|s| {
+        for t in 0..THREADS {
+            s.spawn(move || {
+                for (k, v) in keys.iter().skip(t).step_by(THREADS) {
+                    tree.insert(k, v);
+                }
+                for (k, _) in keys.iter().skip(t).step_by(THREADS * 2) {
+                    assert!(tree.remove(k).is_some());
+                }
+            });
+        }
+    });
+    for (i, (k, v)) in keys.iter().enumerate() {
+        let expected = (i % (THREADS * 2) >= THREADS).then_some(v);
+        assert_eq!(tree.lookup_to_vec(k).as_ref(), expected);
+    }
+}
+
+#[cfg_attr(not(miri), test)]
+fn merges_without_unwinding() {
+    const THREADS: usize = 4;
+    // merges cannot restart without unwinding either, so the tree only shrinks if they lock pessimistically
+    let bm = SimpleBm::<Page, PanicOlcEh>::with_error_handler(1 << 14, 1);
+    let tree = &Tree::new(&bm);
+    let keys = &GoodHeadsKeyset::generate_keyset(20_000);
+    for (k, v) in keys {
+        tree.insert(k, v);
+    }
+    let full = bm.live_pages();
+    std::thread::scope(|s| {
+        for t in 0..THREADS {
+            s.spawn(move || {
+                for (k, _) in keys.iter().skip(t).step_by(THREADS) {
+                    assert!(tree.remove(k).is_some());
+                }
+            });
+        }
+    });
+    for (k, _) in keys {
+        assert!(tree.lookup_to_vec(k).is_none());
+    }
+    assert!(bm.live_pages() < full / 10, "{} of {full} pages are still live", bm.live_pages());
+}
+
+#[test]
+fn injected_faults() {
+    for limit in [None, Some(2)] {
+        let bm = FaultBm::new(SimpleBm::<Page>::new(1 << 12), 42);
+        bm.set_rates(0.1);
+        let mut tree = Tree::new(&bm);
+        tree.set_restart_limit(limit);
+        let keys = GoodHeadsKeyset::generate_keyset(5_000);
+        for (k, v) in &keys {
+            tree.insert(k, v);
+        }
+        for (k, _) in keys.iter().step_by(2) {
+            assert!(tree.remove(k).is_some());
+        }
+        for (i, (k, v)) in keys.iter().enumerate() {
+            let expected = if i % 2 == 0 { None } else { Some(v) };
+            assert_eq!(tree.lookup_to_vec(k).as_ref(), expected);
+        }
+        let mut count = 0;
+        tree.scan(b"", |_, _| {
+            count += 1;
+            false
+        });
+        assert_eq!(count, keys.len() / 2);
+
+        assert!(bm.injected(FaultSite::Check) > 0);
+        assert!(bm.injected(FaultSite::Upgrade) > 0);
+        let stats = tree.restart_stats();
+        assert!(stats.restarts() > 0);
+        if limit.is_some() {
+            // only the pessimistic fallback locks pages at a known version
+            assert!(stats.fallbacks() > 0);
+            assert!(bm.injected(FaultSite::AcquireVersion) > 0);
+        }
+    }
+}
+
+#[test]
+fn targeted_fault() {
+    let bm = FaultBm::new(SimpleBm::<Page>::new(1 << 12), 42);
+    let tree = Tree::new(&bm);
+    let keys = GoodHeadsKeyset::generate_keyset(5_000);
+    for (k, v) in &keys {
+        tree.insert(k, v);
+    }
+    assert_eq!(bm.injected(FaultSite::Check), 0);
+    assert_eq!(tree.restart_stats().restarts(), 0);
+
+    // fails the first validation of the next lookup, which is then restarted once
+    let (k, v) = &keys[keys.len() / 2];
+    bm.fail_call(FaultSite::Check, bm.calls(FaultSite::Check) + 1);
+    assert_eq!(&tree.lookup_to_vec(k).unwrap(), v);
+    assert_eq!(bm.injected(FaultSite::Check), 1);
+    assert_eq!(tree.restart_stats().restarts(), 1);
+
+    // the target is used up
+    assert_eq!(&tree.lookup_to_vec(k).unwrap(), v);
+    assert_eq!(bm.injected(FaultSite::Check), 1);
+}
+
+#[cfg_attr(not(miri), test)]
+fn scans_without_unwinding() {
+    const THREADS: usize = 4;
+    // scans run beside writes that invalidate their leaves, so they must fall back instead of panicking
+    let bm = SimpleBm::<Page, PanicOlcEh>::with_error_handler(1 << 14, 1);
+    let tree = &Tree::new(&bm);
+    let keys = &GoodHeadsKeyset::generate_keyset(20_000);
+    let (present, churned) = keys.split_at(keys.len() / 2);
+    for (k, v) in present {
+        tree.insert(k, v);
+    }
+    let churned_keys = &churned.iter().map(|(k, _)| &k[..]).collect::<HashSet<_>>();
+    let mut sorted: Vec<&[u8]> = present.iter().map(|(k, _)| &k[..]).collect();
+    sorted.sort();
+    let sorted = &sorted;
+    let writing = &AtomicUsize::new(THREADS);
+    std::thread::scope(|s| {
+        for t in 0..THREADS {
+            s.spawn(move || {
+                for _ in 0..3 {
+                    for (k, v) in churned.iter().skip(t).step_by(THREADS) {
+                        tree.insert(k, v);
+                        assert!(tree.remove(k).is_some());
+                    }
+                }
+                writing.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+        for reverse in [false, true] {
+            s.spawn(move || loop {
+                let last = writing.load(Ordering::Relaxed) == 0;
+                let mut seen = Vec::new();
+                let callback = |k: &[u8], _: &[u8]| {
+                    if !churned_keys.contains(k) {
+                        seen.push(k.to_vec());
+                    }
+                    false
+                };
+                if reverse {
+                    tree.scan_range_rev(Bound::Unbounded, Bound::Unbounded, callback);
+                    seen.reverse();
+                } else {
+                    tree.scan_range(Bound::Unbounded, Bound::Unbounded, callback);
+                }
+                assert_eq!(&seen, sorted);
+                if last {
+                    break;
+                }
+            });
+        }
+    });
+    let mut cursor = tree.cursor();
+    cursor.seek(sorted[100]);
+    assert_eq!(cursor.key(), Some(sorted[100]));
+    cursor.prev();
+    assert_eq!(cursor.key(), Some(sorted[99]));
+}
+
+#[test]
+fn scan_falls_back_after_restart_limit() {
+    let bm = FaultBm::new(SimpleBm::<Page>::new(1 << 12), 42);
+    let mut tree = Tree::new(&bm);
+    tree.set_restart_limit(Some(0));
+    let keys = GoodHeadsKeyset::generate_keyset(5_000);
+    for (k, v) in &keys {
+        tree.insert(k, v);
+    }
+    assert_eq!(tree.restart_stats().fallbacks(), 0);
+
+    // fails the first validation of the scan, which then locks pessimistically
+    bm.fail_call(FaultSite::Check, bm.calls(FaultSite::Check) + 1);
+    let mut count = 0;
+    tree.scan(b"", |_, _| {
+        count += 1;
+        false
+    });
+    assert_eq!(count, keys.len());
+    assert_eq!(bm.injected(FaultSite::Check), 1);
+    assert_eq!(tree.restart_stats().fallbacks(), 1);
+}
+
+#[test]
+fn pessimistic_descent_couples_locks() {
+    // writes lock pessimistically, as they cannot restart without unwinding
+    let bm = TraceBm::new(SimpleBm::<Page, PanicOlcEh>::with_error_handler(1 << 12, 1), 1 << 12);
+    let tree = Tree::new(&bm);
+    let keys = GoodHeadsKeyset::generate_keyset(20_000);
+    for (k, v) in &keys[1..] {
+        tree.insert(k, v);
+    }
+    bm.clear();
+    tree.insert(&keys[0].0, &keys[0].1);
+
+    // some lock is held from locking the metadata page until the leaf is locked exclusively
+    let mut held = 0;
+    let mut levels = 0;
+    for e in bm.events().iter().filter(|e| e.ok) {
+        match e.op {
+            TraceOp::Acquire(LockMode::Shared) => {
+                held += 1;
+                levels += 1;
+            }
+            TraceOp::Release(LockMode::Shared) => held -= 1,
+            TraceOp::Acquire(LockMode::Exclusive) => break,
+            _ => continue,
+        }
+        assert!(held > 0, "all locks were released during the descent");
+    }
+    // the metadata page, at least one inner node and the leaf
+    assert!(levels >= 3);
+    assert_eq!(tree.lookup_to_vec(&keys[0].0).as_ref(), Some(&keys[0].1));
+}
+
+#[cfg_attr(not(miri), test)]
+fn pessimistic_writes_beside_merges() {
+    const THREADS: usize = 4;
+    // restarted writes fall back to locking pessimistically while merges free the pages they descend through
+    let bm = SimpleBm::<Page>::new(1 << 14);
+    let mut tree = Tree::new(&bm);
+    tree.set_restart_limit(Some(0));
+    let tree = &tree;
+    let keys = &GoodHeadsKeyset::generate_keyset(10_000 * THREADS);
+    std::thread::scope(|s| {
+        for t in 0..THREADS {
+            s.spawn(move || {
+                let own = || keys.iter().skip(t).step_by(THREADS);
+                for _ in 0..3 {
+                    for (k, v) in own() {
+                        tree.insert(k, v);
+                    }
+                    for (k, _) in own() {
+                        assert!(tree.remove(k).is_some());
+                    }
+                }
+            });
+        }
+    });
+    for (k, _) in keys {
+        assert!(tree.lookup_to_vec(k).is_none());
+    }
+    assert!(bm.live_pages() < bm.high_water_mark() / 2);
+}
@@ -1,11 +1,13 @@
 extern crate core;
 
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ops::Bound;
 use bytemuck::from_bytes;
 use dev_utils::keyset_generator::{BadHeadsKeyset, DenseKeyset, GoodHeadsKeyset, KeyGenerator};
 use dev_utils::tree_utils::check_node_tag_percentage;
 use umolc_btree::{Page, Tree};
-use umolc::SimpleBm;
+use umolc::{LockMode, SimpleBm, TraceBm, TraceOp};
 
 #[test]
 fn basic_scan_test() {
@@ -258,4 +260,274 @@ fn test_scan_sparse_basic_leaf (){
 fn test_scan_sparse_dense_leaf (){
     fastrand::seed(5510);
     scan_on_node_type_sparse::<DenseKeyset::<10000>>(10000, 253, 0.50);
-}
\ No newline at end of file
+}
+fn range_scans_on_node_type<KG: KeyGenerator>(amount: usize, node_tag: u8, margin: f32) {
+    let bm = SimpleBm::<Page>::new(amount / 50);
+    let tree = Tree::new(&bm);
+
+    let mut keyset = KG::generate_keyset(amount);
+    fastrand::shuffle(&mut keyset);
+    for (key, val) in keyset.iter() {
+        tree.insert(key.as_slice(), val.as_slice());
+    }
+    check_node_tag_percentage(node_tag, margin, "insert", true, true, &tree);
+
+    // removed keys make for bounds that are not in the tree
+    for (key, _) in keyset.iter().step_by(3) {
+        tree.remove(key.as_slice());
+    }
+    let expected: BTreeMap<Vec<u8>, Vec<u8>> =
+        keyset.iter().enumerate().filter(|(i, _)| i % 3 != 0).map(|(_, kv)| kv.clone()).collect();
+
+    fn random_bound(key: &[u8]) -> Bound<&[u8]> {
+        match fastrand::u8(..3) {
+            0 => Bound::Included(key),
+            1 => Bound::Excluded(key),
+            _ => Bound::Unbounded,
+        }
+    }
+
+    for _ in 0..300 {
+        let mut a = keyset[fastrand::usize(..keyset.len())].0.as_slice();
+        let mut b = keyset[fastrand::usize(..keyset.len())].0.as_slice();
+        if a > b {
+            std::mem::swap(&mut a, &mut b);
+        }
+        let (lower, upper) = if a == b {
+            (Bound::Included(a), Bound::Included(b))
+        } else {
+            (random_bound(a), random_bound(b))
+        };
+        let limit = if fastrand::bool() { fastrand::usize(1..100) } else { usize::MAX };
+        let reverse = fastrand::bool();
+
+        let mut scanned: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        let callback = |key: &[u8], val: &[u8]| {
+            scanned.push((key.to_vec(), val.to_vec()));
+            scanned.len() == limit
+        };
+        if reverse {
+            tree.scan_range_rev(lower, upper, callback);
+        } else {
+            tree.scan_range(lower, upper, callback);
+        }
+
+        let in_range = expected.range::<[u8], _>((lower, upper)).map(|(k, v)| (k.clone(), v.clone()));
+        let want: Vec<(Vec<u8>, Vec<u8>)> =
+            if reverse { in_range.rev().take(limit).collect() } else { in_range.take(limit).collect() };
+        assert_eq!(scanned, want, "scan of {lower:?}..{upper:?} (reverse: {reverse}) returned wrong keys");
+    }
+
+    for _ in 0..100 {
+        let key = &keyset[fastrand::usize(..keyset.len())].0;
+        let prefix = &key[..fastrand::usize(..=key.len())];
+        let mut scanned = Vec::new();
+        tree.scan_prefix(prefix, |key, _| {
+            scanned.push(key.to_vec());
+            false
+        });
+        let want: Vec<Vec<u8>> = expected.keys().filter(|k| k.starts_with(prefix)).cloned().collect();
+        assert_eq!(scanned, want, "prefix scan of {prefix:?} returned wrong keys");
+    }
+
+    let mut all = Vec::new();
+    tree.scan_range_rev(Bound::Unbounded, Bound::Unbounded, |key, _| {
+        all.push(key.to_vec());
+        false
+    });
+    assert!(all.iter().eq(expected.keys().rev()), "descending scan over all keys");
+}
+
+#[test]
+fn test_range_scan_hash_leaf() {
+    fastrand::seed(5510);
+    range_scans_on_node_type::<BadHeadsKeyset>(5000, 252, 0.7);
+}
+
+#[test]
+fn test_range_scan_basic_leaf() {
+    fastrand::seed(5510);
+    range_scans_on_node_type::<GoodHeadsKeyset>(5000, 251, 0.7);
+}
+
+#[test]
+fn test_range_scan_dense_leaf() {
+    fastrand::seed(5510);
+    range_scans_on_node_type::<DenseKeyset<10000>>(10000, 253, 0.50);
+}
+
+#[test]
+fn cursor_with_interleaved_writes() {
+    fastrand::seed(5510);
+    let bm = SimpleBm::<Page>::new(200);
+    let tree = Tree::new(&bm);
+    let mut keyset = GoodHeadsKeyset::generate_keyset(5000);
+    fastrand::shuffle(&mut keyset);
+    let mut expected = BTreeMap::new();
+    for (key, val) in keyset.iter() {
+        tree.insert(key.as_slice(), val.as_slice());
+        expected.insert(key.clone(), val.clone());
+    }
+
+    let mut cursor = tree.cursor();
+    assert_eq!(cursor.key(), None);
+    let mut forward = Vec::new();
+    while cursor.next() {
+        forward.push(cursor.key().unwrap().to_vec());
+    }
+    assert!(forward.iter().eq(expected.keys()), "forward iteration over all keys");
+    assert!(!cursor.is_positioned());
+    assert!(cursor.prev());
+    assert_eq!(cursor.key(), expected.keys().next_back().map(|k| &k[..]));
+
+    for _ in 0..2000 {
+        let (key, _) = &keyset[fastrand::usize(..keyset.len())];
+        match fastrand::u8(..5) {
+            0 => {
+                tree.remove(key);
+                expected.remove(key);
+            }
+            1 => {
+                let val = fastrand::u64(..).to_be_bytes().to_vec();
+                tree.insert(key, &val);
+                expected.insert(key.clone(), val);
+            }
+            2 => {
+                let moved = cursor.seek(key);
+                let want = expected.range::<[u8], _>((Bound::Included(&key[..]), Bound::Unbounded)).next();
+                assert_eq!(moved, want.is_some());
+                assert_eq!(cursor.key().zip(cursor.value()), want.map(|(k, v)| (&k[..], &v[..])));
+            }
+            3 | 4 => {
+                let reverse = fastrand::bool();
+                let before = cursor.key().map(|k| k.to_vec());
+                let moved = if reverse { cursor.prev() } else { cursor.next() };
+                let want = match (&before, reverse) {
+                    (None, false) => expected.iter().next(),
+                    (None, true) => expected.iter().next_back(),
+                    (Some(k), false) => expected.range::<[u8], _>((Bound::Excluded(&k[..]), Bound::Unbounded)).next(),
+                    (Some(k), true) => expected.range::<[u8], _>((Bound::Unbounded, Bound::Excluded(&k[..]))).next_back(),
+                };
+                assert_eq!(moved, want.is_some());
+                assert_eq!(cursor.key().zip(cursor.value()), want.map(|(k, v)| (&k[..], &v[..])));
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[test]
+fn cursor_steps_within_copied_entries() {
+    let bm = TraceBm::new(SimpleBm::<Page>::new(200), 1 << 16);
+    let tree = Tree::new(&bm);
+    let mut keyset = GoodHeadsKeyset::generate_keyset(3000);
+    keyset.sort();
+    keyset.dedup_by(|a, b| a.0 == b.0);
+    for (key, val) in keyset.iter() {
+        tree.insert(key.as_slice(), val.as_slice());
+    }
+
+    // leaves are only locked for copying a batch, steps within it merely validate the leaf
+    let shared_locks = || bm.events().iter().filter(|e| e.op == TraceOp::Release(LockMode::Shared)).count();
+    bm.clear();
+    let mut cursor = tree.cursor();
+    let mut count = 0;
+    while cursor.next() {
+        assert_eq!(cursor.key(), Some(&keyset[count].0[..]));
+        count += 1;
+    }
+    assert_eq!(count, keyset.len());
+    assert!(shared_locks() < count / 16, "{} shared locks for {count} steps", shared_locks());
+
+    // a write to the leaf invalidates the copy, so the next step sees it
+    assert!(cursor.seek(&keyset[100].0));
+    let mut inserted = keyset[100].0.clone();
+    inserted.push(0);
+    tree.insert(&inserted, b"new");
+    assert!(cursor.next());
+    assert_eq!(cursor.key(), Some(&inserted[..]));
+    assert_eq!(cursor.value(), Some(&b"new"[..]));
+    assert!(cursor.prev());
+    assert_eq!(cursor.key(), Some(&keyset[100].0[..]));
+}
+
+#[test]
+fn reentrant_scan_callback() {
+    fastrand::seed(5510);
+    let bm = SimpleBm::<Page>::new(200);
+    let tree = Tree::new(&bm);
+    let mut keyset = BadHeadsKeyset::generate_keyset(3000);
+    keyset.sort();
+    keyset.dedup_by(|a, b| a.0 == b.0);
+    for (key, val) in keyset.iter() {
+        tree.insert(key.as_slice(), val.as_slice());
+    }
+
+    // the callback writes to the leaf it is scanning
+    let mut visited = 0;
+    tree.scan(&[], |key, val| {
+        assert_eq!(tree.lookup_to_vec(key).as_deref(), Some(val));
+        let mut updated = val.to_vec();
+        updated.push(0);
+        tree.insert(key, &updated);
+        visited += 1;
+        false
+    });
+    assert_eq!(visited, keyset.len());
+    for (key, val) in keyset.iter() {
+        let mut updated = val.clone();
+        updated.push(0);
+        assert_eq!(tree.lookup_to_vec(key), Some(updated));
+    }
+}
+
+#[test]
+fn scan_sees_writes_past_its_batch() {
+    let bm = SimpleBm::<Page>::new(16);
+    let tree = Tree::new(&bm);
+    for i in 0..120u64 {
+        tree.insert(&(2 * i).to_be_bytes(), &[1]);
+    }
+    // the only leaf is copied in batches, so a key inserted far ahead of the scan is still visited
+    let late = 201u64.to_be_bytes();
+    let mut visited = Vec::new();
+    tree.scan(&[], |key, _| {
+        if visited.is_empty() {
+            tree.insert(&late, &[1]);
+        }
+        visited.push(key.to_vec());
+        false
+    });
+    assert_eq!(visited.len(), 121);
+    assert!(visited.contains(&late.to_vec()));
+}
+
+#[test]
+fn hash_leaf_scan_after_remove_and_reinsert() {
+    let bm = SimpleBm::<Page>::new(1 << 12);
+    let tree = Tree::new(&bm);
+    let keyset = BadHeadsKeyset::generate_keyset(10_000);
+    for (key, val) in keyset.iter() {
+        tree.insert(key, val);
+    }
+    check_node_tag_percentage(252, 0.7, "insert", false, true, &tree);
+    // sorts the hash leaves
+    tree.scan(&[], |_, _| false);
+
+    // removing sorted records must shrink the sorted range, or the reinserted records are taken as sorted
+    for (key, _) in keyset.iter().step_by(2) {
+        assert!(tree.remove(key).is_some());
+    }
+    for (key, val) in keyset.iter().step_by(2) {
+        tree.insert(key, val);
+    }
+    let mut previous: Option<Vec<u8>> = None;
+    let mut visited = 0;
+    tree.scan(&[], |key, _| {
+        assert!(previous.as_deref().is_none_or(|p| p < key), "scan is not sorted");
+        previous = Some(key.to_vec());
+        visited += 1;
+        false
+    });
+    assert_eq!(visited, keyset.len());
+}
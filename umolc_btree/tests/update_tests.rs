@@ -0,0 +1,124 @@
+use std::cell::Cell;
+use umolc::{OutOfPages, SimpleBm};
+use umolc_btree::{Page, Tree};
+
+#[test]
+fn update_and_compare_and_swap() {
+    let bm = SimpleBm::<Page>::new(1 << 12);
+    let tree = Tree::new(&bm);
+    let keys: Vec<[u8; 4]> = (0u32..20_000).map(|i| i.to_be_bytes()).collect();
+    for k in &keys {
+        assert_eq!(tree.update(k, |old| old.map_or(Some(k.to_vec()), |_| unreachable!())), Ok(None));
+    }
+    // same length, overwritten in place
+    for k in &keys {
+        let old = tree.update(k, |old| Some(old.unwrap().iter().map(|b| !b).collect())).unwrap();
+        assert_eq!(old.as_deref(), Some(&k[..]));
+    }
+    // longer values need splits
+    for k in keys.iter().step_by(3) {
+        tree.update(k, |old| Some([old.unwrap(), &[42; 20]].concat())).unwrap();
+    }
+    for k in keys.iter().skip(1).step_by(3) {
+        assert!(tree.update(k, |_| None).unwrap().is_some());
+        assert_eq!(tree.update(k, |old| old.map(|v| v.to_vec())), Ok(None));
+    }
+    for (i, k) in keys.iter().enumerate() {
+        let inverted: Vec<u8> = k.iter().map(|b| !b).collect();
+        let expected = match i % 3 {
+            0 => Some([&inverted[..], &[42; 20]].concat()),
+            1 => None,
+            _ => Some(inverted),
+        };
+        assert_eq!(tree.lookup_to_vec(k), expected);
+    }
+
+    let k = &keys[1];
+    assert_eq!(tree.compare_and_swap(k, Some(b"x"), Some(b"a")), Ok(Err(None)));
+    assert_eq!(tree.compare_and_swap(k, None, Some(b"a")), Ok(Ok(())));
+    assert_eq!(tree.compare_and_swap(k, None, Some(b"b")), Ok(Err(Some(b"a".to_vec()))));
+    assert_eq!(tree.compare_and_swap(k, Some(b"a"), Some(b"b")), Ok(Ok(())));
+    assert_eq!(tree.compare_and_swap(k, Some(b"b"), None), Ok(Ok(())));
+    assert_eq!(tree.lookup_to_vec(k), None);
+
+    let calls = Cell::new(0);
+    let make = |v: &[u8]| {
+        calls.set(calls.get() + 1);
+        v.to_vec()
+    };
+    assert_eq!(tree.get_or_insert_with(k, || make(b"c")).unwrap(), b"c");
+    assert_eq!(tree.get_or_insert_with(k, || make(b"d")).unwrap(), b"c");
+    assert_eq!(calls.get(), 1);
+}
+
+#[cfg_attr(not(miri), test)]
+fn concurrent_increments() {
+    const THREADS: u64 = 4;
+    const ROUNDS: u64 = 2_000;
+    const COUNTERS: u64 = 100;
+    let bm = SimpleBm::<Page>::new(1 << 12);
+    let mut tree = Tree::new(&bm);
+    for restart_limit in [None, Some(0)] {
+        tree.set_restart_limit(restart_limit);
+        let tree = &tree;
+        let key = |i: u64| (i * 7919 % COUNTERS).to_be_bytes();
+        std::thread::scope(|s| {
+            for t in 0..THREADS {
+                s.spawn(move || {
+                    for i in 0..ROUNDS {
+                        let k = key(i + t);
+                        if (i + t) % 2 == 0 {
+                            tree.update(&k, |old| {
+                                let old = old.map_or(0, |v| u64::from_le_bytes(v.try_into().unwrap()));
+                                Some((old + 1).to_le_bytes().to_vec())
+                            })
+                            .unwrap();
+                        } else {
+                            let mut current = tree.get_or_insert_with(&k, || 0u64.to_le_bytes().to_vec()).unwrap();
+                            loop {
+                                let new = (u64::from_le_bytes(current[..].try_into().unwrap()) + 1).to_le_bytes();
+                                match tree.compare_and_swap(&k, Some(&current), Some(&new)).unwrap() {
+                                    Ok(()) => break,
+                                    Err(v) => current = v.unwrap(),
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        let mut total = 0;
+        for i in 0..COUNTERS {
+            total += u64::from_le_bytes(tree.update(&key(i), |_| None).unwrap().unwrap().try_into().unwrap());
+        }
+        assert_eq!(total, THREADS * ROUNDS);
+    }
+}
+
+#[test]
+fn update_fails_when_out_of_pages() {
+    let bm = SimpleBm::<Page>::new(32);
+    let tree = Tree::new(&bm);
+    let keys: Vec<[u8; 8]> = (0u64..2_000).map(|i| (i * 7919).to_be_bytes()).collect();
+    for k in &keys {
+        tree.insert(k, &[0; 8]);
+    }
+    // values grow until a split finds no page
+    let mut failed = None;
+    for (i, k) in keys.iter().enumerate() {
+        match tree.update(k, |_| Some(vec![1; 100])) {
+            Ok(old) => assert_eq!(old.as_deref(), Some(&[0; 8][..])),
+            Err(OutOfPages) => {
+                failed = Some(i);
+                break;
+            }
+        }
+    }
+    let failed = failed.expect("the buffer manager did not run out of pages");
+    assert_eq!(tree.lookup_to_vec(&keys[failed]).as_deref(), Some(&[0; 8][..]));
+    assert_eq!(tree.compare_and_swap(&keys[failed], Some(&[0; 8]), Some(&[2; 100])), Err(OutOfPages));
+    assert_eq!(tree.get_or_insert_with(&keys[0], Vec::new), Ok(vec![1; 100]));
+    // shrinking values needs no page
+    assert_eq!(tree.update(&keys[0], |_| None), Ok(Some(vec![1; 100])));
+    assert_eq!(tree.lookup_to_vec(&keys[0]), None);
+}
@@ -0,0 +1,78 @@
+// the wait strategy is global to the process, so these tests have their own test binary
+use std::sync::Barrier;
+use std::time::Duration;
+use umolc::{set_wait_strategy, BufferManager, BufferManagerExt, BufferManagerGuard, SimpleBm, WaitStrategy};
+
+type Bm<'a> = &'a SimpleBm<[u64; 8]>;
+
+const STRATEGIES: [WaitStrategy; 3] = [WaitStrategy::Yield, WaitStrategy::Spin, WaitStrategy::SpinThenPark];
+
+// all tests share the global strategy, so they run in one test function
+#[cfg_attr(not(miri), test)]
+fn wait_strategies() {
+    for strategy in STRATEGIES {
+        set_wait_strategy(strategy);
+        // spinning threads only make progress if they do not outnumber the cores
+        let threads = match strategy {
+            WaitStrategy::Spin => std::thread::available_parallelism().map_or(2, |n| n.get()).min(16) as u64,
+            _ => 16,
+        };
+        mutual_exclusion(threads);
+        if strategy == WaitStrategy::SpinThenPark {
+            queued_writer_keeps_readers_out();
+        }
+    }
+}
+
+fn mutual_exclusion(threads: u64) {
+    const ROUNDS: u64 = 2_000;
+    let bm = SimpleBm::<[u64; 8]>::new(4);
+    let pid = {
+        let mut guard = (&bm).alloc();
+        // readers check guard[0] == guard[1] + 1, which must also hold before the first write
+        guard[0] = 1;
+        guard.page_id()
+    };
+    std::thread::scope(|s| {
+        for t in 0..threads {
+            let bm = &bm;
+            s.spawn(move || {
+                for i in 0..ROUNDS {
+                    if (i + t) % 4 == 0 {
+                        let mut guard = bm.lock_exclusive(pid);
+                        guard[1] = guard[0];
+                        std::thread::yield_now();
+                        guard[0] = guard[1] + 1;
+                    } else {
+                        let guard = bm.lock_shared(pid);
+                        assert_eq!(guard[0], guard[1] + 1);
+                    }
+                }
+            });
+        }
+    });
+    let expected = (0..threads).map(|t| (0..ROUNDS).filter(|i| (i + t) % 4 == 0).count() as u64).sum::<u64>();
+    assert_eq!(bm.lock_shared(pid)[0], expected + 1);
+}
+
+fn queued_writer_keeps_readers_out() {
+    // a writer that has waited long enough asks for priority, so a reader cannot slip in while it is still parked
+    let bm = SimpleBm::<[u64; 8]>::new(4);
+    let holder = (&bm).alloc();
+    let pid = holder.page_id();
+    let barrier = Barrier::new(2);
+    let reader_got_in = std::thread::scope(|s| {
+        s.spawn(|| {
+            let mut guard = (&bm).lock_exclusive(pid);
+            guard[0] = 1;
+            barrier.wait();
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        drop(holder);
+        let reader_got_in = <Bm as BufferManager>::GuardS::try_acquire(&bm, pid).is_ok();
+        barrier.wait();
+        reader_got_in
+    });
+    assert!(!reader_got_in);
+    assert_eq!((&bm).lock_shared(pid)[0], 1);
+}
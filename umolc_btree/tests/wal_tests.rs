@@ -0,0 +1,245 @@
+use dev_utils::mixed_test_keys;
+use dev_utils::test_files::temp_path;
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+use umolc::{MmapBm, PageId};
+use umolc_btree::{Page, SyncMode, Tree, Wal, WalConfig, WalRecord};
+
+const CAPACITY: usize = 1 << 12;
+const FILE_PAGE: usize = 4096;
+
+fn tree_contents<'bm>(tree: &Tree<'bm, &'bm MmapBm<Page>>) -> BTreeMap<Vec<u8>, Vec<u8>> {
+    let mut contents = BTreeMap::new();
+    tree.scan(&[], |k, v| {
+        contents.insert(k.to_vec(), v.to_vec());
+        false
+    });
+    contents
+}
+
+/// A tree whose modifications were logged, along with its data file before and after them.
+struct Logged {
+    data: PathBuf,
+    wal: PathBuf,
+    meta: PageId,
+    checkpoint: Vec<u8>,
+    last: Vec<u8>,
+    expected: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Logged {
+    /// Creates a durable tree, attaches a new log and runs `f` on it.
+    fn run(name: &str, config: WalConfig, f: impl for<'bm> FnOnce(&Tree<'bm, &'bm MmapBm<Page>>)) -> Self {
+        let data = temp_path(name, "data");
+        let wal = temp_path(name, "wal");
+        let bm = MmapBm::<Page>::create(&data, CAPACITY).unwrap();
+        let mut tree = Tree::new(&bm);
+        bm.flush().unwrap();
+        let checkpoint = std::fs::read(&data).unwrap();
+        tree.attach_wal(Wal::create(&wal, config).unwrap());
+        f(&tree);
+        let expected = tree_contents(&tree);
+        let meta = tree.close();
+        drop(bm);
+        let last = std::fs::read(&data).unwrap();
+        Logged { data, wal, meta, checkpoint, last, expected }
+    }
+
+    /// Simulates a crash after which every file page is left as of the checkpoint, as of the end, or torn in between.
+    fn crash(&self, seed: u64) {
+        let mut rng = fastrand::Rng::with_seed(seed);
+        let mut crashed = self.last.clone();
+        for (chunk, old) in crashed.chunks_mut(FILE_PAGE).zip(self.checkpoint.chunks(FILE_PAGE)) {
+            match rng.u8(..3) {
+                0 => chunk.copy_from_slice(old),
+                1 => (),
+                _ => {
+                    let torn = rng.usize(..chunk.len());
+                    chunk[..torn].copy_from_slice(&old[..torn]);
+                }
+            }
+        }
+        std::fs::write(&self.data, crashed).unwrap();
+    }
+
+    /// Recovers the tree from the crashed data file and the log and checks that it is usable afterwards.
+    fn recover(&self, replays: usize) {
+        let bm = MmapBm::<Page>::recover(&self.data).unwrap();
+        let tree = Tree::open(&bm, self.meta);
+        let mut records = 0;
+        Wal::inspect(&self.wal, |_| records += 1).unwrap();
+        for _ in 0..replays {
+            assert_eq!(Wal::replay(&self.wal, &tree).unwrap(), records);
+        }
+        bm.rebuild_free_list(tree.page_ids());
+        assert_eq!(bm.free_page_count(), CAPACITY - tree.page_ids().len());
+        assert_eq!(tree_contents(&tree), self.expected);
+
+        // pages in use must not be handed out again
+        let mut expected = self.expected.clone();
+        for i in 0..3_000u32 {
+            let k = format!("recovered-{i}").into_bytes();
+            tree.insert(&k, &i.to_le_bytes());
+            expected.insert(k, i.to_le_bytes().to_vec());
+        }
+        assert_eq!(tree_contents(&tree), expected);
+    }
+
+    fn remove_files(self) {
+        std::fs::remove_file(&self.data).unwrap();
+        std::fs::remove_file(&self.wal).unwrap();
+    }
+}
+
+fn flush_config() -> WalConfig {
+    WalConfig { sync_mode: SyncMode::Flush, ..Default::default() }
+}
+
+#[cfg_attr(not(miri), test)]
+fn wal_replay_restores_crashed_tree() {
+    let keys = mixed_test_keys(5_000, true, 11);
+    let logged = Logged::run("replay", flush_config(), |tree| {
+        for (i, k) in keys.iter().enumerate() {
+            tree.insert(k, &(i as u32).to_le_bytes());
+        }
+        for k in keys.iter().step_by(3) {
+            tree.remove(k);
+        }
+        for k in keys.iter().step_by(5) {
+            tree.insert(k, b"updated");
+        }
+        for (i, k) in keys.iter().enumerate().step_by(7) {
+            tree.update(k, |_| Some((i as u32 + 1).to_le_bytes().to_vec()));
+        }
+    });
+    for seed in 0..3 {
+        logged.crash(seed);
+        logged.recover(1);
+    }
+    logged.remove_files();
+}
+
+#[cfg_attr(not(miri), test)]
+fn wal_replay_is_idempotent() {
+    let keys = mixed_test_keys(3_000, true, 13);
+    let logged = Logged::run("idempotent", flush_config(), |tree| {
+        for k in &keys {
+            tree.insert(k, k);
+        }
+    });
+    logged.crash(7);
+    logged.recover(2);
+    logged.remove_files();
+}
+
+#[cfg_attr(not(miri), test)]
+fn wal_replay_after_merges() {
+    let keys = mixed_test_keys(5_000, true, 12);
+    let logged = Logged::run("merges", flush_config(), |tree| {
+        for k in &keys {
+            tree.insert(k, k);
+        }
+        let leaves = || {
+            let mut leaves = 0;
+            tree.scan_node_types(&[], |_, _, _| {
+                leaves += 1;
+                false
+            });
+            leaves
+        };
+        let before = leaves();
+        for (i, k) in keys.iter().enumerate() {
+            if i % 10 != 0 {
+                tree.remove(k);
+            }
+        }
+        assert!(leaves() < before);
+    });
+    for seed in 0..3 {
+        logged.crash(seed);
+        logged.recover(1);
+    }
+    logged.remove_files();
+}
+
+#[test]
+fn wal_torn_tail_is_ignored() {
+    let logged = Logged::run("torn", WalConfig { sync_mode: SyncMode::Fsync, ..Default::default() }, |tree| {
+        tree.insert(b"a", b"1");
+        tree.insert(b"b", b"2");
+    });
+    let len = std::fs::metadata(&logged.wal).unwrap().len();
+    // simulate a crash in the middle of writing the last record
+    OpenOptions::new().write(true).open(&logged.wal).unwrap().set_len(len - 2).unwrap();
+    OpenOptions::new().append(true).open(&logged.wal).unwrap().write_all(&[0xff; 3]).unwrap();
+
+    // the leaf was written back with both keys, replaying restores it to the state before the torn record
+    std::fs::write(&logged.data, &logged.last).unwrap();
+    let bm = MmapBm::<Page>::recover(&logged.data).unwrap();
+    let tree = Tree::open(&bm, logged.meta);
+    // the contents of the leaf and the first insert
+    assert_eq!(Wal::replay(&logged.wal, &tree).unwrap(), 2);
+    assert_eq!(tree.lookup_to_vec(b"a").unwrap(), b"1");
+    assert!(tree.lookup_to_vec(b"b").is_none());
+
+    let mut leaf = None;
+    Wal::inspect(&logged.wal, |record| {
+        if let WalRecord::Insert { page, .. } = record {
+            leaf = Some(page);
+        }
+    })
+    .unwrap();
+    let wal = Wal::open(&logged.wal, WalConfig::default()).unwrap();
+    wal.commit(wal.append(WalRecord::Remove { page: leaf.unwrap(), key: b"a" }));
+    drop(wal);
+    let mut records = 0;
+    Wal::inspect(&logged.wal, |_| records += 1).unwrap();
+    assert_eq!(records, 3);
+    assert_eq!(Wal::replay(&logged.wal, &tree).unwrap(), 3);
+    assert!(tree.lookup_to_vec(b"a").is_none());
+    drop(tree);
+    drop(bm);
+    logged.remove_files();
+}
+
+#[test]
+fn wal_replay_rejects_mismatching_log() {
+    let logged = Logged::run("mismatch", flush_config(), |tree| {
+        tree.insert(b"a", b"1");
+    });
+    let bm = MmapBm::<Page>::recover(&logged.data).unwrap();
+    let tree = Tree::open(&bm, logged.meta);
+    let wal = Wal::open(&logged.wal, WalConfig::default()).unwrap();
+    // the metadata page is not a leaf
+    wal.commit(wal.append(WalRecord::Insert { page: logged.meta, key: b"b", val: b"2" }));
+    drop(wal);
+    assert!(Wal::replay(&logged.wal, &tree).is_err());
+    drop(tree);
+    drop(bm);
+    logged.remove_files();
+}
+
+#[cfg_attr(not(miri), test)]
+fn wal_group_commit_multithreaded() {
+    const THREADS: usize = 4;
+    let keys = &mixed_test_keys(2_000, true, 12);
+    let config = WalConfig { sync_mode: SyncMode::Fsync, group_commit_delay: Duration::from_micros(200) };
+    let logged = Logged::run("group-commit", config, |tree| {
+        std::thread::scope(|s| {
+            for t in 0..THREADS {
+                s.spawn(move || {
+                    for k in keys.iter().skip(t).step_by(THREADS) {
+                        tree.insert(k, &[t as u8]);
+                    }
+                });
+            }
+        });
+    });
+    assert_eq!(logged.expected.len(), keys.len());
+    logged.crash(3);
+    logged.recover(1);
+    logged.remove_files();
+}